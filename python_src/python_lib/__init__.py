@@ -2,4 +2,10 @@
 
 """
 
-from .python_lib import StateMachine, par_accumulate
+from .python_lib import (
+    StateMachine,
+    Transition,
+    build_info,
+    par_accumulate,
+    version,
+)
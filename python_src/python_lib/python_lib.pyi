@@ -1,25 +1,52 @@
-"""Stubs for extension functions and classes."""
-from dataclasses import dataclass
-from typing import Protocol
+"""Stubs for extension functions and classes.
+
+Keep the signatures in sync with the pyo3 definitions in src/python_module.rs.
+
+"""
+from typing import Any, Optional, Sequence
 
 import numpy as np
 import numpy.typing as npt
 
-class StateMachine(Protocol):
-    current_state: int
-
+class StateMachine:
     def __new__(
-        cls: "StateMachine",
+        cls,
         starting_state: int,
         rate_constants: npt.NDArray[np.float64],
+        rate_coefficients: Optional[npt.NDArray[np.float64]] = None,
+        labels: Optional[list[str]] = None,
     ) -> "StateMachine": ...
+    signal_check_stride: int
+    @property
+    def current_state(self) -> int: ...
+    @property
+    def stopped(self) -> bool: ...
+    @property
+    def has_rate_coefficients(self) -> bool: ...
+    @property
+    def labels(self) -> Optional[list[str]]: ...
+    def label_of(self, state: int) -> Optional[str]: ...
+    def accumulate(self, ctrl_params: npt.NDArray[np.float64]) -> list[Transition]: ...
+    def accumulate_records(
+        self, ctrl_params: npt.NDArray[np.float64]
+    ) -> npt.NDArray[np.void]: ...
+    def step(self, ctrl_params: npt.NDArray[np.float64]) -> Transition: ...
 
-@dataclass(frozen=True)
-class Transition(Protocol):
-    from_state: int
-    time: float
-    to_state: int
+class Transition:
+    @property
+    def from_state(self) -> int: ...
+    @property
+    def time(self) -> float: ...
+    @property
+    def dwell(self) -> float: ...
+    @property
+    def to_state(self) -> int: ...
 
 def par_accumulate(
-    machines: list[StateMachine], ctrl_params: list[npt.NDArray[np.float64]]
+    machines: list[StateMachine],
+    ctrl_params: list[npt.NDArray[np.float64]],
+    seeds: Optional[Sequence[int]] = None,
+    seed: Optional[int] = None,
 ) -> list[list[Transition]]: ...
+def version() -> str: ...
+def build_info() -> dict[str, Any]: ...
@@ -1,5 +1,9 @@
+import signal
+
 import numpy as np
-from python_lib import StateMachine, par_accumulate
+import pandas as pd
+import pytest
+from python_lib import StateMachine, build_info, par_accumulate, version
 
 
 def test_state_machine():
@@ -9,6 +13,19 @@ def test_state_machine():
     assert isinstance(sm.current_state, int)
 
 
+def test_state_machine_labels():
+    rate_constants = np.array([[-1.0, 1.0], [1.0, -1.0]])
+    sm = StateMachine(0, rate_constants, labels=["bright", "dark"])
+
+    assert sm.labels == ["bright", "dark"]
+    assert sm.label_of(1) == "dark"
+    assert sm.label_of(2) is None
+    assert StateMachine(0, rate_constants).labels is None
+
+    with pytest.raises(ValueError):
+        StateMachine(0, rate_constants, labels=["bright"])
+
+
 def test_state_machine_with_rate_coefficients():
     rate_constants = np.array([[-1.0, 1.0], [1.0, -1.0]])
     rate_coefficients = np.array(
@@ -21,6 +38,22 @@ def test_state_machine_with_rate_coefficients():
     sm = StateMachine(0, rate_constants, rate_coefficients)
 
     assert isinstance(sm.current_state, int)
+    assert sm.has_rate_coefficients
+    assert not StateMachine(0, rate_constants).has_rate_coefficients
+
+
+def test_state_machine_rate_coefficients_depend_on_ctrl_params():
+    rate_constants = np.array([[-1.0, 1.0], [1.0, -1.0]])
+    # One control parameter, first order polynomial: rates = ctrl_param * coefficients
+    rate_coefficients = np.array([[[[-1.0, 1.0], [1.0, -1.0]]]])
+    low = StateMachine(0, rate_constants, rate_coefficients)
+    high = StateMachine(0, rate_constants, rate_coefficients)
+
+    num_low = sum(len(low.accumulate(np.array([1.0]))) for _ in range(100))
+    num_high = sum(len(high.accumulate(np.array([10.0]))) for _ in range(100))
+
+    # The expected number of transitions is ten times larger for the larger control parameter
+    assert num_high > 3 * num_low
 
 
 def test_state_machine_step():
@@ -34,6 +67,22 @@ def test_state_machine_step():
     assert transition.from_state != transition.to_state
     assert transition.to_state != starting_state
     assert transition.time >= 0.0
+    assert transition.dwell == transition.time
+
+
+def test_state_machine_stopped():
+    rate_constants = np.array([[-1.0, 1.0, -1.0], [-1.0, -1.0, 1.0], [-1.0, -1.0, -1.0]])
+    sm = StateMachine(0, rate_constants)
+    ctrl_params = np.array([1.0])
+
+    assert not sm.stopped
+    sm.step(ctrl_params)
+    assert not sm.stopped
+    sm.step(ctrl_params)
+    assert sm.stopped
+    assert sm.current_state == 2
+    with pytest.raises(ValueError):
+        sm.step(ctrl_params)
 
 
 def test_par_accumulate():
@@ -45,3 +94,102 @@ def test_par_accumulate():
     transitions = par_accumulate(machines, ctrl_params)
 
     assert len(transitions) == num_machines
+
+
+def test_par_accumulate_with_seeds():
+    rate_constants = np.array([[-1.0, 1.0], [1.0, -1.0]])
+    ctrl_params = [np.array([1.0])] * 4
+    seeds = [1, 2, 3, 4]
+
+    def run():
+        machines = [StateMachine(0, rate_constants) for _ in range(4)]
+        results = par_accumulate(machines, ctrl_params, seeds)
+        return [[transition.time for transition in result] for result in results]
+
+    first, second = run(), run()
+
+    assert any(first)
+    assert first == second
+
+
+def test_par_accumulate_with_seed():
+    rate_constants = np.array([[-1.0, 1.0], [1.0, -1.0]])
+    ctrl_params = [np.array([1.0])] * 4
+
+    def run(seed):
+        machines = [StateMachine(0, rate_constants) for _ in range(4)]
+        results = par_accumulate(machines, ctrl_params, seed=seed)
+        return [[transition.time for transition in result] for result in results]
+
+    first, second = run(42), run(42)
+
+    assert any(first)
+    assert first == second
+    assert first != run(43)
+    # Every machine draws from its own stream
+    assert first[0] != first[1]
+
+
+def test_par_accumulate_rejects_seeds_and_seed():
+    rate_constants = np.array([[-1.0, 1.0], [1.0, -1.0]])
+    machines = [StateMachine(0, rate_constants) for _ in range(2)]
+    ctrl_params = [np.array([1.0])] * 2
+
+    with pytest.raises(ValueError):
+        par_accumulate(machines, ctrl_params, [1, 2], seed=3)
+
+
+def test_par_accumulate_rejects_wrong_number_of_seeds():
+    rate_constants = np.array([[-1.0, 1.0], [1.0, -1.0]])
+    machines = [StateMachine(0, rate_constants) for _ in range(2)]
+
+    with pytest.raises(ValueError):
+        par_accumulate(machines, [np.array([1.0])] * 2, [1])
+
+
+def test_accumulate_records():
+    rate_constants = np.array([[-1.0, 1.0], [1.0, -1.0]])
+    sm = StateMachine(0, rate_constants)
+
+    records = sm.accumulate_records(np.array([]))
+    df = pd.DataFrame(records)
+
+    assert records.dtype.names == ("from", "time", "to")
+    assert list(df.columns) == ["from", "time", "to"]
+    assert df["from"].dtype == np.uint32
+    assert df["time"].dtype == np.float64
+    assert df["to"].dtype == np.uint32
+    assert (df["time"] <= 1.0).all()
+    assert (df["from"] != df["to"]).all()
+
+
+def test_accumulate_is_interruptible():
+    # A very fast machine performs millions of transitions per accumulate call
+    rate_constants = np.array([[-1.0, 1e7], [1e7, -1.0]])
+    sm = StateMachine(0, rate_constants)
+    sm.signal_check_stride = 100
+
+    def handler(signum, frame):
+        raise KeyboardInterrupt
+
+    previous = signal.signal(signal.SIGALRM, handler)
+    signal.setitimer(signal.ITIMER_REAL, 0.01)
+    try:
+        with pytest.raises(KeyboardInterrupt):
+            sm.accumulate(np.array([]))
+    finally:
+        signal.setitimer(signal.ITIMER_REAL, 0)
+        signal.signal(signal.SIGALRM, previous)
+
+
+def test_version():
+    assert isinstance(version(), str)
+    assert version()
+
+
+def test_build_info():
+    info = build_info()
+
+    assert info["version"] == version()
+    assert isinstance(info["num_threads"], int)
+    assert info["num_threads"] >= 1
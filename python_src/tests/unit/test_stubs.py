@@ -0,0 +1,36 @@
+import ast
+from pathlib import Path
+
+import python_lib
+from python_lib import python_lib as extension
+
+STUB = Path(python_lib.__file__).parent / "python_lib.pyi"
+
+
+def stub_definitions() -> dict[str, ast.AST]:
+    tree = ast.parse(STUB.read_text())
+    return {
+        node.name: node
+        for node in tree.body
+        if isinstance(node, (ast.ClassDef, ast.FunctionDef))
+    }
+
+
+def test_stub_is_packaged():
+    assert STUB.is_file()
+    assert (STUB.parent / "py.typed").is_file()
+
+
+def test_stub_matches_extension():
+    definitions = stub_definitions()
+
+    for name, node in definitions.items():
+        assert hasattr(extension, name), f"{name} is in the stub but not in the extension"
+
+        if isinstance(node, ast.ClassDef):
+            cls = getattr(extension, name)
+            for member in node.body:
+                if isinstance(member, ast.FunctionDef) and member.name != "__new__":
+                    assert hasattr(
+                        cls, member.name
+                    ), f"{name}.{member.name} is in the stub but not in the extension"
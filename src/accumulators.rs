@@ -1,22 +1,85 @@
 //! Provides concrete implementations of the Accumulate trait.
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::iter::Rev;
+use core::slice;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 
-use crate::{Accumulate, Result, Step, Time, Transition};
+use crate::holding_times::{Exponential, HoldingTime};
+use crate::steppers::Stepper;
+use crate::{
+    Accumulate, CtrlParam, Float, Result, State, StateMachineError, Step, Time, Transition,
+};
 
-pub struct StepUntil<S: Step> {
+/// The number of steps between reads of the clock in `StepUntil::accumulate_timed`.
+#[cfg(feature = "std")]
+const TIME_CHECK_STRIDE: usize = 1_000;
+
+/// Steps a state machine until a time limit is exceeded.
+///
+/// The transition times are of type `T`, by default `f64`.
+#[derive(Clone)]
+pub struct StepUntil<S: Step<T>, T: Float = Time> {
     stepper: S,
-    t_cutoff: Time,
-    transition_buffer: Vec<Transition>,
+    t_cutoff: T,
+    t_start: T,
+    transition_buffer: Vec<Transition<T>>,
 }
 
-impl<S: Step> StepUntil<S> {
-    pub fn new(stepper: S, t_cutoff: Time) -> Self {
+/// Checks that `t_cutoff` is a positive number, possibly infinite.
+fn validate_cutoff(t_cutoff: Time) -> Result<()> {
+    if t_cutoff.is_nan() || t_cutoff <= 0.0 {
+        return Err(StateMachineError::InvalidCutoff(t_cutoff));
+    }
+
+    Ok(())
+}
+
+impl<T: Float, S: Step<T>> StepUntil<S, T> {
+    /// Creates a new accumulator that steps a state machine until `t_cutoff` is exceeded.
+    ///
+    /// `t_cutoff` must be a positive, finite number, or `Time::INFINITY` for runs that only end
+    /// when the machine reaches an absorbing state. Zero, negative and NaN values are rejected.
+    pub fn new(stepper: S, t_cutoff: T) -> Result<Self> {
+        StepUntil::with_start_time(stepper, T::ZERO, t_cutoff)
+    }
+
+    /// Creates a new accumulator whose transitions start at `t_start` instead of zero.
+    ///
+    /// All transition times are offset by `t_start`, and accumulation stops once the time exceeds
+    /// `t_start + t_cutoff`, i.e. `t_cutoff` is the duration of the run. This is useful for
+    /// resuming a run or stitching trajectories together. `t_start` must be finite.
+    pub fn with_start_time(stepper: S, t_start: T, t_cutoff: T) -> Result<Self> {
+        validate_cutoff(t_cutoff.to_f64())?;
+        if !t_start.to_f64().is_finite() {
+            return Err(StateMachineError::InvalidStartTime(t_start.to_f64()));
+        }
+
         let transition_buffer = Vec::new();
 
-        StepUntil {
-            stepper: stepper,
-            t_cutoff: t_cutoff,
+        Ok(StepUntil {
+            stepper,
+            t_cutoff,
+            t_start,
             transition_buffer,
+        })
+    }
+
+    /// Returns the time at which accumulation starts.
+    pub fn t_start(&self) -> T {
+        self.t_start
+    }
+
+    /// Sets the time at which the next accumulation starts.
+    pub fn set_t_start(&mut self, t_start: T) -> Result<()> {
+        if !t_start.to_f64().is_finite() {
+            return Err(StateMachineError::InvalidStartTime(t_start.to_f64()));
         }
+        self.t_start = t_start;
+
+        Ok(())
     }
 
     pub fn stepper(&self) -> &S {
@@ -26,33 +89,2172 @@ impl<S: Step> StepUntil<S> {
     pub fn stepper_mut(&mut self) -> &mut S {
         &mut self.stepper
     }
+
+    /// Fills the transition buffer using `step` until the cumulative sum of transition times
+    /// exceeds the limit or the machine is stopped, i.e. reached an absorbing state.
+    fn accumulate_with<F>(&mut self, mut step: F) -> Result<&[Transition<T>]>
+    where
+        F: FnMut(&mut S) -> Result<Transition<T>>,
+    {
+        self.transition_buffer.clear();
+
+        let t_end = self.t_start + self.t_cutoff;
+        let mut t_cumulative: T = self.t_start;
+        let mut transition: Transition<T>;
+        loop {
+            transition = match step(&mut self.stepper) {
+                Ok(transition) => transition,
+                Err(StateMachineError::Stopped) => break,
+                Err(err) => return Err(err),
+            };
+
+            transition.time += t_cumulative;
+            if transition.time > t_end {
+                // The state machine is assumed memoryless, so we don't need to save the transition
+                // for future calls to this function.
+                break;
+            } else {
+                t_cumulative = transition.time;
+                self.transition_buffer.push(transition);
+            }
+        }
+
+        Ok(self.transition_buffer.as_slice())
+    }
 }
 
-impl<S: Step> Accumulate for StepUntil<S> {
+impl<T: Float, S: Step<T>> Accumulate<T> for StepUntil<S, T> {
     /// Steps a state machine until the cumulative sum of transition times exceeds a given limit.
+    ///
+    /// The run ends early, without an error, if the machine reaches an absorbing state; the
+    /// transition into that state is the last one returned.
+    fn accumulate<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[f64],
+        rng: &mut R,
+    ) -> Result<&[Transition<T>]> {
+        self.accumulate_with(|stepper| stepper.step(ctrl_params, rng))
+    }
+}
+
+impl<S: Step> StepUntil<S> {
+    /// Steps a state machine like `accumulate` and also returns the state of the machine at the
+    /// end of the run and the time at which it entered that state.
+    ///
+    /// These are the `to` state and the time of the last transition, or the initial state and
+    /// `t_start` if no transition occurred. Note that they may differ from the current state and
+    /// time of the stepper, which already took the step that crossed the cutoff.
+    pub fn accumulate_full<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[f64],
+        rng: &mut R,
+    ) -> Result<(&[Transition], State, Time)> {
+        let initial = self.stepper.current_state();
+        let t_start = self.t_start;
+        let transitions = self.accumulate_with(|stepper| stepper.step(ctrl_params, rng))?;
+        let (final_state, final_time) =
+            transitions.last().map_or((initial, t_start), |transition| {
+                (transition.to, transition.time)
+            });
+
+        Ok((transitions, final_state, final_time))
+    }
+
+    /// Steps a state machine like `accumulate` and returns the transitions newest-first.
+    ///
+    /// The transition times are unchanged, i.e. still absolute and now decreasing. The buffer is
+    /// iterated backwards rather than reversed, so this costs nothing over `accumulate`.
+    pub fn accumulate_reversed<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[f64],
+        rng: &mut R,
+    ) -> Result<Rev<slice::Iter<'_, Transition>>> {
+        Ok(self.accumulate(ctrl_params, rng)?.iter().rev())
+    }
+
+    /// Steps a state machine like `accumulate` and returns the transitions, with absolute times,
+    /// together with the waiting time before each of them.
+    ///
+    /// The waiting times are the `dwell` times of the transitions, so their cumulative sum plus
+    /// `t_start` is the time of each transition.
+    pub fn accumulate_both<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[f64],
+        rng: &mut R,
+    ) -> Result<(Vec<Transition>, Vec<Time>)> {
+        let transitions = self.accumulate(ctrl_params, rng)?.to_vec();
+        let intervals = transitions.iter().map(|t| t.dwell).collect();
+
+        Ok((transitions, intervals))
+    }
+
+    /// Steps a state machine like `accumulate`, but returns the run-length encoding of the visited
+    /// states instead of the transitions.
+    ///
+    /// Each pair holds a state and how long the machine stayed in it, starting with the initial
+    /// state. Consecutive visits to the same state, i.e. self-transitions, form a single pair. The
+    /// time from the last transition until the cutoff is included, so the durations sum to
+    /// `t_cutoff`; if the machine reaches an absorbing state, it stays there until the cutoff.
+    pub fn accumulate_rle<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[f64],
+        rng: &mut R,
+    ) -> Result<Vec<(State, Time)>> {
+        let mut runs = Vec::new();
+
+        let mut state = self.stepper.current_state();
+        let mut duration: Time = 0.0;
+        let mut t_elapsed: Time = 0.0;
+        loop {
+            let transition = match self.stepper.step(ctrl_params, rng) {
+                Ok(transition) => transition,
+                Err(StateMachineError::Stopped) => break,
+                Err(err) => return Err(err),
+            };
+            if t_elapsed + transition.time > self.t_cutoff {
+                break;
+            }
+
+            duration += transition.time;
+            t_elapsed += transition.time;
+            if transition.to != state {
+                runs.push((state, duration));
+                state = transition.to;
+                duration = 0.0;
+            }
+        }
+        runs.push((state, duration + self.t_cutoff - t_elapsed));
+
+        Ok(runs)
+    }
+
+    /// Steps a state machine like `accumulate`, but calls `keep_going` every `check_stride` steps
+    /// and stops with `StateMachineError::Interrupted` as soon as it returns false.
+    ///
+    /// This allows long runs to be cancelled, e.g. when the user presses Ctrl-C. Larger strides
+    /// reduce the overhead of the checks at the cost of responsiveness. A stride of zero disables
+    /// the checks.
+    pub fn accumulate_interruptible<R, F>(
+        &mut self,
+        ctrl_params: &[f64],
+        rng: &mut R,
+        check_stride: usize,
+        mut keep_going: F,
+    ) -> Result<&[Transition]>
+    where
+        R: rand::Rng + ?Sized,
+        F: FnMut() -> bool,
+    {
+        let mut num_steps: usize = 0;
+        self.accumulate_with(|stepper| {
+            num_steps += 1;
+            if check_stride > 0 && num_steps.is_multiple_of(check_stride) && !keep_going() {
+                return Err(StateMachineError::Interrupted);
+            }
+            stepper.step(ctrl_params, rng)
+        })
+    }
+
+    /// Steps a state machine like `accumulate`, but stops early once `wall_budget` of real time
+    /// has elapsed.
+    ///
+    /// The clock is only read every `TIME_CHECK_STRIDE` steps to limit the overhead, so the budget
+    /// may be exceeded slightly. Returns the transitions accumulated so far together with
+    /// `TerminationReason::TimedOut` if the budget ran out, else with `TerminationReason::Cutoff`.
+    #[cfg(feature = "std")]
+    pub fn accumulate_timed<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[f64],
+        rng: &mut R,
+        wall_budget: Duration,
+    ) -> Result<(&[Transition], TerminationReason)> {
+        let start = Instant::now();
+        let reason =
+            match self.accumulate_interruptible(ctrl_params, rng, TIME_CHECK_STRIDE, || {
+                start.elapsed() < wall_budget
+            }) {
+                Ok(_) => TerminationReason::Cutoff,
+                Err(StateMachineError::Interrupted) => TerminationReason::TimedOut,
+                Err(err) => return Err(err),
+            };
+
+        Ok((self.transition_buffer.as_slice(), reason))
+    }
+}
+
+impl<H: HoldingTime> StepUntil<Stepper<H>> {
+    /// Steps a state machine like `accumulate`, but only returns the total time spent in each
+    /// category of states instead of storing the transitions.
+    ///
+    /// `categories[i]` is the category of state i. If `categories` is `None`, the categories set
+    /// by `Stepper::with_categories` are used. The result has one total per category from 0 to the
+    /// largest one. The time from the last transition until `t_start + t_cutoff` is included, so
+    /// the totals sum to `t_cutoff`; if the machine reaches an absorbing state, the run ends like
+    /// in `accumulate` and the machine stays in its category until the cutoff, which is forever
+    /// if `t_cutoff` is infinite.
+    ///
+    /// Returns `NumElems` if there is not one category per state.
+    pub fn count_category_time<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[f64],
+        rng: &mut R,
+        categories: Option<&[u8]>,
+    ) -> Result<Vec<Time>> {
+        let num_states = self.stepper.num_states();
+        let categories = categories.or(self.stepper.categories()).unwrap_or(&[]);
+        if categories.len() != num_states {
+            return Err(StateMachineError::NumElems {
+                actual: categories.len(),
+                expected: num_states,
+            });
+        }
+        let categories = categories.to_vec();
+        let num_categories = categories.iter().max().map_or(0, |max| *max as usize + 1);
+        let mut totals = vec![0.0; num_categories];
+
+        let t_end = self.t_start + self.t_cutoff;
+        let mut t_cumulative: Time = self.t_start;
+        let mut state = self.stepper.current_state();
+        loop {
+            let transition = match self.stepper.step(ctrl_params, rng) {
+                Ok(transition) => transition,
+                Err(StateMachineError::Stopped) => break,
+                Err(err) => return Err(err),
+            };
+            if t_cumulative + transition.time > t_end {
+                break;
+            }
+
+            totals[categories[state] as usize] += transition.time;
+            t_cumulative += transition.time;
+            state = transition.to;
+        }
+        totals[categories[state] as usize] += t_end - t_cumulative;
+
+        Ok(totals)
+    }
+
+    /// Deterministically steps the state machine using mean waiting times until the cumulative
+    /// sum of transition times exceeds the limit.
+    ///
+    /// This is an approximation of the stochastic dynamics: the machine always moves to the state
+    /// with the highest rate, and the waiting time is the mean waiting time of the current state.
+    /// It produces a reproducible "expected" trajectory that is useful for checking the structure
+    /// of a model before simulating it with `accumulate`.
+    pub fn accumulate_mean(&mut self, ctrl_params: &[f64]) -> Result<&[Transition]> {
+        self.accumulate_with(|stepper| stepper.step_mean(ctrl_params))
+    }
+
+    /// Steps the state machine like `accumulate` and returns the transitions grouped by the state
+    /// from which they occurred.
+    ///
+    /// The result contains one group per state of the machine; the transitions within each group
+    /// are in chronological order.
+    pub fn accumulate_grouped<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[f64],
+        rng: &mut R,
+    ) -> Result<Vec<Vec<Transition>>> {
+        let mut groups = vec![Vec::new(); self.stepper.num_states()];
+        for transition in self.accumulate(ctrl_params, rng)? {
+            groups[transition.from()].push(transition.clone());
+        }
+
+        Ok(groups)
+    }
+}
+
+/// The bound that ended a run of an accumulator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TerminationReason {
+    /// The cumulative sum of transition times exceeded `t_cutoff`.
+    Cutoff,
+    /// `max_steps` transitions were accumulated.
+    MaxSteps,
+    /// The wall-clock budget of `StepUntil::accumulate_timed` was exceeded.
+    TimedOut,
+    /// The machine reached an absorbing state.
+    Absorbed,
+}
+
+/// Steps a state machine until either a time limit is exceeded or a maximum number of transitions
+/// is reached, whichever comes first.
+#[derive(Clone)]
+pub struct StepUntilEither<S: Step> {
+    stepper: S,
+    t_cutoff: Time,
+    max_steps: usize,
+    termination_reason: Option<TerminationReason>,
+    transition_buffer: Vec<Transition>,
+}
+
+impl<S: Step> StepUntilEither<S> {
+    /// Creates a new accumulator bounded by both a duration and a number of transitions.
+    pub fn new(stepper: S, t_cutoff: Time, max_steps: usize) -> Result<Self> {
+        validate_cutoff(t_cutoff)?;
+
+        Ok(StepUntilEither {
+            stepper,
+            t_cutoff,
+            max_steps,
+            termination_reason: None,
+            transition_buffer: Vec::with_capacity(max_steps.min(1024)),
+        })
+    }
+
+    /// Returns the bound that ended the last run, or `None` if no run has completed.
+    pub fn termination_reason(&self) -> Option<TerminationReason> {
+        self.termination_reason
+    }
+
+    pub fn stepper(&self) -> &S {
+        &self.stepper
+    }
+
+    pub fn stepper_mut(&mut self) -> &mut S {
+        &mut self.stepper
+    }
+}
+
+impl<S: Step> Accumulate for StepUntilEither<S> {
+    /// Steps a state machine until the cumulative sum of transition times exceeds a given limit
+    /// or `max_steps` transitions have been accumulated.
+    ///
+    /// The run ends early, without an error, if the machine reaches an absorbing state; the
+    /// termination reason is then `TerminationReason::Absorbed`.
+    fn accumulate<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[f64],
+        rng: &mut R,
+    ) -> Result<&[Transition]> {
+        self.transition_buffer.clear();
+        self.termination_reason = None;
+
+        let mut t_cumulative: Time = 0.0;
+        let reason = loop {
+            if self.transition_buffer.len() >= self.max_steps {
+                break TerminationReason::MaxSteps;
+            }
+
+            let mut transition = match self.stepper.step(ctrl_params, rng) {
+                Ok(transition) => transition,
+                Err(StateMachineError::Stopped) => break TerminationReason::Absorbed,
+                Err(err) => return Err(err),
+            };
+
+            transition.time += t_cumulative;
+            if transition.time > self.t_cutoff {
+                break TerminationReason::Cutoff;
+            }
+            t_cumulative = transition.time;
+            self.transition_buffer.push(transition);
+        };
+        self.termination_reason = Some(reason);
+
+        Ok(self.transition_buffer.as_slice())
+    }
+}
+
+/// Simulates several memoryless state machines that interact on a shared timeline.
+///
+/// At each event, the machine with the earliest next transition steps, and then `coupling` is
+/// called with the index of that machine, its transition, and the control parameters of all the
+/// machines, which it may modify to change the rates of any machine. Because the machines are
+/// memoryless, the pending transitions of the other machines are simply redrawn with the new
+/// rates.
+pub struct CoupledEnsemble<F>
+where
+    F: FnMut(usize, &Transition, &mut [Vec<CtrlParam>]),
+{
+    steppers: Vec<Stepper>,
+    ctrl_params: Vec<Vec<CtrlParam>>,
+    t_cutoff: Time,
+    coupling: F,
+    // The time of the last transition of each machine, relative to the start of the next
+    // accumulation
+    last_times: Vec<Time>,
+    event_buffer: Vec<(usize, Transition)>,
+}
+
+impl<F> CoupledEnsemble<F>
+where
+    F: FnMut(usize, &Transition, &mut [Vec<CtrlParam>]),
+{
+    /// Creates a new ensemble of coupled machines that runs until `t_cutoff` is exceeded.
+    ///
+    /// `ctrl_params` holds the initial control parameters of each machine.
+    pub fn new(
+        steppers: Vec<Stepper>,
+        ctrl_params: Vec<Vec<CtrlParam>>,
+        t_cutoff: Time,
+        coupling: F,
+    ) -> Result<Self> {
+        if steppers.len() != ctrl_params.len() {
+            return Err(StateMachineError::NumElems {
+                actual: ctrl_params.len(),
+                expected: steppers.len(),
+            });
+        }
+        validate_cutoff(t_cutoff)?;
+        let last_times = vec![0.0; steppers.len()];
+
+        Ok(CoupledEnsemble {
+            steppers,
+            ctrl_params,
+            t_cutoff,
+            coupling,
+            last_times,
+            event_buffer: Vec::new(),
+        })
+    }
+
+    pub fn steppers(&self) -> &[Stepper] {
+        &self.steppers
+    }
+
+    /// Returns the current control parameters of each machine.
+    pub fn ctrl_params(&self) -> &[Vec<CtrlParam>] {
+        &self.ctrl_params
+    }
+
+    /// Steps the machines until the shared clock exceeds the limit or all machines are stopped.
+    ///
+    /// Returns the events in chronological order as (machine index, transition) pairs, with
+    /// absolute transition times on the shared clock, which starts at zero on each call. The
+    /// dwell time of a transition is the time since the previous transition of the same machine,
+    /// or since the start of the first accumulation. The clocks of the steppers follow the shared
+    /// clock, which ends at `t_cutoff` unless it is infinite and all the machines are stopped.
+    pub fn accumulate<R: rand::Rng + ?Sized>(
+        &mut self,
+        rng: &mut R,
+    ) -> Result<&[(usize, Transition)]> {
+        self.event_buffer.clear();
+
+        let mut t_cumulative: Time = 0.0;
+        loop {
+            // Draw the waiting time of every machine and keep the earliest one
+            let mut next: Option<(usize, Time)> = None;
+            for (index, (stepper, ctrl_params)) in self
+                .steppers
+                .iter_mut()
+                .zip(self.ctrl_params.iter())
+                .enumerate()
+            {
+                let exit_rate = stepper.current_exit_rate(ctrl_params)?;
+                if exit_rate <= 0.0 {
+                    continue;
+                }
+                let waiting_time = stepper.waiting_time(Exponential.sample(exit_rate, rng)?)?;
+                if next.is_none_or(|(_, earliest)| waiting_time < earliest) {
+                    next = Some((index, waiting_time));
+                }
+            }
+
+            let Some((index, waiting_time)) = next else {
+                break;
+            };
+            if t_cumulative + waiting_time > self.t_cutoff {
+                break;
+            }
+            t_cumulative += waiting_time;
+            for stepper in self.steppers.iter_mut() {
+                stepper.advance_time(waiting_time);
+            }
+
+            let from = self.steppers[index].current_state();
+            let to = self.steppers[index].jump(&self.ctrl_params[index], rng)?;
+            let transition = Transition {
+                from,
+                time: t_cumulative,
+                dwell: t_cumulative - self.last_times[index],
+                to,
+            };
+            self.last_times[index] = t_cumulative;
+
+            (self.coupling)(index, &transition, &mut self.ctrl_params);
+            self.event_buffer.push((index, transition));
+        }
+
+        let t_end = if self.t_cutoff.is_finite() {
+            self.t_cutoff
+        } else {
+            t_cumulative
+        };
+        for (stepper, last_time) in self.steppers.iter_mut().zip(self.last_times.iter_mut()) {
+            stepper.advance_time(t_end - t_cumulative);
+            *last_time -= t_end;
+        }
+
+        Ok(self.event_buffer.as_slice())
+    }
+}
+
+/// Steps a state machine until a given directed transition has occurred a given number of times.
+///
+/// This is e.g. used to simulate until a number of photons were emitted on the transition from
+/// the bright to the ground state. There is no time limit unless one is set with `with_cutoff`,
+/// so the transition must otherwise be reachable.
+#[derive(Clone)]
+pub struct StepUntilEdgeCount<S: Step> {
+    stepper: S,
+    from: State,
+    to: State,
+    count: usize,
+    t_cutoff: Time,
+    transition_buffer: Vec<Transition>,
+}
+
+impl<S: Step> StepUntilEdgeCount<S> {
+    /// Creates a new accumulator that steps a state machine until it transitioned `count` times
+    /// from `from` to `to`.
+    pub fn new(stepper: S, from: State, to: State, count: usize) -> Self {
+        StepUntilEdgeCount {
+            stepper,
+            from,
+            to,
+            count,
+            t_cutoff: Time::INFINITY,
+            transition_buffer: Vec::new(),
+        }
+    }
+
+    /// Also stops accumulating once the cumulative sum of transition times exceeds `t_cutoff`.
+    pub fn with_cutoff(mut self, t_cutoff: Time) -> Result<Self> {
+        validate_cutoff(t_cutoff)?;
+        self.t_cutoff = t_cutoff;
+
+        Ok(self)
+    }
+
+    pub fn stepper(&self) -> &S {
+        &self.stepper
+    }
+
+    pub fn stepper_mut(&mut self) -> &mut S {
+        &mut self.stepper
+    }
+}
+
+impl<S: Step> Accumulate for StepUntilEdgeCount<S> {
+    /// Steps a state machine until the transition from `from` to `to` occurred `count` times and
+    /// returns all the transitions up to and including the last such one, with cumulative times.
+    ///
+    /// The run ends early, without an error, if the time limit is exceeded or the machine reaches
+    /// an absorbing state; the transitions so far are returned.
+    fn accumulate<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[f64],
+        rng: &mut R,
+    ) -> Result<&[Transition]> {
+        self.transition_buffer.clear();
+
+        let mut t_cumulative: Time = 0.0;
+        let mut num_edges: usize = 0;
+        while num_edges < self.count {
+            let mut transition = match self.stepper.step(ctrl_params, rng) {
+                Ok(transition) => transition,
+                Err(StateMachineError::Stopped) => break,
+                Err(err) => return Err(err),
+            };
+
+            transition.time += t_cumulative;
+            if transition.time > self.t_cutoff {
+                break;
+            }
+            t_cumulative = transition.time;
+            if transition.from == self.from && transition.to == self.to {
+                num_edges += 1;
+            }
+            self.transition_buffer.push(transition);
+        }
+
+        Ok(self.transition_buffer.as_slice())
+    }
+}
+
+/// Steps a state machine until it enters any of several target states.
+///
+/// This is e.g. used for kinetics with several products, where the run ends once any of them
+/// formed. There is no time limit unless one is set with `with_cutoff`, so at least one target
+/// must otherwise be reachable.
+#[derive(Clone)]
+pub struct StepUntilAnyState<S: Step> {
+    stepper: S,
+    targets: Vec<State>,
+    t_cutoff: Time,
+    hit_target: Option<State>,
+    transition_buffer: Vec<Transition>,
+}
+
+impl<S: Step> StepUntilAnyState<S> {
+    /// Creates a new accumulator that steps a state machine until it enters one of `targets`.
+    pub fn new(stepper: S, targets: Vec<State>) -> Self {
+        StepUntilAnyState {
+            stepper,
+            targets,
+            t_cutoff: Time::INFINITY,
+            hit_target: None,
+            transition_buffer: Vec::new(),
+        }
+    }
+
+    pub fn stepper(&self) -> &S {
+        &self.stepper
+    }
+
+    pub fn stepper_mut(&mut self) -> &mut S {
+        &mut self.stepper
+    }
+
+    /// Also stops accumulating once the cumulative sum of transition times exceeds `t_cutoff`.
+    pub fn with_cutoff(mut self, t_cutoff: Time) -> Result<Self> {
+        validate_cutoff(t_cutoff)?;
+        self.t_cutoff = t_cutoff;
+
+        Ok(self)
+    }
+
+    /// Returns the target state that ended the last accumulation, or `None` before the first
+    /// accumulation and if no target was entered.
+    pub fn hit_target(&self) -> Option<State> {
+        self.hit_target
+    }
+}
+
+impl<S: Step> Accumulate for StepUntilAnyState<S> {
+    /// Steps a state machine until it enters one of the targets and returns all the transitions
+    /// up to and including the one into the target, with cumulative times.
+    ///
+    /// A machine that starts in a target only stops once it enters a target again. The run ends
+    /// early, without an error, if the time limit is exceeded or the machine reaches an absorbing
+    /// state that is not a target; the transitions so far are returned.
     fn accumulate<R: rand::Rng + ?Sized>(
         &mut self,
         ctrl_params: &[f64],
         rng: &mut R,
     ) -> Result<&[Transition]> {
         self.transition_buffer.clear();
+        self.hit_target = None;
 
         let mut t_cumulative: Time = 0.0;
-        let mut transition: Transition;
         loop {
-            transition = self.stepper.step(ctrl_params, rng)?;
+            let mut transition = match self.stepper.step(ctrl_params, rng) {
+                Ok(transition) => transition,
+                Err(StateMachineError::Stopped) => break,
+                Err(err) => return Err(err),
+            };
 
             transition.time += t_cumulative;
             if transition.time > self.t_cutoff {
-                // The state machine is assumed memoryless, so we don't need to save the transition
-                // for future calls to this function.
                 break;
-            } else {
-                t_cumulative = transition.time;
-                self.transition_buffer.push(transition);
+            }
+            t_cumulative = transition.time;
+            let to = transition.to;
+            self.transition_buffer.push(transition);
+            if self.targets.contains(&to) {
+                self.hit_target = Some(to);
+                break;
             }
         }
 
         Ok(self.transition_buffer.as_slice())
     }
 }
+
+/// Wraps an accumulator and applies a function to each transition it accumulates.
+///
+/// This is e.g. used to relabel the states with `Transition::map_states` while accumulating,
+/// instead of in a second pass over the results of `par_accumulate`.
+#[derive(Clone)]
+pub struct MapAccumulate<A, F>
+where
+    A: Accumulate,
+    F: Fn(Transition) -> Transition,
+{
+    accumulator: A,
+    f: F,
+    transition_buffer: Vec<Transition>,
+}
+
+impl<A, F> MapAccumulate<A, F>
+where
+    A: Accumulate,
+    F: Fn(Transition) -> Transition,
+{
+    /// Creates a new accumulator that applies `f` to the transitions of `accumulator`.
+    pub fn new(accumulator: A, f: F) -> Self {
+        MapAccumulate {
+            accumulator,
+            f,
+            transition_buffer: Vec::new(),
+        }
+    }
+
+    pub fn accumulator(&self) -> &A {
+        &self.accumulator
+    }
+
+    pub fn accumulator_mut(&mut self) -> &mut A {
+        &mut self.accumulator
+    }
+}
+
+impl<A, F> Accumulate for MapAccumulate<A, F>
+where
+    A: Accumulate,
+    F: Fn(Transition) -> Transition,
+{
+    /// Accumulates the transitions of the wrapped accumulator and returns them mapped by `f`.
+    fn accumulate<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[f64],
+        rng: &mut R,
+    ) -> Result<&[Transition]> {
+        self.transition_buffer.clear();
+
+        let transitions = self.accumulator.accumulate(ctrl_params, rng)?;
+        self.transition_buffer
+            .extend(transitions.iter().cloned().map(&self.f));
+
+        Ok(self.transition_buffer.as_slice())
+    }
+}
+
+/// Steps a state machine until a time limit is exceeded, keeping only the last transitions.
+///
+/// The memory needed is bounded by the capacity regardless of the length of the run, e.g. when
+/// only the end of a long run in the steady state is of interest.
+#[derive(Clone)]
+pub struct RingBuffer<S: Step> {
+    stepper: S,
+    t_cutoff: Time,
+    capacity: usize,
+    transition_buffer: VecDeque<Transition>,
+}
+
+impl<S: Step> RingBuffer<S> {
+    /// Creates a new accumulator that keeps the last `capacity` transitions before `t_cutoff` is
+    /// exceeded.
+    pub fn new(stepper: S, t_cutoff: Time, capacity: usize) -> Result<Self> {
+        validate_cutoff(t_cutoff)?;
+
+        Ok(RingBuffer {
+            stepper,
+            t_cutoff,
+            capacity,
+            transition_buffer: VecDeque::with_capacity(capacity.min(1024)),
+        })
+    }
+
+    /// Returns the maximum number of transitions that are kept.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn stepper(&self) -> &S {
+        &self.stepper
+    }
+
+    pub fn stepper_mut(&mut self) -> &mut S {
+        &mut self.stepper
+    }
+}
+
+impl<S: Step> Accumulate for RingBuffer<S> {
+    /// Steps a state machine until the cumulative sum of transition times exceeds a given limit
+    /// and returns the last transitions in chronological order.
+    ///
+    /// The run ends early, without an error, if the machine reaches an absorbing state; the last
+    /// transitions up to that state are returned.
+    fn accumulate<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[f64],
+        rng: &mut R,
+    ) -> Result<&[Transition]> {
+        self.transition_buffer.clear();
+
+        let mut t_cumulative: Time = 0.0;
+        loop {
+            let mut transition = match self.stepper.step(ctrl_params, rng) {
+                Ok(transition) => transition,
+                Err(StateMachineError::Stopped) => break,
+                Err(err) => return Err(err),
+            };
+
+            transition.time += t_cumulative;
+            if transition.time > self.t_cutoff {
+                break;
+            }
+            t_cumulative = transition.time;
+
+            if self.capacity == 0 {
+                continue;
+            }
+            if self.transition_buffer.len() == self.capacity {
+                self.transition_buffer.pop_front();
+            }
+            self.transition_buffer.push_back(transition);
+        }
+
+        Ok(self.transition_buffer.make_contiguous())
+    }
+}
+
+/// Samples rare events of a memoryless state machine with the weighted ensemble method.
+///
+/// The ensemble consists of weighted copies of a machine, the walkers, whose weights sum to one.
+/// Each iteration propagates all walkers for a time `tau` and then resamples them within the bins
+/// of a progress coordinate, given by `progress_bin` as a function of the state: walkers are split
+/// into copies that share their weight until each occupied bin holds at least `walkers_per_bin`
+/// walkers and none of them carries much more than its share of the bin's weight, and then the
+/// lightest walkers of overfull bins are merged. The weights are conserved, so the total
+/// weight in a state estimates the probability of the state, including states that independent
+/// machines would rarely visit.
+pub struct WeightedEnsemble<F>
+where
+    F: Fn(State) -> usize,
+{
+    walkers: Vec<Stepper>,
+    weights: Vec<f64>,
+    progress_bin: F,
+    walkers_per_bin: usize,
+    tau: Time,
+    time: Time,
+}
+
+impl<F> WeightedEnsemble<F>
+where
+    F: Fn(State) -> usize,
+{
+    /// Creates a new ensemble of `walkers_per_bin` copies of `stepper` with equal weights.
+    ///
+    /// `tau` is the time between resamplings and must be positive and finite, and
+    /// `walkers_per_bin` must be positive.
+    pub fn new(
+        stepper: Stepper,
+        progress_bin: F,
+        walkers_per_bin: usize,
+        tau: Time,
+    ) -> Result<Self> {
+        if walkers_per_bin == 0 {
+            return Err(StateMachineError::InvalidWalkersPerBin);
+        }
+        if !tau.is_finite() || tau <= 0.0 {
+            return Err(StateMachineError::InvalidCutoff(tau));
+        }
+
+        Ok(WeightedEnsemble {
+            walkers: vec![stepper; walkers_per_bin],
+            weights: vec![1.0 / walkers_per_bin as f64; walkers_per_bin],
+            progress_bin,
+            walkers_per_bin,
+            tau,
+            time: 0.0,
+        })
+    }
+
+    pub fn walkers(&self) -> &[Stepper] {
+        &self.walkers
+    }
+
+    /// Returns the weight of each walker, in the order of `walkers`.
+    pub fn weights(&self) -> &[f64] {
+        &self.weights
+    }
+
+    /// Returns the time simulated by all the iterations so far.
+    pub fn time(&self) -> Time {
+        self.time
+    }
+
+    /// Returns the total weight of the walkers in `state`, i.e. the estimated probability that
+    /// the machine is in `state` at the current time.
+    pub fn weight_in(&self, state: State) -> f64 {
+        self.walkers
+            .iter()
+            .zip(self.weights.iter())
+            .filter(|(walker, _)| walker.current_state() == state)
+            .map(|(_, weight)| weight)
+            .sum()
+    }
+
+    /// Propagates all walkers for a time `tau` and then resamples them.
+    pub fn iterate<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[CtrlParam],
+        rng: &mut R,
+    ) -> Result<()> {
+        for walker in self.walkers.iter_mut() {
+            let mut t_cumulative: Time = 0.0;
+            loop {
+                let transition = match walker.step(ctrl_params, rng) {
+                    Ok(transition) => transition,
+                    Err(StateMachineError::Stopped) => break,
+                    Err(err) => return Err(err),
+                };
+
+                if t_cumulative + transition.time > self.tau {
+                    // The walker is still in its previous state at the end of the iteration
+                    walker.undo_step(&transition);
+                    break;
+                }
+                t_cumulative += transition.time;
+            }
+        }
+        self.time += self.tau;
+
+        self.resample(rng);
+
+        Ok(())
+    }
+
+    /// Splits and merges the walkers of each bin until it holds `walkers_per_bin` walkers.
+    fn resample<R: rand::Rng + ?Sized>(&mut self, rng: &mut R) {
+        let mut bins: BTreeMap<usize, Vec<(Stepper, f64)>> = BTreeMap::new();
+        for (walker, weight) in self.walkers.drain(..).zip(self.weights.drain(..)) {
+            bins.entry((self.progress_bin)(walker.current_state()))
+                .or_default()
+                .push((walker, weight));
+        }
+
+        for (_, mut walkers) in bins {
+            // Split the heaviest walker in two until the bin is full and no walker is much heavier
+            // than its share of the bin's weight
+            let share =
+                walkers.iter().map(|(_, weight)| weight).sum::<f64>() / self.walkers_per_bin as f64;
+            while let Some(heaviest) =
+                (0..walkers.len()).max_by(|i, j| walkers[*i].1.total_cmp(&walkers[*j].1))
+            {
+                if walkers.len() >= self.walkers_per_bin && walkers[heaviest].1 <= 2.0 * share {
+                    break;
+                }
+                walkers[heaviest].1 /= 2.0;
+                walkers.push(walkers[heaviest].clone());
+            }
+
+            // Merge the two lightest walkers into one of them, chosen with a probability
+            // proportional to its weight. The bin holds at least two walkers here because
+            // `walkers_per_bin` is positive.
+            while walkers.len() > self.walkers_per_bin {
+                walkers.sort_by(|a, b| b.1.total_cmp(&a.1));
+                let (second, lightest) = (walkers.len() - 2, walkers.len() - 1);
+                let total = walkers[second].1 + walkers[lightest].1;
+                if rng.gen::<f64>() * total < walkers[lightest].1 {
+                    walkers.swap(second, lightest);
+                }
+                walkers.truncate(lightest);
+                walkers[second].1 = total;
+            }
+
+            for (walker, weight) in walkers {
+                self.walkers.push(walker);
+                self.weights.push(weight);
+            }
+        }
+    }
+}
+
+/// Steps a state machine until a time limit is exceeded, keeping only the visited states.
+///
+/// This needs half the memory of `StepUntil` for runs where the transition times are not needed.
+#[derive(Clone)]
+pub struct VisitSequence<S: Step> {
+    stepper: S,
+    t_cutoff: Time,
+    state_buffer: Vec<State>,
+}
+
+impl<S: Step> VisitSequence<S> {
+    /// Creates a new accumulator that steps a state machine until `t_cutoff` is exceeded.
+    pub fn new(stepper: S, t_cutoff: Time) -> Result<Self> {
+        validate_cutoff(t_cutoff)?;
+
+        Ok(VisitSequence {
+            stepper,
+            t_cutoff,
+            state_buffer: Vec::new(),
+        })
+    }
+
+    pub fn stepper(&self) -> &S {
+        &self.stepper
+    }
+
+    pub fn stepper_mut(&mut self) -> &mut S {
+        &mut self.stepper
+    }
+
+    /// Steps the state machine until the cumulative sum of transition times exceeds the limit and
+    /// returns the states it entered, in order.
+    ///
+    /// The state in which the run starts is not included, so the sequence is the same as the `to`
+    /// states of the transitions returned by `StepUntil::accumulate`. Like there, the run ends
+    /// early, without an error, if the machine reaches an absorbing state.
+    pub fn accumulate<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[f64],
+        rng: &mut R,
+    ) -> Result<&[State]> {
+        self.state_buffer.clear();
+
+        let mut t_cumulative: Time = 0.0;
+        loop {
+            let transition = match self.stepper.step(ctrl_params, rng) {
+                Ok(transition) => transition,
+                Err(StateMachineError::Stopped) => break,
+                Err(err) => return Err(err),
+            };
+
+            t_cumulative += transition.time;
+            if t_cumulative > self.t_cutoff {
+                break;
+            }
+            self.state_buffer.push(transition.to);
+        }
+
+        Ok(self.state_buffer.as_slice())
+    }
+}
+
+/// Steps a state machine until a time limit is exceeded while its control parameters follow a
+/// piecewise constant schedule.
+///
+/// A transition that would happen after the end of a segment is undone and redrawn using the
+/// parameters of the next segment, which is exact because the waiting times are memoryless. For
+/// this reason, only machines with exponential holding times are supported.
+#[derive(Clone)]
+pub struct StepUntilPiecewise {
+    stepper: Stepper,
+    schedule: Vec<(Time, Vec<CtrlParam>)>,
+    t_cutoff: Time,
+    transition_buffer: Vec<Transition>,
+}
+
+impl StepUntilPiecewise {
+    /// Creates a new accumulator that steps a state machine until `t_cutoff` is exceeded.
+    ///
+    /// Each entry of `schedule` holds the time from which its control parameters apply, until the
+    /// time of the next entry. The first entry must start at time zero and the times must be
+    /// strictly increasing and finite.
+    pub fn new(
+        stepper: Stepper,
+        schedule: Vec<(Time, Vec<CtrlParam>)>,
+        t_cutoff: Time,
+    ) -> Result<Self> {
+        validate_cutoff(t_cutoff)?;
+        if schedule.first().is_none_or(|(t, _)| *t != 0.0)
+            || schedule.iter().any(|(t, _)| !t.is_finite())
+            || schedule.windows(2).any(|w| w[0].0 >= w[1].0)
+        {
+            return Err(StateMachineError::InvalidSchedule);
+        }
+
+        Ok(StepUntilPiecewise {
+            stepper,
+            schedule,
+            t_cutoff,
+            transition_buffer: Vec::new(),
+        })
+    }
+
+    pub fn schedule(&self) -> &[(Time, Vec<CtrlParam>)] {
+        &self.schedule
+    }
+
+    pub fn stepper(&self) -> &Stepper {
+        &self.stepper
+    }
+
+    pub fn stepper_mut(&mut self) -> &mut Stepper {
+        &mut self.stepper
+    }
+
+    /// Steps the state machine through the schedule until the cumulative sum of transition times
+    /// exceeds the limit.
+    ///
+    /// A machine that is stopped at the parameters of one segment waits for the next segment,
+    /// whose parameters may allow it to leave its state. The `dwell` of each transition is the
+    /// time since the previous transition, or since the start of the run, even across segments.
+    /// The clock of the stepper advances by exactly `t_cutoff`.
+    pub fn accumulate<R: rand::Rng + ?Sized>(&mut self, rng: &mut R) -> Result<&[Transition]> {
+        self.transition_buffer.clear();
+
+        for (k, (t_segment, ctrl_params)) in self.schedule.iter().enumerate() {
+            if *t_segment > self.t_cutoff {
+                break;
+            }
+            let t_end = self
+                .schedule
+                .get(k + 1)
+                .map_or(self.t_cutoff, |(t, _)| t.min(self.t_cutoff));
+
+            let mut t_cumulative: Time = *t_segment;
+            loop {
+                let transition = match self.stepper.step(ctrl_params, rng) {
+                    Ok(transition) => transition,
+                    Err(StateMachineError::Stopped) => break,
+                    Err(err) => return Err(err),
+                };
+
+                if t_cumulative + transition.time > t_end {
+                    // The machine is still in its previous state when the segment ends
+                    self.stepper.undo_step(&transition);
+                    break;
+                }
+                let t_entered = self.transition_buffer.last().map_or(0.0, |t| t.time);
+                t_cumulative += transition.time;
+                self.transition_buffer.push(Transition {
+                    time: t_cumulative,
+                    dwell: t_cumulative - t_entered,
+                    ..transition
+                });
+            }
+            self.stepper.advance_time(t_end - t_cumulative);
+        }
+
+        Ok(self.transition_buffer.as_slice())
+    }
+}
+
+/// Steps a state machine until a time limit is exceeded while forcing it into given states at
+/// scheduled times, e.g. to model a laser pulse that excites the machine.
+///
+/// A natural transition that would happen after an event is undone and redrawn from the state
+/// the event forced, which is exact because the waiting times are memoryless. For this reason,
+/// only machines with exponential holding times are supported.
+#[derive(Clone)]
+pub struct StepUntilEvents {
+    stepper: Stepper,
+    events: Vec<(Time, State)>,
+    t_cutoff: Time,
+    transition_buffer: Vec<Transition>,
+}
+
+impl StepUntilEvents {
+    /// Creates a new accumulator that steps a state machine until `t_cutoff` is exceeded.
+    ///
+    /// Each event holds the time, relative to the start of an accumulation, at which the machine
+    /// is forced into its state. The times must be non-negative, finite and non-decreasing, and
+    /// the states must be states of the machine.
+    pub fn new(stepper: Stepper, events: Vec<(Time, State)>, t_cutoff: Time) -> Result<Self> {
+        validate_cutoff(t_cutoff)?;
+        if events.iter().any(|(t, _)| !t.is_finite() || *t < 0.0)
+            || events.windows(2).any(|w| w[0].0 > w[1].0)
+        {
+            return Err(StateMachineError::InvalidEvents);
+        }
+        let num_states = stepper.num_states();
+        if let Some((_, state)) = events.iter().find(|(_, state)| *state >= num_states) {
+            return Err(StateMachineError::StateOutOfRange {
+                state: *state,
+                num_states,
+            });
+        }
+
+        Ok(StepUntilEvents {
+            stepper,
+            events,
+            t_cutoff,
+            transition_buffer: Vec::new(),
+        })
+    }
+
+    pub fn events(&self) -> &[(Time, State)] {
+        &self.events
+    }
+
+    pub fn stepper(&self) -> &Stepper {
+        &self.stepper
+    }
+
+    pub fn stepper_mut(&mut self) -> &mut Stepper {
+        &mut self.stepper
+    }
+}
+
+impl Accumulate for StepUntilEvents {
+    /// Steps the state machine until the cumulative sum of transition times exceeds the limit,
+    /// forcing it into the state of each event at its time.
+    ///
+    /// Each event appears in the transitions as a transition from the state the machine was in to
+    /// the forced state, which may be the same. A machine that is stopped waits for the next
+    /// event, which may move it out of its absorbing state. The clock of the stepper advances by
+    /// exactly `t_cutoff`.
+    fn accumulate<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[f64],
+        rng: &mut R,
+    ) -> Result<&[Transition]> {
+        self.transition_buffer.clear();
+
+        let mut t_cumulative: Time = 0.0;
+        let mut events = self.events.iter().take_while(|(t, _)| *t <= self.t_cutoff);
+        loop {
+            let next_event = events.next();
+            let t_end = next_event.map_or(self.t_cutoff, |(t, _)| *t);
+
+            loop {
+                let transition = match self.stepper.step(ctrl_params, rng) {
+                    Ok(transition) => transition,
+                    Err(StateMachineError::Stopped) => break,
+                    Err(err) => return Err(err),
+                };
+
+                if t_cumulative + transition.time > t_end {
+                    // The machine is still in its previous state when the event occurs
+                    self.stepper.undo_step(&transition);
+                    break;
+                }
+                t_cumulative += transition.time;
+                self.transition_buffer.push(Transition {
+                    time: t_cumulative,
+                    ..transition
+                });
+            }
+            self.stepper.advance_time(t_end - t_cumulative);
+
+            let Some((t_event, state)) = next_event else {
+                break;
+            };
+            let t_entered = self.transition_buffer.last().map_or(0.0, |t| t.time);
+            self.transition_buffer.push(Transition {
+                from: self.stepper.current_state(),
+                time: *t_event,
+                dwell: *t_event - t_entered,
+                to: *state,
+            });
+            self.stepper.set_state(*state)?;
+            t_cumulative = *t_event;
+        }
+
+        Ok(self.transition_buffer.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std")]
+    use std::time::Duration;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::{
+        CoupledEnsemble, MapAccumulate, RingBuffer, StepUntil, StepUntilAnyState,
+        StepUntilEdgeCount, StepUntilEither, StepUntilEvents, StepUntilPiecewise,
+        TerminationReason, VisitSequence, WeightedEnsemble,
+    };
+    use crate::arrays::{Array2D, Array4D};
+    use crate::steppers::Stepper;
+    use crate::{Accumulate, StateMachineError, Transition};
+
+    fn two_state_stepper() -> Stepper {
+        Stepper::new(
+            0,
+            Array2D {
+                data: vec![-1.0, 1.0, 1.0, -1.0],
+                shape: (2, 2),
+            },
+        )
+    }
+
+    #[test]
+    fn step_until_new_accepts_positive_and_infinite_cutoffs() {
+        assert!(StepUntil::new(two_state_stepper(), 1.0).is_ok());
+        assert!(StepUntil::new(two_state_stepper(), f64::INFINITY).is_ok());
+    }
+
+    #[test]
+    fn step_until_infinite_cutoff_ends_on_absorption() {
+        let mut rng = StdRng::seed_from_u64(24);
+        // 0 -> 1 -> 2 with state 2 absorbing
+        let stepper = Stepper::new(
+            0,
+            Array2D::new(
+                vec![-1.0, 1.0, -1.0, -1.0, -1.0, 1.0, -1.0, -1.0, -1.0],
+                (3, 3),
+            )
+            .unwrap(),
+        );
+        let mut acc = StepUntil::new(stepper, f64::INFINITY).unwrap();
+
+        let transitions = acc.accumulate(&[], &mut rng).unwrap();
+
+        assert_eq!(2, transitions.len());
+        assert_eq!(1, transitions[0].to());
+        assert_eq!(2, transitions[1].to());
+        assert!(acc.stepper().is_stopped());
+        assert!(acc.accumulate(&[], &mut rng).unwrap().is_empty());
+    }
+
+    #[test]
+    fn step_until_new_rejects_zero_cutoff() {
+        let result = StepUntil::new(two_state_stepper(), 0.0);
+
+        assert!(matches!(result, Err(StateMachineError::InvalidCutoff(_))));
+    }
+
+    #[test]
+    fn step_until_new_rejects_negative_cutoff() {
+        let result = StepUntil::new(two_state_stepper(), -1.0);
+
+        assert!(matches!(result, Err(StateMachineError::InvalidCutoff(_))));
+    }
+
+    #[test]
+    fn step_until_new_rejects_nan_cutoff() {
+        let result = StepUntil::new(two_state_stepper(), f64::NAN);
+
+        assert!(matches!(result, Err(StateMachineError::InvalidCutoff(_))));
+    }
+
+    #[test]
+    fn step_until_with_start_time_offsets_transitions() {
+        let mut rng = rand::thread_rng();
+        let (t_start, t_cutoff) = (100.0, 20.0);
+        let mut accumulator =
+            StepUntil::with_start_time(two_state_stepper(), t_start, t_cutoff).unwrap();
+
+        let transitions = accumulator.accumulate(&[], &mut rng).unwrap();
+
+        assert!(!transitions.is_empty());
+        assert!(transitions[0].time() > t_start);
+        assert!(transitions.last().unwrap().time() <= t_start + t_cutoff);
+    }
+
+    #[test]
+    fn step_until_with_start_time_rejects_non_finite_start() {
+        let result = StepUntil::with_start_time(two_state_stepper(), f64::NAN, 1.0);
+
+        assert!(matches!(
+            result,
+            Err(StateMachineError::InvalidStartTime(_))
+        ));
+    }
+
+    #[test]
+    fn step_until_accumulate_mean_is_deterministic() {
+        let rate_constants = Array2D {
+            data: vec![-1.0, 2.0, 1.0, 4.0, -1.0, 1.0, 1.0, 1.0, -1.0],
+            shape: (3, 3),
+        };
+        let mut first = StepUntil::new(Stepper::new(0, rate_constants.clone()), 5.0).unwrap();
+        let mut second = StepUntil::new(Stepper::new(0, rate_constants), 5.0).unwrap();
+
+        let first = first.accumulate_mean(&[]).unwrap().to_vec();
+        let second = second.accumulate_mean(&[]).unwrap().to_vec();
+
+        assert!(!first.is_empty());
+        assert_eq!(first.len(), second.len());
+        for (a, b) in first.iter().zip(second.iter()) {
+            assert_eq!((a.from(), a.time(), a.to()), (b.from(), b.time(), b.to()));
+        }
+
+        // 0 -> 1 after 1 / 3, then 1 -> 0 after 1 / 5
+        assert_eq!((0, 1), (first[0].from(), first[0].to()));
+        assert!((first[0].time() - 1.0 / 3.0).abs() < 1e-12);
+        assert_eq!((1, 0), (first[1].from(), first[1].to()));
+        assert!((first[1].time() - (1.0 / 3.0 + 1.0 / 5.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn step_until_accumulate_grouped() {
+        let mut rng = rand::thread_rng();
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 1.0, 1.0, -1.0, 1.0, 1.0, 1.0, -1.0],
+            shape: (3, 3),
+        };
+        let mut accumulator = StepUntil::new(Stepper::new(0, rate_constants), 20.0).unwrap();
+
+        let groups = accumulator.accumulate_grouped(&[], &mut rng).unwrap();
+
+        assert_eq!(3, groups.len());
+        assert!(groups.iter().any(|group| !group.is_empty()));
+        for (state, group) in groups.iter().enumerate() {
+            assert!(group.iter().all(|transition| transition.from() == state));
+            assert!(group
+                .windows(2)
+                .all(|pair| pair[0].time() <= pair[1].time()));
+        }
+    }
+
+    #[test]
+    fn step_until_either_stops_at_cutoff() {
+        let mut rng = rand::thread_rng();
+        let mut accumulator = StepUntilEither::new(two_state_stepper(), 5.0, usize::MAX).unwrap();
+        assert_eq!(None, accumulator.termination_reason());
+
+        let transitions = accumulator.accumulate(&[], &mut rng).unwrap();
+
+        assert!(transitions.iter().all(|t| t.time() <= 5.0));
+        assert_eq!(
+            Some(TerminationReason::Cutoff),
+            accumulator.termination_reason()
+        );
+    }
+
+    #[test]
+    fn step_until_either_stops_at_max_steps() {
+        let mut rng = rand::thread_rng();
+        let mut accumulator = StepUntilEither::new(two_state_stepper(), f64::INFINITY, 7).unwrap();
+
+        let transitions = accumulator.accumulate(&[], &mut rng).unwrap();
+
+        assert_eq!(7, transitions.len());
+        assert_eq!(
+            Some(TerminationReason::MaxSteps),
+            accumulator.termination_reason()
+        );
+    }
+
+    #[test]
+    fn step_until_either_ends_on_absorption() {
+        let mut rng = StdRng::seed_from_u64(24);
+        // 0 -> 1 -> 2 with state 2 absorbing
+        let stepper = Stepper::new(
+            0,
+            Array2D::new(
+                vec![-1.0, 1.0, -1.0, -1.0, -1.0, 1.0, -1.0, -1.0, -1.0],
+                (3, 3),
+            )
+            .unwrap(),
+        );
+        let mut accumulator = StepUntilEither::new(stepper, f64::INFINITY, usize::MAX).unwrap();
+
+        let transitions = accumulator.accumulate(&[], &mut rng).unwrap();
+
+        assert_eq!(2, transitions.len());
+        assert_eq!(2, transitions[1].to());
+        assert_eq!(
+            Some(TerminationReason::Absorbed),
+            accumulator.termination_reason()
+        );
+    }
+
+    #[test]
+    fn step_until_either_rejects_invalid_cutoff() {
+        let result = StepUntilEither::new(two_state_stepper(), 0.0, 10);
+
+        assert!(matches!(result, Err(StateMachineError::InvalidCutoff(_))));
+    }
+
+    #[test]
+    fn coupled_ensemble_gates_transitions() {
+        let mut rng = StdRng::seed_from_u64(5);
+        // Machine 0 toggles between two states; machine 1 can only transition while machine 0 is
+        // in state 1, because its rates are proportional to its control parameter
+        let gated = Stepper::new(0, Array2D::new(vec![-1.0; 4], (2, 2)).unwrap())
+            .with_rate_coefficients(Array4D::new(vec![-1.0, 1.0, 1.0, -1.0], (1, 1, 2, 2)).unwrap())
+            .unwrap();
+        let mut ensemble = CoupledEnsemble::new(
+            vec![two_state_stepper(), gated],
+            vec![vec![], vec![0.0]],
+            50.0,
+            |index: usize, transition: &Transition, ctrl_params: &mut [Vec<f64>]| {
+                if index == 0 {
+                    ctrl_params[1][0] = if transition.to() == 1 { 5.0 } else { 0.0 };
+                }
+            },
+        )
+        .unwrap();
+
+        let events = ensemble.accumulate(&mut rng).unwrap();
+
+        let mut gate_state = 0;
+        let mut num_gated_transitions = 0;
+        for (index, transition) in events {
+            if *index == 0 {
+                gate_state = transition.to();
+            } else {
+                assert_eq!(1, gate_state);
+                num_gated_transitions += 1;
+            }
+        }
+        assert!(num_gated_transitions > 0);
+        assert!(events
+            .windows(2)
+            .all(|pair| pair[0].1.time() <= pair[1].1.time()));
+        assert!(events
+            .iter()
+            .all(|(_, transition)| transition.time() <= 50.0));
+    }
+
+    #[test]
+    fn coupled_ensemble_rejects_mismatched_ctrl_params() {
+        let result = CoupledEnsemble::new(
+            vec![two_state_stepper()],
+            vec![],
+            1.0,
+            |_: usize, _: &Transition, _: &mut [Vec<f64>]| {},
+        );
+
+        assert!(matches!(
+            result,
+            Err(StateMachineError::NumElems {
+                actual: 0,
+                expected: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn visit_sequence_matches_step_until() {
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 2.0, 1.0, -1.0, 1.0, 3.0, 1.0, -1.0],
+            shape: (3, 3),
+        };
+        let mut step_until = StepUntil::new(Stepper::new(0, rate_constants.clone()), 20.0).unwrap();
+        let mut visit_sequence = VisitSequence::new(Stepper::new(0, rate_constants), 20.0).unwrap();
+
+        let transitions = step_until
+            .accumulate(&[], &mut StdRng::seed_from_u64(42))
+            .unwrap();
+        let states = visit_sequence
+            .accumulate(&[], &mut StdRng::seed_from_u64(42))
+            .unwrap();
+
+        assert!(!states.is_empty());
+        assert_eq!(
+            transitions.iter().map(|t| t.to()).collect::<Vec<_>>(),
+            states
+        );
+    }
+
+    #[test]
+    fn visit_sequence_matches_step_until_on_absorption() {
+        // 0 -> 1 -> 2 with state 2 absorbing
+        let rate_constants = Array2D::new(
+            vec![-1.0, 1.0, -1.0, -1.0, -1.0, 1.0, -1.0, -1.0, -1.0],
+            (3, 3),
+        )
+        .unwrap();
+        let mut step_until =
+            StepUntil::new(Stepper::new(0, rate_constants.clone()), f64::INFINITY).unwrap();
+        let mut visit_sequence =
+            VisitSequence::new(Stepper::new(0, rate_constants), f64::INFINITY).unwrap();
+
+        let transitions = step_until
+            .accumulate(&[], &mut StdRng::seed_from_u64(42))
+            .unwrap();
+        let states = visit_sequence
+            .accumulate(&[], &mut StdRng::seed_from_u64(42))
+            .unwrap();
+
+        assert_eq!(&[1, 2], states);
+        assert_eq!(
+            transitions.iter().map(|t| t.to()).collect::<Vec<_>>(),
+            states
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn step_until_accumulate_timed_stops_early() {
+        let mut rng = rand::thread_rng();
+        let mut accumulator = StepUntil::new(two_state_stepper(), f64::INFINITY).unwrap();
+
+        let (transitions, reason) = accumulator
+            .accumulate_timed(&[], &mut rng, Duration::ZERO)
+            .unwrap();
+
+        assert_eq!(TerminationReason::TimedOut, reason);
+        assert!(!transitions.is_empty());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn step_until_accumulate_timed_reaches_cutoff() {
+        let mut rng = rand::thread_rng();
+        let mut accumulator = StepUntil::new(two_state_stepper(), 5.0).unwrap();
+
+        let (_, reason) = accumulator
+            .accumulate_timed(&[], &mut rng, Duration::from_secs(60))
+            .unwrap();
+
+        assert_eq!(TerminationReason::Cutoff, reason);
+    }
+
+    #[test]
+    fn step_until_accumulate_interruptible() {
+        let mut rng = rand::thread_rng();
+        let mut accumulator = StepUntil::new(two_state_stepper(), f64::INFINITY).unwrap();
+        let mut num_checks = 0;
+
+        let result = accumulator.accumulate_interruptible(&[], &mut rng, 10, || {
+            num_checks += 1;
+            num_checks < 5
+        });
+
+        assert!(matches!(result, Err(StateMachineError::Interrupted)));
+        assert_eq!(5, num_checks);
+    }
+
+    #[test]
+    fn step_until_piecewise_rejects_invalid_schedules() {
+        for schedule in [
+            vec![],
+            vec![(1.0, vec![])],
+            vec![(0.0, vec![]), (2.0, vec![]), (2.0, vec![])],
+            vec![(0.0, vec![]), (f64::NAN, vec![])],
+        ] {
+            assert!(matches!(
+                StepUntilPiecewise::new(two_state_stepper(), schedule, 10.0),
+                Err(StateMachineError::InvalidSchedule)
+            ));
+        }
+    }
+
+    #[test]
+    fn step_until_piecewise_follows_schedule() {
+        let mut rng = StdRng::seed_from_u64(4);
+        // The rates are c * [[-1, 1], [1, -1]], so the machine is stopped while c = 0
+        let stepper = Stepper::new(0, Array2D::new(vec![-1.0, 1.0, 1.0, -1.0], (2, 2)).unwrap())
+            .with_rate_coefficients(Array4D::new(vec![-1.0, 1.0, 1.0, -1.0], (1, 1, 2, 2)).unwrap())
+            .unwrap();
+        let schedule = vec![(0.0, vec![1.0]), (10.0, vec![0.0]), (20.0, vec![1.0])];
+        let mut acc = StepUntilPiecewise::new(stepper, schedule, 30.0).unwrap();
+
+        let transitions = acc.accumulate(&mut rng).unwrap();
+
+        assert!(transitions.iter().any(|t| t.time < 10.0));
+        assert!(transitions.iter().all(|t| t.time <= 10.0 || t.time > 20.0));
+        assert!(transitions.iter().any(|t| t.time > 20.0 && t.time <= 30.0));
+        assert!(transitions.windows(2).all(|w| w[0].to == w[1].from));
+    }
+
+    #[test]
+    fn accumulate_full_returns_final_state_and_time() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let mut acc = StepUntil::new(two_state_stepper(), 10.0).unwrap();
+
+        let (transitions, final_state, final_time) = acc.accumulate_full(&[], &mut rng).unwrap();
+
+        let last = transitions.last().unwrap();
+        assert_eq!(last.to, final_state);
+        assert_eq!(last.time, final_time);
+    }
+
+    #[test]
+    fn accumulate_reversed_yields_newest_first() {
+        let mut forward = StepUntil::new(two_state_stepper(), 10.0).unwrap();
+        let expected: Vec<f64> = forward
+            .accumulate(&[], &mut StdRng::seed_from_u64(8))
+            .unwrap()
+            .iter()
+            .map(|t| t.time)
+            .collect();
+        let mut backward = StepUntil::new(two_state_stepper(), 10.0).unwrap();
+
+        let reversed: Vec<f64> = backward
+            .accumulate_reversed(&[], &mut StdRng::seed_from_u64(8))
+            .unwrap()
+            .map(|t| t.time)
+            .collect();
+
+        assert!(expected.len() > 1);
+        assert_eq!(expected.into_iter().rev().collect::<Vec<_>>(), reversed);
+    }
+
+    #[test]
+    fn accumulate_full_without_transitions() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let stepper = Stepper::new(
+            1,
+            Array2D::new(vec![-1.0, 1e-9, 1e-9, -1.0], (2, 2)).unwrap(),
+        );
+        let mut acc = StepUntil::with_start_time(stepper, 5.0, 1e-3).unwrap();
+
+        let (transitions, final_state, final_time) = acc.accumulate_full(&[], &mut rng).unwrap();
+
+        assert!(transitions.is_empty());
+        assert_eq!(1, final_state);
+        assert_eq!(5.0, final_time);
+    }
+
+    #[test]
+    fn step_until_preserves_dwell_times() {
+        let mut rng = StdRng::seed_from_u64(12);
+        let mut acc = StepUntil::new(two_state_stepper(), 20.0).unwrap();
+
+        let transitions = acc.accumulate(&[], &mut rng).unwrap();
+
+        assert!(transitions.len() > 2);
+        assert_eq!(transitions[0].time(), transitions[0].dwell());
+        for pair in transitions.windows(2) {
+            assert!((pair[1].time() - pair[0].time() - pair[1].dwell()).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn step_until_dwell_times_sum_to_final_time() {
+        let mut rng = StdRng::seed_from_u64(14);
+        let mut acc = StepUntil::with_start_time(two_state_stepper(), 3.0, 50.0).unwrap();
+
+        let (transitions, _, final_time) = acc.accumulate_full(&[], &mut rng).unwrap();
+        let total_dwell: f64 = transitions.iter().map(|t| t.dwell()).sum();
+
+        assert!(transitions.len() > 10);
+        assert!((3.0 + total_dwell - final_time).abs() < 1e-9);
+    }
+
+    #[test]
+    fn accumulate_both_intervals_reconstruct_times() {
+        let mut rng = StdRng::seed_from_u64(23);
+        let mut acc = StepUntil::with_start_time(two_state_stepper(), 2.0, 10.0).unwrap();
+
+        let (transitions, intervals) = acc.accumulate_both(&[], &mut rng).unwrap();
+
+        assert!(transitions.len() > 1);
+        assert_eq!(transitions.len(), intervals.len());
+        let mut time = 2.0;
+        for (transition, interval) in transitions.iter().zip(intervals) {
+            time += interval;
+            assert!((transition.time() - time).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn accumulate_rle_reconstructs_trajectory() {
+        let mut acc = StepUntil::new(two_state_stepper(), 10.0).unwrap();
+        let transitions = acc
+            .accumulate(&[], &mut StdRng::seed_from_u64(20))
+            .unwrap()
+            .to_vec();
+        let mut acc = StepUntil::new(two_state_stepper(), 10.0).unwrap();
+
+        let runs = acc
+            .accumulate_rle(&[], &mut StdRng::seed_from_u64(20))
+            .unwrap();
+
+        assert_eq!(transitions.len() + 1, runs.len());
+        assert_eq!(0, runs[0].0);
+        let mut t_end = 0.0;
+        for ((state, duration), transition) in runs.iter().zip(transitions.iter()) {
+            t_end += duration;
+            assert_eq!(*state, transition.from());
+            assert!((t_end - transition.time()).abs() < 1e-9);
+        }
+        assert_eq!(transitions.last().unwrap().to(), runs.last().unwrap().0);
+        let total: f64 = runs.iter().map(|(_, duration)| duration).sum();
+        assert!((total - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn count_category_time_sums_to_cutoff() {
+        let mut rng = StdRng::seed_from_u64(13);
+        // States 0 and 1 are dark, state 2 is bright
+        let stepper = Stepper::new(
+            0,
+            Array2D::new(vec![-1.0, 1.0, 1.0, 1.0, -1.0, 1.0, 1.0, 1.0, -1.0], (3, 3)).unwrap(),
+        );
+        let mut acc = StepUntil::new(stepper, 3000.0).unwrap();
+
+        let totals = acc
+            .count_category_time(&[], &mut rng, Some(&[0, 0, 1]))
+            .unwrap();
+
+        assert_eq!(2, totals.len());
+        assert!((totals.iter().sum::<f64>() - 3000.0).abs() < 1e-6);
+        // Each state is occupied a third of the time
+        assert!((totals[1] / 3000.0 - 1.0 / 3.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn count_category_time_after_absorption() {
+        let mut rng = StdRng::seed_from_u64(13);
+        let stepper = Stepper::new(
+            0,
+            Array2D::new(vec![-1.0, 1.0, -1.0, -1.0], (2, 2)).unwrap(),
+        );
+        let mut acc = StepUntil::new(stepper, 1000.0).unwrap();
+
+        let totals = acc
+            .count_category_time(&[], &mut rng, Some(&[0, 1]))
+            .unwrap();
+
+        assert!((totals.iter().sum::<f64>() - 1000.0).abs() < 1e-9);
+        assert!(totals[1] > totals[0]);
+        // The machine is stopped, so all the time of the next run is spent in the absorbing state
+        let totals = acc
+            .count_category_time(&[], &mut rng, Some(&[0, 1]))
+            .unwrap();
+        assert_eq!(vec![0.0, 1000.0], totals);
+    }
+
+    #[test]
+    fn count_category_time_uses_stepper_categories() {
+        let stepper = two_state_stepper().with_categories(vec![1, 0]).unwrap();
+        let mut acc = StepUntil::with_start_time(stepper, 50.0, 100.0).unwrap();
+
+        let totals = acc
+            .count_category_time(&[], &mut StdRng::seed_from_u64(13), None)
+            .unwrap();
+
+        assert_eq!(2, totals.len());
+        assert!((totals.iter().sum::<f64>() - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn count_category_time_rejects_wrong_number_of_categories() {
+        let mut acc = StepUntil::new(two_state_stepper(), 100.0).unwrap();
+        let mut rng = StdRng::seed_from_u64(13);
+
+        for categories in [None, Some([0].as_slice()), Some([0, 1, 1].as_slice())] {
+            assert!(matches!(
+                acc.count_category_time(&[], &mut rng, categories),
+                Err(StateMachineError::NumElems { expected: 2, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn ring_buffer_keeps_last_transitions() {
+        let mut ring = RingBuffer::new(two_state_stepper(), 100.0, 5).unwrap();
+        let mut full = StepUntil::new(two_state_stepper(), 100.0).unwrap();
+
+        let kept = ring
+            .accumulate(&[], &mut StdRng::seed_from_u64(14))
+            .unwrap()
+            .to_vec();
+        let all = full
+            .accumulate(&[], &mut StdRng::seed_from_u64(14))
+            .unwrap();
+
+        assert!(all.len() > 5);
+        assert_eq!(5, kept.len());
+        for (kept, expected) in kept.iter().zip(&all[all.len() - 5..]) {
+            assert_eq!(expected.time(), kept.time());
+            assert_eq!(expected.to(), kept.to());
+        }
+    }
+
+    #[test]
+    fn ring_buffer_ends_on_absorption() {
+        let mut rng = StdRng::seed_from_u64(14);
+        // 0 -> 1 -> 2 with state 2 absorbing
+        let stepper = Stepper::new(
+            0,
+            Array2D::new(
+                vec![-1.0, 1.0, -1.0, -1.0, -1.0, 1.0, -1.0, -1.0, -1.0],
+                (3, 3),
+            )
+            .unwrap(),
+        );
+        let mut ring = RingBuffer::new(stepper, f64::INFINITY, 1).unwrap();
+
+        let kept = ring.accumulate(&[], &mut rng).unwrap();
+
+        assert_eq!(1, kept.len());
+        assert_eq!(1, kept[0].from());
+        assert_eq!(2, kept[0].to());
+    }
+
+    #[test]
+    fn step_until_edge_count_stops_after_count_edges() {
+        let mut rng = StdRng::seed_from_u64(16);
+        // 0 -> 1 -> 2 -> 0 with shortcuts back from 2 to 1
+        let stepper = Stepper::new(
+            0,
+            Array2D::new(
+                vec![-1.0, 1.0, -1.0, -1.0, -1.0, 1.0, 1.0, 1.0, -1.0],
+                (3, 3),
+            )
+            .unwrap(),
+        );
+        let mut acc = StepUntilEdgeCount::new(stepper, 2, 0, 5);
+
+        let transitions = acc.accumulate(&[], &mut rng).unwrap();
+
+        let is_edge = |t: &Transition| t.from() == 2 && t.to() == 0;
+        assert_eq!(5, transitions.iter().filter(|t| is_edge(t)).count());
+        assert!(is_edge(transitions.last().unwrap()));
+        assert!(transitions.windows(2).all(|w| w[0].time() < w[1].time()));
+    }
+
+    #[test]
+    fn step_until_edge_count_ends_on_cutoff_or_absorption() {
+        let mut rng = StdRng::seed_from_u64(16);
+        // The edge from 1 to 0 is never taken, and state 2 is absorbing
+        let stepper = Stepper::new(
+            0,
+            Array2D::new(
+                vec![-1.0, 1.0, -1.0, -1.0, -1.0, 1.0, -1.0, -1.0, -1.0],
+                (3, 3),
+            )
+            .unwrap(),
+        );
+        let mut acc = StepUntilEdgeCount::new(stepper.clone(), 1, 0, 1);
+        let transitions = acc.accumulate(&[], &mut rng).unwrap();
+        assert_eq!(2, transitions.len());
+        assert_eq!(2, transitions[1].to());
+
+        let mut acc = StepUntilEdgeCount::new(two_state_stepper(), 1, 0, usize::MAX)
+            .with_cutoff(50.0)
+            .unwrap();
+        let transitions = acc.accumulate(&[], &mut rng).unwrap();
+        assert!(!transitions.is_empty());
+        assert!(transitions.iter().all(|t| t.time() <= 50.0));
+
+        assert!(matches!(
+            StepUntilEdgeCount::new(stepper, 1, 0, 1).with_cutoff(0.0),
+            Err(StateMachineError::InvalidCutoff(_))
+        ));
+    }
+
+    #[test]
+    fn step_until_any_state_reports_target() {
+        let mut rng = StdRng::seed_from_u64(18);
+        // 0 <-> 1 with exits from 1 to the products 2 and 3, which are three times more likely
+        let stepper = Stepper::new(
+            0,
+            Array2D::new(
+                vec![
+                    -1.0, 1.0, -1.0, -1.0, 1.0, -1.0, 1.0, 3.0, -1.0, -1.0, -1.0, -1.0, -1.0, -1.0,
+                    -1.0, -1.0,
+                ],
+                (4, 4),
+            )
+            .unwrap(),
+        );
+
+        let mut num_hits = [0; 4];
+        for _ in 0..1000 {
+            let mut acc = StepUntilAnyState::new(stepper.clone(), vec![2, 3]);
+            assert_eq!(None, acc.hit_target());
+
+            let last = acc.accumulate(&[], &mut rng).unwrap().last().unwrap().to();
+
+            assert_eq!(Some(last), acc.hit_target());
+            num_hits[last] += 1;
+        }
+
+        assert_eq!(1000, num_hits[2] + num_hits[3]);
+        assert!((num_hits[3] as f64 / 1000.0 - 0.75).abs() < 0.05);
+    }
+
+    #[test]
+    fn step_until_any_state_ends_on_cutoff_or_absorption() {
+        let mut rng = StdRng::seed_from_u64(18);
+        // 0 -> 1 with state 1 absorbing, and state 2 unreachable
+        let absorbing = Stepper::new(
+            0,
+            Array2D::new(
+                vec![-1.0, 1.0, -1.0, -1.0, -1.0, -1.0, -1.0, -1.0, -1.0],
+                (3, 3),
+            )
+            .unwrap(),
+        );
+        let mut acc = StepUntilAnyState::new(absorbing, vec![2]);
+        let transitions = acc.accumulate(&[], &mut rng).unwrap();
+        assert_eq!(1, transitions.len());
+        assert_eq!(None, acc.hit_target());
+
+        // 0 <-> 1, and state 2 unreachable
+        let cycling = Stepper::new(
+            0,
+            Array2D::new(
+                vec![-1.0, 1.0, -1.0, 1.0, -1.0, -1.0, -1.0, -1.0, -1.0],
+                (3, 3),
+            )
+            .unwrap(),
+        );
+        let mut acc = StepUntilAnyState::new(cycling, vec![2])
+            .with_cutoff(50.0)
+            .unwrap();
+        let transitions = acc.accumulate(&[], &mut rng).unwrap();
+        assert!(!transitions.is_empty());
+        assert!(transitions.iter().all(|t| t.time() <= 50.0));
+        assert_eq!(None, acc.hit_target());
+    }
+
+    #[test]
+    fn map_accumulate_relabels_states() {
+        // States 0 and 1 form macrostate 0, and states 2 and 3 form macrostate 1
+        let stepper = Stepper::new(
+            0,
+            Array2D::new(
+                vec![
+                    -1.0, 1.0, 1.0, -1.0, 1.0, -1.0, -1.0, 1.0, 1.0, -1.0, -1.0, 1.0, -1.0, 1.0,
+                    1.0, -1.0,
+                ],
+                (4, 4),
+            )
+            .unwrap(),
+        );
+        let mut plain = StepUntil::new(stepper.clone(), 20.0).unwrap();
+        let mut mapped = MapAccumulate::new(StepUntil::new(stepper, 20.0).unwrap(), |t| {
+            t.map_states(|state| state / 2)
+        });
+
+        let expected = plain
+            .accumulate(&[], &mut StdRng::seed_from_u64(19))
+            .unwrap()
+            .to_vec();
+        let transitions = mapped
+            .accumulate(&[], &mut StdRng::seed_from_u64(19))
+            .unwrap();
+
+        assert!(!expected.is_empty());
+        assert_eq!(expected.len(), transitions.len());
+        for (original, transition) in expected.iter().zip(transitions) {
+            assert_eq!(original.from() / 2, transition.from());
+            assert_eq!(original.to() / 2, transition.to());
+            assert_eq!(original.time(), transition.time());
+            assert_eq!(original.dwell(), transition.dwell());
+        }
+        assert!(expected.iter().any(|t| t.to() > 1));
+    }
+
+    #[test]
+    fn weighted_ensemble_samples_rare_state() {
+        let mut rng = StdRng::seed_from_u64(17);
+        // A chain 0 <-> 1 <-> 2 <-> 3 with a steep barrier: up at rate 0.05, down at rate 1
+        let (up, down) = (0.05, 1.0);
+        let mut data = vec![-1.0; 16];
+        for i in 0..3 {
+            data[i * 4 + i + 1] = up;
+            data[(i + 1) * 4 + i] = down;
+        }
+        let stepper = Stepper::new(0, Array2D::new(data, (4, 4)).unwrap());
+        let ratio: f64 = up / down;
+        let exact = ratio.powi(3) / (0..4).map(|k| ratio.powi(k)).sum::<f64>();
+
+        let walkers_per_bin = 10;
+        let mut ensemble =
+            WeightedEnsemble::new(stepper.clone(), |state| state, walkers_per_bin, 1.0).unwrap();
+        let mut estimates = Vec::new();
+        for iteration in 0..5000 {
+            ensemble.iterate(&[], &mut rng).unwrap();
+            assert!((ensemble.weights().iter().sum::<f64>() - 1.0).abs() < 1e-9);
+            if iteration >= 200 {
+                estimates.push(ensemble.weight_in(3));
+            }
+        }
+        let weighted = estimates.iter().sum::<f64>() / estimates.len() as f64;
+
+        // Naive sampling with as many machines as the weighted ensemble can hold
+        let num_machines = 4 * walkers_per_bin;
+        let num_in_target = (0..num_machines)
+            .filter(|_| {
+                let mut acc = StepUntil::new(stepper.clone(), 400.0).unwrap();
+                let transitions = acc.accumulate(&[], &mut rng).unwrap();
+                transitions.last().is_some_and(|t| t.to() == 3)
+            })
+            .count();
+        let naive = num_in_target as f64 / num_machines as f64;
+
+        assert!((weighted - exact).abs() < 0.3 * exact);
+        assert!((weighted - exact).abs() < (naive - exact).abs());
+    }
+
+    #[test]
+    fn step_until_events_forces_scheduled_states() {
+        let mut rng = StdRng::seed_from_u64(21);
+        // 0 -> 1 -> 2 with state 2 absorbing, but events pull the machine back to 0
+        let stepper = Stepper::new(
+            0,
+            Array2D::new(
+                vec![-1.0, 1.0, -1.0, -1.0, -1.0, 1.0, -1.0, -1.0, -1.0],
+                (3, 3),
+            )
+            .unwrap(),
+        );
+        let mut acc = StepUntilEvents::new(stepper, vec![(20.0, 0), (40.0, 0)], 50.0).unwrap();
+
+        let transitions = acc.accumulate(&[], &mut rng).unwrap();
+
+        // The machine is absorbed long before each event, so the events override its dynamics
+        let forced: Vec<&Transition> = transitions.iter().filter(|t| t.to() == 0).collect();
+        assert_eq!(2, forced.len());
+        for (transition, t_event) in forced.iter().zip([20.0, 40.0]) {
+            assert_eq!(2, transition.from());
+            assert_eq!(t_event, transition.time());
+        }
+        assert_eq!(6, transitions.iter().filter(|t| t.time() <= 40.0).count());
+        assert!(transitions.windows(2).all(|w| w[0].to() == w[1].from()));
+        assert!(transitions.windows(2).all(|w| w[0].time() <= w[1].time()));
+        for pair in transitions.windows(2) {
+            assert!((pair[1].time() - pair[0].time() - pair[1].dwell()).abs() < 1e-9);
+        }
+        assert!((acc.stepper().current_time() - 50.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn step_until_events_rejects_invalid_events() {
+        for events in [
+            vec![(-1.0, 0)],
+            vec![(f64::NAN, 0)],
+            vec![(2.0, 0), (1.0, 1)],
+        ] {
+            assert!(matches!(
+                StepUntilEvents::new(two_state_stepper(), events, 10.0),
+                Err(StateMachineError::InvalidEvents)
+            ));
+        }
+        assert!(matches!(
+            StepUntilEvents::new(two_state_stepper(), vec![(1.0, 2)], 10.0),
+            Err(StateMachineError::StateOutOfRange {
+                state: 2,
+                num_states: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn step_until_piecewise_dwell_spans_segments() {
+        let mut rng = StdRng::seed_from_u64(4);
+        // The rates are c * [[-1, 1], [1, -1]], so the machine is stopped while c = 0
+        let stepper = Stepper::new(0, Array2D::new(vec![-1.0, 1.0, 1.0, -1.0], (2, 2)).unwrap())
+            .with_rate_coefficients(Array4D::new(vec![-1.0, 1.0, 1.0, -1.0], (1, 1, 2, 2)).unwrap())
+            .unwrap();
+        let schedule = vec![(0.0, vec![1.0]), (10.0, vec![0.0]), (20.0, vec![1.0])];
+        let mut acc = StepUntilPiecewise::new(stepper, schedule, 30.0).unwrap();
+
+        let transitions = acc.accumulate(&mut rng).unwrap().to_vec();
+
+        assert_eq!(transitions[0].time, transitions[0].dwell);
+        for w in transitions.windows(2) {
+            assert!((w[1].dwell - (w[1].time - w[0].time)).abs() < 1e-12);
+        }
+        let first_after = transitions.iter().find(|t| t.time > 20.0).unwrap();
+        assert!(first_after.dwell > first_after.time - 20.0);
+        assert!((acc.stepper().current_time() - 30.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn coupled_ensemble_dwell_spans_accumulations() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let mut ensemble = CoupledEnsemble::new(
+            vec![two_state_stepper(), two_state_stepper()],
+            vec![vec![], vec![]],
+            5.0,
+            |_: usize, _: &Transition, _: &mut [Vec<f64>]| {},
+        )
+        .unwrap();
+
+        let mut t_offset = 0.0;
+        let mut last_times = [0.0, 0.0];
+        for _ in 0..4 {
+            let events = ensemble.accumulate(&mut rng).unwrap();
+            assert!(!events.is_empty());
+            for (index, transition) in events {
+                let time = t_offset + transition.time();
+                assert!((transition.dwell() - (time - last_times[*index])).abs() < 1e-9);
+                last_times[*index] = time;
+            }
+            t_offset += 5.0;
+        }
+
+        for stepper in ensemble.steppers() {
+            assert!((stepper.current_time() - 20.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn weighted_ensemble_rejects_empty_bins() {
+        assert!(matches!(
+            WeightedEnsemble::new(two_state_stepper(), |state| state, 0, 1.0),
+            Err(StateMachineError::InvalidWalkersPerBin)
+        ));
+    }
+}
@@ -0,0 +1,1999 @@
+//! Functions for analyzing the transitions produced by an ensemble of state machines.
+//!
+//! Unless stated otherwise, the functions in this module expect transitions whose times are
+//! absolute, i.e. as returned by an accumulator, rather than the waiting times returned by
+//! `Step::step`.
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+
+use crate::arrays::Array2D;
+use crate::{Rate, Result, State, StateMachineError, Time, Transition};
+
+/// The largest product of the total exit rate and the step size used by the ODE integrator.
+const MAX_RATE_TIMES_STEP: f64 = 0.01;
+
+/// Returns the state of a machine at each time of a grid.
+///
+/// The machine is in `initial` until its first transition. A transition that occurs exactly at a
+/// grid time is considered to have already happened.
+pub fn sample_on_grid(transitions: &[Transition], initial: State, grid: &[Time]) -> Vec<State> {
+    grid.iter()
+        .map(|t| {
+            // Transition times are non-decreasing, so a binary search finds the last transition
+            // that occurred at or before t
+            let num_past = transitions.partition_point(|transition| transition.time <= *t);
+            if num_past == 0 {
+                initial
+            } else {
+                transitions[num_past - 1].to
+            }
+        })
+        .collect()
+}
+
+/// Returns the fraction of machines in each state at each time of a grid.
+///
+/// The result is a `grid.len()` x `num_states` matrix, i.e. the empirical probability P(state, t).
+/// All machines are assumed to start in the same `initial` state.
+///
+/// Panics if a machine visits a state that is greater than or equal to `num_states`.
+pub fn occupancy_over_time(
+    results: &[Vec<Transition>],
+    num_states: usize,
+    initial: State,
+    grid: &[Time],
+) -> Vec<Vec<f64>> {
+    let mut occupancy = vec![vec![0f64; num_states]; grid.len()];
+    if results.is_empty() {
+        return occupancy;
+    }
+
+    for transitions in results {
+        for (row, state) in sample_on_grid(transitions, initial, grid)
+            .into_iter()
+            .enumerate()
+        {
+            occupancy[row][state] += 1.0;
+        }
+    }
+
+    let num_machines = results.len() as f64;
+    for row in occupancy.iter_mut() {
+        for fraction in row.iter_mut() {
+            *fraction /= num_machines;
+        }
+    }
+
+    occupancy
+}
+
+/// Returns the state of a machine after its last transition.
+pub fn final_state(transitions: &[Transition], initial: State) -> State {
+    transitions
+        .last()
+        .map_or(initial, |transition| transition.to)
+}
+
+/// Returns the largest state index a machine visited, including its initial state.
+///
+/// This is e.g. the maximum length of a queue whose states count the queued items.
+pub fn max_state_visited(transitions: &[Transition], initial: State) -> State {
+    transitions
+        .iter()
+        .map(|transition| transition.to)
+        .fold(initial, State::max)
+}
+
+/// Checks that a trajectory is consistent, e.g. after it was constructed outside of this crate.
+///
+/// The times must be non-decreasing, so a NaN time following another transition is an error, and
+/// each transition must start in the state in which the previous one ended. The first violation
+/// is returned as a `TimeInversion` or `BrokenChain` error with the index of the offending
+/// transition.
+pub fn validate_trajectory(transitions: &[Transition]) -> Result<()> {
+    for (index, pair) in transitions.windows(2).enumerate() {
+        let (previous, transition) = (&pair[0], &pair[1]);
+        if transition.time < previous.time || transition.time.is_nan() {
+            return Err(StateMachineError::TimeInversion {
+                index: index + 1,
+                time: transition.time,
+                previous: previous.time,
+            });
+        }
+        if transition.from != previous.to {
+            return Err(StateMachineError::BrokenChain {
+                index: index + 1,
+                from: transition.from,
+                previous_to: previous.to,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the total time a machine spent in each state between t = 0 and `t_end`.
+///
+/// Panics if the machine visits a state that is greater than or equal to `num_states`.
+pub fn occupation_times(
+    transitions: &[Transition],
+    num_states: usize,
+    initial: State,
+    t_end: Time,
+) -> Vec<Time> {
+    let mut times = vec![0f64; num_states];
+
+    let mut state = initial;
+    let mut t_entered: Time = 0.0;
+    for transition in transitions.iter().take_while(|t| t.time <= t_end) {
+        times[state] += transition.time - t_entered;
+        state = transition.to;
+        t_entered = transition.time;
+    }
+    times[state] += (t_end - t_entered).max(0.0);
+
+    times
+}
+
+/// Returns the fraction of the total time that an ensemble of machines spent in each state, where
+/// each machine is observed from t = 0 until its last transition.
+///
+/// `initials[i]` is the state in which machine i started.
+fn empirical_occupation(
+    results: &[Vec<Transition>],
+    initials: &[State],
+    num_states: usize,
+) -> Vec<f64> {
+    let mut occupation = vec![0f64; num_states];
+    for (transitions, initial) in results.iter().zip(initials.iter()) {
+        let t_end = transitions.last().map_or(0.0, |transition| transition.time);
+        let times = occupation_times(transitions, num_states, *initial, t_end);
+        for (total, time) in occupation.iter_mut().zip(times) {
+            *total += time;
+        }
+    }
+    let total_time: Time = occupation.iter().sum();
+
+    occupation.iter().map(|time| time / total_time).collect()
+}
+
+/// Returns a `num_states` x `num_states` matrix whose element (i, j) is the number of transitions
+/// from state i to state j.
+///
+/// Panics if a transition involves a state that is greater than or equal to `num_states`.
+pub fn transition_counts(transitions: &[Transition], num_states: usize) -> Vec<Vec<usize>> {
+    transitions.iter().cloned().transition_counts(num_states)
+}
+
+/// Returns the durations of all the completed visits to each state.
+///
+/// A visit is completed when the machine leaves the state, so the time spent in the final state
+/// is not included.
+///
+/// Panics if the machine visits a state that is greater than or equal to `num_states`.
+pub fn dwell_times(
+    transitions: &[Transition],
+    num_states: usize,
+    initial: State,
+) -> Vec<Vec<Time>> {
+    transitions.iter().cloned().dwell_times(num_states, initial)
+}
+
+/// Returns the transitions with the self-transitions, i.e. those whose `from` and `to` states
+/// are equal, merged into the next change of state.
+///
+/// Because the times are absolute, the time of the transition that ends a run of
+/// self-transitions already includes the sum of their waiting times, so the run is dropped and
+/// its dwell times are added to the dwell time of that transition. Self-transitions after the
+/// last change of state are dropped as well.
+pub fn merge_self_transitions(transitions: &[Transition]) -> Vec<Transition> {
+    let mut merged = Vec::new();
+    let mut pending_dwell: Time = 0.0;
+    for transition in transitions {
+        if transition.from == transition.to {
+            pending_dwell += transition.dwell;
+        } else {
+            merged.push(Transition {
+                dwell: pending_dwell + transition.dwell,
+                ..transition.clone()
+            });
+            pending_dwell = 0.0;
+        }
+    }
+
+    merged
+}
+
+/// Returns the durations of all the completed visits to each category of states.
+///
+/// `categories[i]` is the category of state i, e.g. as set by `Stepper::with_categories`, and the
+/// result has one group per category from 0 to the largest one. Consecutive transitions between
+/// states of the same category belong to the same visit, so this is the dwell time of the
+/// coarse-grained trajectory. The time spent in the final category is not included.
+///
+/// Panics if the machine visits a state that has no category.
+pub fn category_dwell_times(
+    transitions: &[Transition],
+    categories: &[u8],
+    initial: State,
+) -> Vec<Vec<Time>> {
+    let num_categories = categories
+        .iter()
+        .max()
+        .map_or(0, |max| usize::from(*max) + 1);
+    let mut dwells = vec![Vec::new(); num_categories];
+
+    let mut current = categories[initial];
+    let mut t_entered: Time = 0.0;
+    for transition in transitions {
+        let next = categories[transition.to];
+        if next != current {
+            dwells[usize::from(current)].push(transition.time - t_entered);
+            current = next;
+            t_entered = transition.time;
+        }
+    }
+
+    dwells
+}
+
+/// Summaries of a stream of transitions, e.g. `transitions.into_iter().transition_counts(2)`.
+///
+/// This trait is implemented for all iterators over transitions with absolute times.
+pub trait TransitionIteratorExt: Iterator<Item = Transition> + Sized {
+    /// Returns a `num_states` x `num_states` matrix whose element (i, j) is the number of
+    /// transitions from state i to state j.
+    ///
+    /// Panics if a transition involves a state that is greater than or equal to `num_states`.
+    fn transition_counts(self, num_states: usize) -> Vec<Vec<usize>> {
+        self.fold(
+            vec![vec![0usize; num_states]; num_states],
+            |mut counts, transition| {
+                counts[transition.from][transition.to] += 1;
+                counts
+            },
+        )
+    }
+
+    /// Returns the durations of all the completed visits to each state.
+    ///
+    /// Panics if the machine visits a state that is greater than or equal to `num_states`.
+    fn dwell_times(self, num_states: usize, initial: State) -> Vec<Vec<Time>> {
+        let mut dwells = vec![Vec::new(); num_states];
+
+        let mut state = initial;
+        let mut t_entered: Time = 0.0;
+        for transition in self {
+            dwells[state].push(transition.time - t_entered);
+            state = transition.to;
+            t_entered = transition.time;
+        }
+
+        dwells
+    }
+}
+
+impl<I: Iterator<Item = Transition>> TransitionIteratorExt for I {}
+
+/// Returns the number of transitions of each machine of an ensemble.
+pub fn trajectory_lengths(results: &[Vec<Transition>]) -> Vec<usize> {
+    results
+        .iter()
+        .map(|transitions| transitions.len())
+        .collect()
+}
+
+/// Returns the mean and the (population) standard deviation of the number of transitions per
+/// machine of an ensemble.
+///
+/// Both are NaN for an empty ensemble.
+pub fn trajectory_length_stats(results: &[Vec<Transition>]) -> (f64, f64) {
+    let lengths = trajectory_lengths(results);
+    let n = lengths.len() as f64;
+
+    let mean = lengths.iter().sum::<usize>() as f64 / n;
+    let variance = lengths
+        .iter()
+        .map(|length| (*length as f64 - mean).powi(2))
+        .sum::<f64>()
+        / n;
+
+    (mean, variance.sqrt())
+}
+
+/// Returns the times between consecutive transitions.
+///
+/// The result has one element less than `transitions`, so it is empty if there are fewer than two
+/// transitions. The time before the first transition is not included because the time at which
+/// the run started is not known from the transitions alone.
+pub fn inter_event_times(transitions: &[Transition]) -> Vec<Time> {
+    transitions
+        .windows(2)
+        .map(|pair| pair[1].time - pair[0].time)
+        .collect()
+}
+
+/// Returns the mean and the (population) variance of the times between consecutive transitions.
+///
+/// Both are NaN if there are fewer than two transitions.
+pub fn inter_event_time_stats(transitions: &[Transition]) -> (Time, Time) {
+    let gaps = inter_event_times(transitions);
+    let n = gaps.len() as f64;
+
+    let mean = gaps.iter().sum::<Time>() / n;
+    let variance = gaps.iter().map(|gap| (gap - mean).powi(2)).sum::<Time>() / n;
+
+    (mean, variance)
+}
+
+/// Returns the empirical survival function of first-passage times at each time of a grid, i.e. the
+/// fraction of the trajectories that have not reached the target by then.
+///
+/// Trajectories that never reached the target are censored and should be given a first-passage
+/// time of `Time::INFINITY`, so that they survive at all grid times. A passage that occurs exactly
+/// at a grid time is considered to have already happened. The result is NaN for every grid time
+/// if `first_passage_times` is empty.
+pub fn survival_function(first_passage_times: &[Time], grid: &[Time]) -> Vec<f64> {
+    let n = first_passage_times.len() as f64;
+    grid.iter()
+        .map(|t| {
+            first_passage_times
+                .iter()
+                .filter(|passage_time| *passage_time > t)
+                .count() as f64
+                / n
+        })
+        .collect()
+}
+
+/// The transitions produced by an ensemble of state machines, e.g. by `par_accumulate`.
+///
+/// The methods of this type aggregate over all the machines of the ensemble.
+#[derive(Clone, Debug)]
+pub struct EnsembleResult {
+    results: Vec<Vec<Transition>>,
+    initial_states: Vec<State>,
+    num_states: usize,
+}
+
+impl EnsembleResult {
+    /// Creates a new ensemble result.
+    ///
+    /// # Arguments
+    ///
+    /// - **results** The transitions of each machine, with absolute times
+    /// - **initial_states** The state in which each machine started
+    /// - **num_states** The number of states of the machines
+    pub fn new(
+        results: Vec<Vec<Transition>>,
+        initial_states: Vec<State>,
+        num_states: usize,
+    ) -> Result<Self> {
+        if results.len() != initial_states.len() {
+            return Err(StateMachineError::NumElems {
+                actual: initial_states.len(),
+                expected: results.len(),
+            });
+        }
+
+        let states = results
+            .iter()
+            .flatten()
+            .flat_map(|transition| [transition.from, transition.to])
+            .chain(initial_states.iter().copied());
+        for state in states {
+            if state >= num_states {
+                return Err(StateMachineError::StateOutOfRange { state, num_states });
+            }
+        }
+
+        Ok(EnsembleResult {
+            results,
+            initial_states,
+            num_states,
+        })
+    }
+
+    /// Returns the transitions of each machine.
+    pub fn results(&self) -> &[Vec<Transition>] {
+        &self.results
+    }
+
+    /// Consumes the ensemble result and returns the transitions of each machine.
+    pub fn into_results(self) -> Vec<Vec<Transition>> {
+        self.results
+    }
+
+    /// Returns the state in which each machine started.
+    pub fn initial_states(&self) -> &[State] {
+        &self.initial_states
+    }
+
+    /// Returns the number of states of the machines.
+    pub fn num_states(&self) -> usize {
+        self.num_states
+    }
+
+    /// Returns the number of machines in the ensemble.
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    /// Returns true if the ensemble contains no machines.
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    /// Appends the machines of `other`, e.g. the results of another batch of a sweep.
+    ///
+    /// The machines of `other` keep their order and initial states, and their indices are offset
+    /// by the number of machines already in the ensemble. Returns an error if the machines of both
+    /// ensembles have different numbers of states.
+    pub fn extend(&mut self, other: EnsembleResult) -> Result<()> {
+        if other.num_states != self.num_states {
+            return Err(StateMachineError::NumElems {
+                actual: other.num_states,
+                expected: self.num_states,
+            });
+        }
+
+        self.results.extend(other.results);
+        self.initial_states.extend(other.initial_states);
+
+        Ok(())
+    }
+
+    /// Returns the state of each machine after its last transition.
+    pub fn final_states(&self) -> Vec<State> {
+        self.results
+            .iter()
+            .zip(self.initial_states.iter())
+            .map(|(transitions, initial)| final_state(transitions, *initial))
+            .collect()
+    }
+
+    /// Returns the total time spent in each state by all machines between t = 0 and `t_end`.
+    pub fn occupation_times(&self, t_end: Time) -> Vec<Time> {
+        let mut totals = vec![0f64; self.num_states];
+        for (transitions, initial) in self.results.iter().zip(self.initial_states.iter()) {
+            let times = occupation_times(transitions, self.num_states, *initial, t_end);
+            for (total, time) in totals.iter_mut().zip(times) {
+                *total += time;
+            }
+        }
+
+        totals
+    }
+
+    /// Returns the number of transitions from state i to state j over all machines.
+    pub fn transition_counts(&self) -> Vec<Vec<usize>> {
+        let mut totals = vec![vec![0usize; self.num_states]; self.num_states];
+        for transitions in self.results.iter() {
+            let counts = transition_counts(transitions, self.num_states);
+            for (total_row, row) in totals.iter_mut().zip(counts) {
+                for (total, count) in total_row.iter_mut().zip(row) {
+                    *total += count;
+                }
+            }
+        }
+
+        totals
+    }
+
+    /// Returns the mean duration of the completed visits to each state over all machines.
+    ///
+    /// The mean is NaN for states that were never left.
+    pub fn mean_dwell_times(&self) -> Vec<Time> {
+        let mut sums = vec![0f64; self.num_states];
+        let mut counts = vec![0usize; self.num_states];
+        for (transitions, initial) in self.results.iter().zip(self.initial_states.iter()) {
+            for (state, dwells) in dwell_times(transitions, self.num_states, *initial)
+                .into_iter()
+                .enumerate()
+            {
+                sums[state] += dwells.iter().sum::<Time>();
+                counts[state] += dwells.len();
+            }
+        }
+
+        sums.into_iter()
+            .zip(counts)
+            .map(|(sum, count)| sum / count as f64)
+            .collect()
+    }
+
+    /// Returns `n_resamples` bootstrap replicates of a statistic of the ensemble.
+    ///
+    /// Each replicate is `statistic` evaluated on as many machines as the ensemble has, drawn
+    /// with replacement. The spread of the replicates estimates the uncertainty of the statistic,
+    /// e.g. for error bars. The resampling is deterministic for a given `seed`.
+    pub fn bootstrap<F>(&self, statistic: F, n_resamples: usize, seed: u64) -> Vec<f64>
+    where
+        F: Fn(&[Vec<Transition>]) -> f64,
+    {
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
+        let n = self.results.len();
+        let mut resample: Vec<Vec<Transition>> = Vec::with_capacity(n);
+
+        (0..n_resamples)
+            .map(|_| {
+                resample.clear();
+                resample.extend((0..n).map(|_| self.results[rng.gen_range(0..n)].clone()));
+                statistic(&resample)
+            })
+            .collect()
+    }
+
+    /// Returns the total variation distance between the state occupation of the machines and the
+    /// steady state of `rate_constants`.
+    ///
+    /// Like in `stationary_kl`, the empirical distribution is the fraction of the total time that
+    /// the machines spent in each state, where each machine is observed from t = 0 until its last
+    /// transition. Returns an error if `rate_constants` has a different number of states than
+    /// the machines or has no unique steady state.
+    pub fn tv_to_stationary(&self, rate_constants: &Array2D) -> Result<f64> {
+        if rate_constants.shape.0 != self.num_states {
+            return Err(StateMachineError::NumElems {
+                actual: rate_constants.shape.0,
+                expected: self.num_states,
+            });
+        }
+        let stationary = steady_state(rate_constants)?;
+        let empirical = empirical_occupation(&self.results, &self.initial_states, self.num_states);
+
+        total_variation(&empirical, &stationary)
+    }
+
+    /// Returns the maximum likelihood estimate of the rate constants of the machines.
+    ///
+    /// The rate from state i to state j is the number of transitions from i to j divided by the
+    /// total duration of the completed visits to i. Transitions that were never observed, the
+    /// diagonal, and the rows of states that were never left are set to -1, i.e. no transition.
+    pub fn estimate_rates(&self) -> Array2D {
+        let n = self.num_states;
+        let counts = self.transition_counts();
+        let mut exposures = vec![0f64; n];
+        for (transitions, initial) in self.results.iter().zip(self.initial_states.iter()) {
+            for (state, dwells) in dwell_times(transitions, n, *initial)
+                .into_iter()
+                .enumerate()
+            {
+                exposures[state] += dwells.iter().sum::<Time>();
+            }
+        }
+
+        let mut data = vec![-1f64; n * n];
+        for i in 0..n {
+            for j in (0..n).filter(|j| *j != i) {
+                if counts[i][j] > 0 && exposures[i] > 0.0 {
+                    data[i * n + j] = counts[i][j] as f64 / exposures[i];
+                }
+            }
+        }
+
+        Array2D {
+            data,
+            shape: (n, n),
+        }
+    }
+
+    /// Checks that the rates estimated from the ensemble match `input` within `tol`.
+    ///
+    /// Only the rates out of states that were left at least once are checked, and non-positive
+    /// rates count as zero. Returns `StateMachineError::RateMismatch` listing (from, to, expected,
+    /// estimated) for every off-diagonal rate that deviates by more than `tol`.
+    pub fn validate_against(&self, input: &Array2D, tol: f64) -> Result<()> {
+        let n = self.num_states;
+        for actual in [input.shape.0, input.shape.1] {
+            if actual != n {
+                return Err(StateMachineError::NumElems {
+                    actual,
+                    expected: n,
+                });
+            }
+        }
+
+        let estimated = self.estimate_rates();
+        let counts = self.transition_counts();
+        let mut mismatches = Vec::new();
+        for i in (0..n).filter(|i| counts[*i].iter().sum::<usize>() > 0) {
+            for j in (0..n).filter(|j| *j != i) {
+                let expected = input.data[i * n + j].max(0.0);
+                let actual = estimated.data[i * n + j].max(0.0);
+                if (expected - actual).abs() > tol {
+                    mismatches.push((i, j, expected, actual));
+                }
+            }
+        }
+
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(StateMachineError::RateMismatch(mismatches))
+        }
+    }
+}
+
+/// Returns the infinitesimal generator Q of a matrix of rate constants as a row-major vector.
+///
+/// Negative rate constants denote forbidden transitions and self-transitions do not change the
+/// state, so the off-diagonal elements of Q are the non-negative rate constants and its diagonal
+/// elements are the negative row sums.
+fn generator(rate_constants: &Array2D) -> Vec<Rate> {
+    let (rows, cols) = rate_constants.shape;
+    let mut q = vec![0f64; rows * cols];
+
+    for i in 0..rows {
+        let mut exit_rate = 0f64;
+        for j in (0..cols).filter(|j| *j != i) {
+            let rate = rate_constants.data[i * cols + j];
+            if rate >= 0.0 {
+                q[i * cols + j] = rate;
+                exit_rate += rate;
+            }
+        }
+        q[i * cols + i] = -exit_rate;
+    }
+
+    q
+}
+
+/// Returns dp/dt = Q^T p for the generator Q of a n-state machine.
+fn master_equation_derivative(q: &[Rate], p: &[f64], n: usize) -> Vec<f64> {
+    (0..n)
+        .map(|j| (0..n).map(|i| q[i * n + j] * p[i]).sum())
+        .collect()
+}
+
+/// Solves the master equation dp/dt = Q^T p of a state machine at the given times.
+///
+/// The ODE is integrated from t = 0 with a fourth order Runge-Kutta method. The result is a
+/// `times.len()` x N matrix whose rows are the probabilities of being in each of the N states.
+/// Returns `InvalidTimes` unless the times are non-negative, finite and non-decreasing.
+///
+/// # Arguments
+///
+/// - **rate_constants** The N x N rate constants of the state machine
+/// - **initial** The probability of being in each state at t = 0
+/// - **times** The times at which the probabilities are returned
+pub fn solve_master_equation(
+    rate_constants: &Array2D,
+    initial: &[f64],
+    times: &[Time],
+) -> Result<Vec<Vec<f64>>> {
+    let n = rate_constants.shape.0;
+    if initial.len() != n {
+        return Err(StateMachineError::NumElems {
+            actual: initial.len(),
+            expected: n,
+        });
+    }
+    if times.iter().any(|t| !t.is_finite() || *t < 0.0) || times.windows(2).any(|w| w[0] > w[1]) {
+        return Err(StateMachineError::InvalidTimes);
+    }
+
+    let q = generator(rate_constants);
+    let max_exit_rate = (0..n).map(|i| -q[i * n + i]).fold(0f64, f64::max);
+
+    let mut result = Vec::with_capacity(times.len());
+    let mut p = initial.to_vec();
+    let mut t_current: Time = 0.0;
+    for t in times {
+        let interval = t - t_current;
+        let num_steps = (interval * max_exit_rate / MAX_RATE_TIMES_STEP)
+            .ceil()
+            .max(1.0);
+        let dt = interval / num_steps;
+
+        for _ in 0..(num_steps as usize) {
+            let k1 = master_equation_derivative(&q, &p, n);
+            let p2: Vec<f64> = (0..n).map(|i| p[i] + 0.5 * dt * k1[i]).collect();
+            let k2 = master_equation_derivative(&q, &p2, n);
+            let p3: Vec<f64> = (0..n).map(|i| p[i] + 0.5 * dt * k2[i]).collect();
+            let k3 = master_equation_derivative(&q, &p3, n);
+            let p4: Vec<f64> = (0..n).map(|i| p[i] + dt * k3[i]).collect();
+            let k4 = master_equation_derivative(&q, &p4, n);
+
+            for i in 0..n {
+                p[i] += dt / 6.0 * (k1[i] + 2.0 * k2[i] + 2.0 * k3[i] + k4[i]);
+            }
+        }
+
+        t_current = *t;
+        result.push(p.clone());
+    }
+
+    Ok(result)
+}
+
+/// The magnitude below which a pivot is considered to be zero when solving linear systems.
+const PIVOT_TOLERANCE: f64 = 1e-12;
+
+/// Solves the n x n linear system A x = b using Gaussian elimination with partial pivoting.
+///
+/// `a` is stored in row-major order. Returns an error if A is singular.
+fn solve_linear_system(mut a: Vec<f64>, mut b: Vec<f64>, n: usize) -> Result<Vec<f64>> {
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|i, j| a[i * n + col].abs().total_cmp(&a[j * n + col].abs()))
+            .ok_or(StateMachineError::SingularMatrix)?;
+        if a[pivot * n + col].abs() < PIVOT_TOLERANCE {
+            return Err(StateMachineError::SingularMatrix);
+        }
+        if pivot != col {
+            for k in 0..n {
+                a.swap(pivot * n + k, col * n + k);
+            }
+            b.swap(pivot, col);
+        }
+
+        for row in (col + 1)..n {
+            let factor = a[row * n + col] / a[col * n + col];
+            for k in col..n {
+                a[row * n + k] -= factor * a[col * n + k];
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+
+    let mut x = vec![0f64; n];
+    for row in (0..n).rev() {
+        let sum: f64 = ((row + 1)..n).map(|k| a[row * n + k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row * n + row];
+    }
+
+    Ok(x)
+}
+
+/// Returns the stationary distribution pi of a state machine, i.e. the solution of pi Q = 0 whose
+/// elements sum to one.
+///
+/// Returns an error if the stationary distribution is not unique, e.g. if the machine has more
+/// than one closed class of states. The distribution of a machine without states is empty.
+pub fn steady_state(rate_constants: &Array2D) -> Result<Vec<f64>> {
+    let n = rate_constants.shape.0;
+    if n == 0 {
+        return Ok(Vec::new());
+    }
+    let q = generator(rate_constants);
+
+    // The rows of the system are the columns of Q; the last equation is replaced by the
+    // normalization condition because the equations of pi Q = 0 are linearly dependent
+    let mut a = vec![0f64; n * n];
+    for i in 0..n {
+        for j in 0..n {
+            a[i * n + j] = if i == n - 1 { 1.0 } else { q[j * n + i] };
+        }
+    }
+    let mut b = vec![0f64; n];
+    b[n - 1] = 1.0;
+
+    solve_linear_system(a, b, n)
+}
+
+/// Returns true if a state machine satisfies detailed balance, i.e. pi_i Q_ij = pi_j Q_ji within
+/// the absolute tolerance `tol` for every pair of states, where pi is its stationary distribution
+/// and Q its generator.
+///
+/// The stationary distribution is computed with `steady_state` if `stationary` is `None`, which
+/// fails if it is not unique. An error is also returned if `stationary` has the wrong length.
+pub fn is_reversible(
+    rate_constants: &Array2D,
+    stationary: Option<&[f64]>,
+    tol: f64,
+) -> Result<bool> {
+    let n = rate_constants.shape.0;
+    let computed;
+    let pi = match stationary {
+        Some(pi) => pi,
+        None => {
+            computed = steady_state(rate_constants)?;
+            &computed
+        }
+    };
+    if pi.len() != n {
+        return Err(StateMachineError::NumElems {
+            actual: pi.len(),
+            expected: n,
+        });
+    }
+
+    let q = generator(rate_constants);
+    Ok((0..n)
+        .all(|i| ((i + 1)..n).all(|j| (pi[i] * q[i * n + j] - pi[j] * q[j * n + i]).abs() <= tol)))
+}
+
+/// Returns the stationary probability flux pi_i Q_ij from state `i` to state `j`, where pi is
+/// the stationary distribution, e.g. from `steady_state`, and Q the generator of a machine.
+///
+/// The flux is zero if there is no transition from `i` to `j`, including if `i` equals `j`.
+/// Panics if `i` or `j` is out of range.
+pub fn flux(rate_constants: &Array2D, stationary: &[f64], i: State, j: State) -> f64 {
+    let cols = rate_constants.shape.1;
+    let rate = rate_constants.data[i * cols + j];
+    if i == j || rate <= 0.0 {
+        return 0.0;
+    }
+
+    stationary[i] * rate
+}
+
+/// Returns the net stationary probability flux from state `i` to state `j`, i.e. the flux from
+/// `i` to `j` minus the flux from `j` to `i`.
+///
+/// The net fluxes all vanish if and only if the machine satisfies detailed balance. Panics if `i`
+/// or `j` is out of range.
+pub fn net_flux(rate_constants: &Array2D, stationary: &[f64], i: State, j: State) -> f64 {
+    flux(rate_constants, stationary, i, j) - flux(rate_constants, stationary, j, i)
+}
+
+/// Returns the Kullback-Leibler divergence of the stationary distribution from the empirical
+/// state occupation of an ensemble.
+///
+/// The empirical distribution is the fraction of the total time that the machines spent in each
+/// state, where each machine is observed from t = 0 until its last transition. Small values
+/// indicate that the ensemble has converged to the steady state. The divergence is infinite if
+/// the machines spent time in a state whose stationary probability is zero. An error is returned
+/// if `initial` or a state of a transition is not a state of the machines.
+///
+/// # Arguments
+///
+/// - **results** The transitions of each machine, with absolute times
+/// - **rate_constants** The rate constants of the machines
+/// - **initial** The state in which all machines started
+pub fn stationary_kl(
+    results: &[Vec<Transition>],
+    rate_constants: &Array2D,
+    initial: State,
+) -> Result<f64> {
+    let num_states = rate_constants.shape.0;
+    let states = results
+        .iter()
+        .flatten()
+        .flat_map(|transition| [transition.from, transition.to])
+        .chain([initial]);
+    for state in states {
+        if state >= num_states {
+            return Err(StateMachineError::StateOutOfRange { state, num_states });
+        }
+    }
+
+    let stationary = steady_state(rate_constants)?;
+    let empirical = empirical_occupation(results, &vec![initial; results.len()], num_states);
+
+    Ok(empirical
+        .iter()
+        .zip(stationary.iter())
+        .filter(|(p, _)| **p > 0.0)
+        .map(|(p, q)| p * (p / q).ln())
+        .sum())
+}
+
+/// Returns the time-autocorrelation of the indicator of `state` at each lag, averaged over an
+/// ensemble.
+///
+/// The indicator I(t) is 1 while a machine is in `state` and 0 otherwise. Each machine is observed
+/// from t = 0 until its last transition, i.e. over [0, T), and contributes the integral of
+/// I(t) I(t + lag) over [0, T - lag). The result is the sum of these integrals divided by the sum
+/// of the lengths T - lag, so it is the fraction of time spent in `state` at lag zero and NaN for
+/// lags that are longer than all runs. For a machine in its steady state, this is
+/// p (p + (1 - p) e^(-k lag)) for a two-state machine with stationary probability p of `state`
+/// and total rate k.
+///
+/// # Arguments
+///
+/// - **results** The transitions of each machine, with absolute times
+/// - **state** The state whose indicator is correlated
+/// - **num_states** The number of states of the machines
+/// - **initial** The state in which all machines started
+/// - **lags** The non-negative lags at which the autocorrelation is computed
+pub fn autocorrelation(
+    results: &[Vec<Transition>],
+    state: State,
+    num_states: usize,
+    initial: State,
+    lags: &[Time],
+) -> Result<Vec<f64>> {
+    for state in [state, initial] {
+        if state >= num_states {
+            return Err(StateMachineError::StateOutOfRange { state, num_states });
+        }
+    }
+
+    // The intervals [start, end) that each machine spent in `state`, and the end of its run
+    let visits: Vec<(Vec<(Time, Time)>, Time)> = results
+        .iter()
+        .map(|transitions| {
+            let mut intervals = Vec::new();
+            let mut current = initial;
+            let mut t_entered: Time = 0.0;
+            for transition in transitions {
+                if current == state {
+                    intervals.push((t_entered, transition.time));
+                }
+                current = transition.to;
+                t_entered = transition.time;
+            }
+            (intervals, t_entered)
+        })
+        .collect();
+
+    Ok(lags
+        .iter()
+        .map(|lag| {
+            let mut overlap: Time = 0.0;
+            let mut total_time: Time = 0.0;
+            for (intervals, t_end) in visits.iter() {
+                let window = t_end - lag;
+                if window <= 0.0 {
+                    continue;
+                }
+                total_time += window;
+
+                // Overlap of the intervals with the intervals shifted back by the lag, both of
+                // which are sorted
+                let mut first = 0;
+                for (start, end) in intervals.iter() {
+                    while first < intervals.len() && intervals[first].1 - lag <= *start {
+                        first += 1;
+                    }
+                    for (shifted_start, shifted_end) in intervals[first..]
+                        .iter()
+                        .map(|(s, e)| (s - lag, e - lag))
+                        .take_while(|(shifted_start, _)| shifted_start < end)
+                    {
+                        let lower = start.max(shifted_start);
+                        let upper = end.min(shifted_end).min(window);
+                        overlap += (upper - lower).max(0.0);
+                    }
+                }
+            }
+
+            overlap / total_time
+        })
+        .collect())
+}
+
+/// Returns the total variation distance between two distributions over the same states.
+///
+/// This is half the sum of the absolute differences of the probabilities. Unlike the KL
+/// divergence, it is bounded by 1 and finite if one distribution has zeros where the other has
+/// none, which makes it convenient for convergence diagnostics. Returns an error if the
+/// distributions have different lengths.
+pub fn total_variation(empirical: &[f64], analytic: &[f64]) -> Result<f64> {
+    if empirical.len() != analytic.len() {
+        return Err(StateMachineError::NumElems {
+            actual: empirical.len(),
+            expected: analytic.len(),
+        });
+    }
+
+    Ok(0.5
+        * empirical
+            .iter()
+            .zip(analytic)
+            .map(|(p, q)| (p - q).abs())
+            .sum::<f64>())
+}
+
+/// Returns the forward committor of each state, i.e. the probability that a machine starting in
+/// that state reaches a `product` state before a `reactant` state.
+///
+/// The committor is 0 on the reactant states, 1 on the product states, and satisfies
+/// sum_j Q_ij q_j = 0 on all other states, where Q is the generator of the machine. The state sets
+/// must be non-empty and disjoint, and every other state must be able to reach one of them.
+pub fn committor(
+    rate_constants: &Array2D,
+    reactant: &[State],
+    product: &[State],
+) -> Result<Vec<f64>> {
+    let n = rate_constants.shape.0;
+    if reactant.is_empty()
+        || product.is_empty()
+        || reactant.iter().any(|state| product.contains(state))
+    {
+        return Err(StateMachineError::InvalidStateSets);
+    }
+    if let Some(&state) = reactant.iter().chain(product).find(|state| **state >= n) {
+        return Err(StateMachineError::StateOutOfRange {
+            state,
+            num_states: n,
+        });
+    }
+
+    let q = generator(rate_constants);
+    let mut a = vec![0f64; n * n];
+    let mut b = vec![0f64; n];
+    for i in 0..n {
+        if reactant.contains(&i) || product.contains(&i) {
+            a[i * n + i] = 1.0;
+            b[i] = if product.contains(&i) { 1.0 } else { 0.0 };
+        } else {
+            a[(i * n)..((i * n) + n)].copy_from_slice(&q[(i * n)..((i * n) + n)]);
+        }
+    }
+
+    solve_linear_system(a, b, n)
+}
+
+/// Returns the probability that a machine starting in each state is eventually absorbed into each
+/// of the `absorbing` states.
+///
+/// The result has one row per state and one column per absorbing state, in the order given. Rows
+/// of absorbing states are indicator vectors; the rows of all other states sum to one provided
+/// that every state can reach one of the absorbing states. Rates out of the absorbing states are
+/// ignored.
+pub fn absorption_probabilities(rate_constants: &Array2D, absorbing: &[State]) -> Result<Array2D> {
+    let n = rate_constants.shape.0;
+    let m = absorbing.len();
+    if absorbing.is_empty()
+        || absorbing
+            .iter()
+            .enumerate()
+            .any(|(k, state)| absorbing[..k].contains(state))
+    {
+        return Err(StateMachineError::InvalidStateSets);
+    }
+    if let Some(&state) = absorbing.iter().find(|state| **state >= n) {
+        return Err(StateMachineError::StateOutOfRange {
+            state,
+            num_states: n,
+        });
+    }
+
+    let q = generator(rate_constants);
+    let mut a = vec![0f64; n * n];
+    for i in 0..n {
+        if absorbing.contains(&i) {
+            a[i * n + i] = 1.0;
+        } else {
+            a[(i * n)..((i * n) + n)].copy_from_slice(&q[(i * n)..((i * n) + n)]);
+        }
+    }
+
+    let mut data = vec![0f64; n * m];
+    for (k, target) in absorbing.iter().enumerate() {
+        let mut b = vec![0f64; n];
+        b[*target] = 1.0;
+        let probabilities = solve_linear_system(a.clone(), b, n)?;
+        for (i, p) in probabilities.into_iter().enumerate() {
+            data[i * m + k] = p;
+        }
+    }
+
+    Ok(Array2D {
+        data,
+        shape: (n, m),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        absorption_probabilities, autocorrelation, category_dwell_times, committor, dwell_times,
+        flux, inter_event_time_stats, inter_event_times, is_reversible, max_state_visited,
+        merge_self_transitions, net_flux, occupancy_over_time, sample_on_grid,
+        solve_master_equation, stationary_kl, steady_state, survival_function, total_variation,
+        trajectory_length_stats, trajectory_lengths, validate_trajectory, EnsembleResult,
+        TransitionIteratorExt,
+    };
+    use crate::accumulators::StepUntil;
+    use crate::arrays::Array2D;
+    use crate::steppers::Stepper;
+    use crate::{Accumulate, StateMachineError, Step, Time, Transition};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_sample_on_grid() {
+        let transitions = vec![
+            Transition {
+                from: 0,
+                time: 1.0,
+                dwell: 1.0,
+                to: 1,
+            },
+            Transition {
+                from: 1,
+                time: 2.0,
+                dwell: 1.0,
+                to: 2,
+            },
+        ];
+        let grid = [0.0, 0.5, 1.0, 1.5, 2.5];
+
+        let result = sample_on_grid(&transitions, 0, &grid);
+
+        assert_eq!(vec![0, 0, 1, 1, 2], result);
+    }
+
+    #[test]
+    fn test_occupancy_over_time_two_state_relaxation() {
+        // For symmetric rates k, P(0, t) = 0.5 + 0.5 * exp(-2kt) when starting in state 0
+        let k = 1.0;
+        let num_machines = 5000;
+        let grid = [0.0, 0.25, 0.5, 1.0, 2.0];
+        let mut rng = rand::thread_rng();
+
+        let results: Vec<Vec<Transition>> = (0..num_machines)
+            .map(|_| {
+                let stepper = Stepper::new(
+                    0,
+                    Array2D {
+                        data: vec![-1.0, k, k, -1.0],
+                        shape: (2, 2),
+                    },
+                );
+                let mut accumulator = StepUntil::new(stepper, 2.0).unwrap();
+                accumulator.accumulate(&[], &mut rng).unwrap().to_vec()
+            })
+            .collect();
+
+        let occupancy = occupancy_over_time(&results, 2, 0, &grid);
+
+        assert_eq!(grid.len(), occupancy.len());
+        for (t, row) in grid.iter().zip(occupancy.iter()) {
+            let expected = 0.5 + 0.5 * (-2.0 * k * t).exp();
+            assert!((row[0] - expected).abs() < 0.05);
+            assert!((row[0] + row[1] - 1.0).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_solve_master_equation_two_state() {
+        // For rates k01 and k10, P(0, t) = k10 / k + (1 - k10 / k) * exp(-kt) with k = k01 + k10
+        let (k01, k10) = (2.0, 0.5);
+        let rate_constants = Array2D {
+            data: vec![-1.0, k01, k10, -1.0],
+            shape: (2, 2),
+        };
+        let times = [0.0, 0.1, 0.5, 1.0, 5.0];
+
+        let result = solve_master_equation(&rate_constants, &[1.0, 0.0], &times).unwrap();
+
+        let k = k01 + k10;
+        for (t, p) in times.iter().zip(result.iter()) {
+            let expected = k10 / k + (1.0 - k10 / k) * (-k * t).exp();
+            assert!((p[0] - expected).abs() < 1e-8);
+            assert!((p[1] - (1.0 - expected)).abs() < 1e-8);
+        }
+    }
+
+    #[test]
+    fn test_solve_master_equation_wrong_initial_length() {
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 1.0, -1.0],
+            shape: (2, 2),
+        };
+
+        let result = solve_master_equation(&rate_constants, &[1.0], &[1.0]);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_solve_master_equation_invalid_times() {
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 1.0, -1.0],
+            shape: (2, 2),
+        };
+
+        for times in [
+            vec![f64::INFINITY],
+            vec![f64::NAN],
+            vec![-1.0],
+            vec![2.0, 1.0],
+        ] {
+            assert!(matches!(
+                solve_master_equation(&rate_constants, &[1.0, 0.0], &times),
+                Err(StateMachineError::InvalidTimes)
+            ));
+        }
+    }
+
+    fn small_ensemble() -> EnsembleResult {
+        let results = vec![
+            vec![
+                Transition {
+                    from: 0,
+                    time: 1.0,
+                    dwell: 1.0,
+                    to: 1,
+                },
+                Transition {
+                    from: 1,
+                    time: 3.0,
+                    dwell: 2.0,
+                    to: 0,
+                },
+            ],
+            vec![Transition {
+                from: 1,
+                time: 2.0,
+                dwell: 2.0,
+                to: 0,
+            }],
+            vec![],
+        ];
+
+        EnsembleResult::new(results, vec![0, 1, 1], 2).unwrap()
+    }
+
+    #[test]
+    fn ensemble_result_new_validates_inputs() {
+        let results = vec![vec![Transition {
+            from: 0,
+            time: 1.0,
+            dwell: 1.0,
+            to: 2,
+        }]];
+
+        assert!(EnsembleResult::new(results.clone(), vec![0, 0], 3).is_err());
+        assert!(EnsembleResult::new(results.clone(), vec![0], 2).is_err());
+        assert!(EnsembleResult::new(results, vec![0], 3).is_ok());
+    }
+
+    #[test]
+    fn ensemble_result_final_states() {
+        let ensemble = small_ensemble();
+
+        assert_eq!(vec![0, 0, 1], ensemble.final_states());
+    }
+
+    #[test]
+    fn ensemble_result_extend() {
+        let mut ensemble = small_ensemble();
+        let other = small_ensemble();
+
+        ensemble.extend(other.clone()).unwrap();
+
+        assert_eq!(2 * other.len(), ensemble.len());
+        for index in 0..other.len() {
+            let offset = index + other.len();
+            assert_eq!(
+                other.initial_states()[index],
+                ensemble.initial_states()[offset]
+            );
+            assert_eq!(
+                other.results()[index].len(),
+                ensemble.results()[offset].len()
+            );
+        }
+        assert_eq!(vec![0, 0, 1, 0, 0, 1], ensemble.final_states());
+    }
+
+    #[test]
+    fn ensemble_result_extend_rejects_mismatched_states() {
+        let mut ensemble = small_ensemble();
+        let other = EnsembleResult::new(vec![vec![]], vec![0], ensemble.num_states() + 1).unwrap();
+
+        assert!(matches!(
+            ensemble.extend(other),
+            Err(StateMachineError::NumElems { .. })
+        ));
+        assert_eq!(3, ensemble.len());
+    }
+
+    #[test]
+    fn ensemble_result_occupation_times() {
+        let ensemble = small_ensemble();
+
+        // Machine 0: 1 + 1 in state 0 and 2 in state 1; machine 1: 2 in state 1 and 2 in state 0;
+        // machine 2: 4 in state 1
+        assert_eq!(vec![4.0, 8.0], ensemble.occupation_times(4.0));
+    }
+
+    #[test]
+    fn ensemble_result_transition_counts() {
+        let ensemble = small_ensemble();
+
+        assert_eq!(vec![vec![0, 1], vec![2, 0]], ensemble.transition_counts());
+    }
+
+    #[test]
+    fn ensemble_result_mean_dwell_times() {
+        let ensemble = small_ensemble();
+
+        // Completed visits: state 0 => [1.0]; state 1 => [2.0, 2.0]
+        assert_eq!(vec![1.0, 2.0], ensemble.mean_dwell_times());
+    }
+
+    #[test]
+    fn transition_iterator_ext_summaries() {
+        let transitions = small_ensemble().into_results().remove(0);
+
+        let counts = transitions.clone().into_iter().transition_counts(2);
+        let dwells = transitions.into_iter().dwell_times(2, 0);
+
+        assert_eq!(vec![vec![0, 1], vec![1, 0]], counts);
+        assert_eq!(vec![vec![1.0], vec![2.0]], dwells);
+    }
+
+    #[test]
+    fn test_steady_state() {
+        let rate_constants = Array2D {
+            data: vec![-1.0, 2.0, -1.0, 1.0, -1.0, 3.0, 1.0, -1.0, -1.0],
+            shape: (3, 3),
+        };
+
+        let pi = steady_state(&rate_constants).unwrap();
+
+        // Balance: 2 pi0 = pi1 + pi2, 4 pi1 = 2 pi0, pi2 = 3 pi1
+        let expected = [1.0 / 3.0, 1.0 / 6.0, 1.0 / 2.0];
+        for (actual, expected) in pi.iter().zip(expected) {
+            assert!((actual - expected).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_steady_state_reducible() {
+        let rate_constants = Array2D {
+            data: vec![-1.0, -1.0, -1.0, -1.0],
+            shape: (2, 2),
+        };
+
+        assert!(steady_state(&rate_constants).is_err());
+    }
+
+    #[test]
+    fn test_steady_state_no_states() {
+        let rate_constants = Array2D {
+            data: vec![],
+            shape: (0, 0),
+        };
+
+        assert!(steady_state(&rate_constants).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_stationary_kl_converged_ensemble() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let rate_constants = Array2D {
+            data: vec![-1.0, 2.0, 1.0, -1.0],
+            shape: (2, 2),
+        };
+
+        let results: Vec<Vec<Transition>> = (0..200)
+            .map(|_| {
+                let stepper = Stepper::new(0, rate_constants.clone());
+                let mut accumulator = StepUntil::new(stepper, 50.0).unwrap();
+                accumulator.accumulate(&[], &mut rng).unwrap().to_vec()
+            })
+            .collect();
+
+        let kl = stationary_kl(&results, &rate_constants, 0).unwrap();
+
+        assert!(kl >= 0.0);
+        assert!(kl < 1e-3);
+    }
+
+    #[test]
+    fn test_stationary_kl_state_out_of_range() {
+        let rate_constants = Array2D {
+            data: vec![-1.0, 2.0, 1.0, -1.0],
+            shape: (2, 2),
+        };
+        let results = vec![vec![Transition {
+            from: 0,
+            time: 1.0,
+            dwell: 1.0,
+            to: 2,
+        }]];
+
+        assert!(matches!(
+            stationary_kl(&[], &rate_constants, 2),
+            Err(StateMachineError::StateOutOfRange {
+                state: 2,
+                num_states: 2
+            })
+        ));
+        assert!(matches!(
+            stationary_kl(&results, &rate_constants, 0),
+            Err(StateMachineError::StateOutOfRange {
+                state: 2,
+                num_states: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn test_autocorrelation_two_state() {
+        let mut rng = StdRng::seed_from_u64(22);
+        // 0 -> 1 at rate 1 and 1 -> 0 at rate 2, so p = 1 / 3 and k = 3
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, 2.0, -1.0], (2, 2)).unwrap();
+        let results: Vec<Vec<Transition>> = (0..20)
+            .map(|_| {
+                let stepper = Stepper::new(0, rate_constants.clone());
+                let mut accumulator = StepUntil::new(stepper, 500.0).unwrap();
+                accumulator.accumulate(&[], &mut rng).unwrap().to_vec()
+            })
+            .collect();
+        let lags = [0.0, 0.2, 0.5, 1.0, 3.0];
+
+        let correlation = autocorrelation(&results, 1, 2, 0, &lags).unwrap();
+
+        let p: f64 = 1.0 / 3.0;
+        for (lag, c) in lags.iter().zip(correlation) {
+            let expected = p * (p + (1.0 - p) * (-3.0 * lag).exp());
+            assert!((c - expected).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_autocorrelation_by_hand() {
+        // In state 1 during [1, 3) and [4, 5), observed until t = 6
+        let transitions = vec![
+            Transition {
+                from: 0,
+                time: 1.0,
+                dwell: 1.0,
+                to: 1,
+            },
+            Transition {
+                from: 1,
+                time: 3.0,
+                dwell: 2.0,
+                to: 0,
+            },
+            Transition {
+                from: 0,
+                time: 4.0,
+                dwell: 1.0,
+                to: 1,
+            },
+            Transition {
+                from: 1,
+                time: 5.0,
+                dwell: 1.0,
+                to: 0,
+            },
+            Transition {
+                from: 0,
+                time: 6.0,
+                dwell: 1.0,
+                to: 1,
+            },
+        ];
+
+        let correlation = autocorrelation(&[transitions], 1, 2, 0, &[0.0, 1.0, 3.0, 6.0]).unwrap();
+
+        // Lag 1: [1, 3) and [4, 5) overlap [0, 2) and [3, 4) only over [1, 2)
+        // Lag 3: [1, 3) and [4, 5) overlap [-2, 0) and [1, 2) only over [1, 2)
+        assert_eq!(3.0 / 6.0, correlation[0]);
+        assert_eq!(1.0 / 5.0, correlation[1]);
+        assert_eq!(1.0 / 3.0, correlation[2]);
+        assert!(correlation[3].is_nan());
+        assert!(autocorrelation(&[], 2, 2, 0, &[0.0]).is_err());
+    }
+
+    #[test]
+    fn test_total_variation() {
+        let tv = total_variation(&[0.5, 0.25, 0.25], &[0.25, 0.25, 0.5]).unwrap();
+        assert!((tv - 0.25).abs() < 1e-12);
+
+        assert_eq!(0.0, total_variation(&[0.3, 0.7], &[0.3, 0.7]).unwrap());
+        assert_eq!(1.0, total_variation(&[1.0, 0.0], &[0.0, 1.0]).unwrap());
+        assert!(matches!(
+            total_variation(&[1.0], &[0.5, 0.5]),
+            Err(StateMachineError::NumElems {
+                actual: 1,
+                expected: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn test_tv_to_stationary() {
+        let ensemble = small_ensemble();
+        // The stationary distribution is (1/3, 2/3), and the machines spent 1 time unit in
+        // state 0 and 4 in state 1
+        let rate_constants = Array2D::new(vec![-1.0, 2.0, 1.0, -1.0], (2, 2)).unwrap();
+
+        let tv = ensemble.tv_to_stationary(&rate_constants).unwrap();
+
+        assert!((tv - 2.0 / 15.0).abs() < 1e-12);
+        assert!(ensemble
+            .tv_to_stationary(&Array2D::new(vec![-1.0; 9], (3, 3)).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_trajectory_lengths() {
+        let results = small_ensemble().into_results();
+
+        let lengths = trajectory_lengths(&results);
+        let (mean, std) = trajectory_length_stats(&results);
+
+        assert_eq!(vec![2, 1, 0], lengths);
+        assert!((mean - 1.0).abs() < 1e-12);
+        assert!((std - (2.0f64 / 3.0).sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_trajectory_length_stats_empty() {
+        let (mean, std) = trajectory_length_stats(&[]);
+
+        assert!(mean.is_nan());
+        assert!(std.is_nan());
+    }
+
+    #[test]
+    fn max_state_visited_of_queue() {
+        let transitions: Vec<Transition> = [(1, 2), (2, 3), (3, 2), (2, 1), (1, 0)]
+            .iter()
+            .enumerate()
+            .map(|(i, (from, to))| Transition {
+                from: *from,
+                time: i as f64,
+                dwell: 1.0,
+                to: *to,
+            })
+            .collect();
+
+        assert_eq!(3, max_state_visited(&transitions, 1));
+        assert_eq!(4, max_state_visited(&[], 4));
+    }
+
+    #[test]
+    fn is_reversible_birth_death_chain() {
+        // Every chain without cycles satisfies detailed balance
+        let rate_constants = Array2D::new(
+            vec![-1.0, 2.0, -1.0, 1.0, -1.0, 3.0, -1.0, 5.0, -1.0],
+            (3, 3),
+        )
+        .unwrap();
+
+        assert!(is_reversible(&rate_constants, None, 1e-12).unwrap());
+
+        let stationary = [5.0 / 21.0, 10.0 / 21.0, 6.0 / 21.0];
+        assert!(is_reversible(&rate_constants, Some(&stationary), 1e-12).unwrap());
+    }
+
+    #[test]
+    fn is_reversible_driven_cycle() {
+        // 0 -> 1 -> 2 -> 0 with a net current around the cycle
+        let rate_constants =
+            Array2D::new(vec![-1.0, 2.0, 1.0, 1.0, -1.0, 2.0, 2.0, 1.0, -1.0], (3, 3)).unwrap();
+
+        assert!(!is_reversible(&rate_constants, None, 1e-6).unwrap());
+        assert!(matches!(
+            is_reversible(&rate_constants, Some(&[0.5, 0.5]), 1e-6),
+            Err(StateMachineError::NumElems {
+                actual: 2,
+                expected: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn net_flux_of_three_state_cycles() {
+        // Driven: 0 -> 1 -> 2 -> 0 at rate 2 and backwards at rate 1
+        let driven =
+            Array2D::new(vec![-1.0, 2.0, 1.0, 1.0, -1.0, 2.0, 2.0, 1.0, -1.0], (3, 3)).unwrap();
+        // Reversible: all rates equal
+        let reversible =
+            Array2D::new(vec![-1.0, 1.0, 1.0, 1.0, -1.0, 1.0, 1.0, 1.0, -1.0], (3, 3)).unwrap();
+
+        for (rate_constants, expected) in [(driven, 1.0 / 3.0), (reversible, 0.0)] {
+            let stationary = steady_state(&rate_constants).unwrap();
+
+            for (i, j) in [(0, 1), (1, 2), (2, 0)] {
+                assert!((net_flux(&rate_constants, &stationary, i, j) - expected).abs() < 1e-12);
+                assert!((net_flux(&rate_constants, &stationary, j, i) + expected).abs() < 1e-12);
+            }
+            assert!(
+                (flux(&rate_constants, &stationary, 0, 1) - stationary[0] * rate_constants.data[1])
+                    .abs()
+                    < 1e-12
+            );
+            assert_eq!(0.0, flux(&rate_constants, &stationary, 1, 1));
+        }
+    }
+
+    #[test]
+    fn committor_of_linear_chain() {
+        // 0 <-> 1 <-> 2 where state 1 goes to 0 at rate 1 and to 2 at rate 3
+        let rate_constants = Array2D::new(
+            vec![-1.0, 2.0, -1.0, 1.0, -1.0, 3.0, -1.0, 5.0, -1.0],
+            (3, 3),
+        )
+        .unwrap();
+
+        let q = committor(&rate_constants, &[0], &[2]).unwrap();
+
+        assert_eq!(3, q.len());
+        assert_eq!(0.0, q[0]);
+        assert!((q[1] - 0.75).abs() < 1e-12);
+        assert_eq!(1.0, q[2]);
+    }
+
+    #[test]
+    fn committor_rejects_inconsistent_state_sets() {
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, 1.0, -1.0], (2, 2)).unwrap();
+
+        for (reactant, product) in [
+            (&[][..], &[1][..]),
+            (&[0][..], &[][..]),
+            (&[0][..], &[0, 1][..]),
+        ] {
+            assert!(matches!(
+                committor(&rate_constants, reactant, product),
+                Err(StateMachineError::InvalidStateSets)
+            ));
+        }
+        assert!(matches!(
+            committor(&rate_constants, &[0], &[2]),
+            Err(StateMachineError::StateOutOfRange {
+                state: 2,
+                num_states: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn absorption_probabilities_of_branched_chain() {
+        // 0 -> 1 at rate 1, 1 -> 0 at rate 1, 1 -> 2 at rate 1, 1 -> 3 at rate 2
+        let rate_constants = Array2D::new(
+            vec![
+                -1.0, 1.0, -1.0, -1.0, 1.0, -1.0, 1.0, 2.0, -1.0, -1.0, -1.0, -1.0, -1.0, -1.0,
+                -1.0, -1.0,
+            ],
+            (4, 4),
+        )
+        .unwrap();
+
+        let p = absorption_probabilities(&rate_constants, &[2, 3]).unwrap();
+
+        assert_eq!((4, 2), p.shape);
+        for row in p.data.chunks(2) {
+            assert!((row.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        }
+        assert!((p.data[0] - 1.0 / 3.0).abs() < 1e-12);
+        assert!((p.data[2] - 1.0 / 3.0).abs() < 1e-12);
+        assert_eq!(&[1.0, 0.0, 0.0, 1.0], &p.data[4..]);
+    }
+
+    #[test]
+    fn absorption_probabilities_rejects_invalid_states() {
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, 1.0, -1.0], (2, 2)).unwrap();
+
+        for absorbing in [&[][..], &[1, 1][..]] {
+            assert!(matches!(
+                absorption_probabilities(&rate_constants, absorbing),
+                Err(StateMachineError::InvalidStateSets)
+            ));
+        }
+        assert!(matches!(
+            absorption_probabilities(&rate_constants, &[2]),
+            Err(StateMachineError::StateOutOfRange {
+                state: 2,
+                num_states: 2
+            })
+        ));
+    }
+
+    fn simulated_two_state_ensemble() -> EnsembleResult {
+        let mut rng = StdRng::seed_from_u64(3);
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, 2.0, -1.0], (2, 2)).unwrap();
+        let results: Vec<Vec<Transition>> = (0..20)
+            .map(|_| {
+                let mut accumulator =
+                    StepUntil::new(Stepper::new(0, rate_constants.clone()), 100.0).unwrap();
+                accumulator.accumulate(&[], &mut rng).unwrap().to_vec()
+            })
+            .collect();
+
+        EnsembleResult::new(results, vec![0; 20], 2).unwrap()
+    }
+
+    #[test]
+    fn ensemble_result_estimate_rates() {
+        let estimated = simulated_two_state_ensemble().estimate_rates();
+
+        assert_eq!((2, 2), estimated.shape);
+        assert_eq!(-1.0, estimated.data[0]);
+        assert!((estimated.data[1] - 1.0).abs() < 0.1);
+        assert!((estimated.data[2] - 2.0).abs() < 0.2);
+        assert_eq!(-1.0, estimated.data[3]);
+    }
+
+    #[test]
+    fn ensemble_result_validate_against() {
+        let ensemble = simulated_two_state_ensemble();
+        let correct = Array2D::new(vec![-1.0, 1.0, 2.0, -1.0], (2, 2)).unwrap();
+        let wrong = Array2D::new(vec![-1.0, 1.0, 4.0, -1.0], (2, 2)).unwrap();
+
+        assert!(ensemble.validate_against(&correct, 0.2).is_ok());
+        match ensemble.validate_against(&wrong, 0.2) {
+            Err(StateMachineError::RateMismatch(entries)) => {
+                assert_eq!(1, entries.len());
+                assert_eq!((1, 0, 4.0), (entries[0].0, entries[0].1, entries[0].2));
+            }
+            _ => panic!("expected a rate mismatch"),
+        }
+
+        let too_wide = Array2D::new(vec![-1.0; 6], (2, 3)).unwrap();
+        assert!(matches!(
+            ensemble.validate_against(&too_wide, 0.2),
+            Err(StateMachineError::NumElems {
+                actual: 3,
+                expected: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn category_dwell_times_of_four_state_trajectory() {
+        // States 0 and 1 are "bright" (category 0), states 2 and 3 are "dark" (category 1)
+        let categories = [0, 0, 1, 1];
+        let transitions = vec![
+            Transition {
+                from: 0,
+                time: 1.0,
+                dwell: 1.0,
+                to: 1,
+            },
+            Transition {
+                from: 1,
+                time: 3.0,
+                dwell: 2.0,
+                to: 2,
+            },
+            Transition {
+                from: 2,
+                time: 4.0,
+                dwell: 1.0,
+                to: 3,
+            },
+            Transition {
+                from: 3,
+                time: 6.0,
+                dwell: 2.0,
+                to: 0,
+            },
+            Transition {
+                from: 0,
+                time: 7.0,
+                dwell: 1.0,
+                to: 2,
+            },
+        ];
+
+        let dwells = category_dwell_times(&transitions, &categories, 0);
+
+        assert_eq!(vec![vec![3.0, 1.0], vec![3.0]], dwells);
+    }
+
+    #[test]
+    fn ensemble_result_bootstrap() {
+        let ensemble = simulated_two_state_ensemble();
+        let mean_length = |results: &[Vec<Transition>]| {
+            results.iter().map(Vec::len).sum::<usize>() as f64 / results.len() as f64
+        };
+
+        let replicates = ensemble.bootstrap(mean_length, 500, 11);
+
+        assert_eq!(500, replicates.len());
+        assert_eq!(replicates, ensemble.bootstrap(mean_length, 500, 11));
+        let full = mean_length(ensemble.results());
+        let mean = replicates.iter().sum::<f64>() / replicates.len() as f64;
+        assert!((mean - full).abs() < 0.01 * full);
+        assert!(replicates.iter().any(|replicate| *replicate != full));
+    }
+
+    #[test]
+    fn merge_self_transitions_removes_self_loops() {
+        let transitions = vec![
+            Transition {
+                from: 0,
+                time: 1.0,
+                dwell: 1.0,
+                to: 0,
+            },
+            Transition {
+                from: 0,
+                time: 1.5,
+                dwell: 0.5,
+                to: 0,
+            },
+            Transition {
+                from: 0,
+                time: 2.0,
+                dwell: 0.5,
+                to: 1,
+            },
+            Transition {
+                from: 1,
+                time: 3.0,
+                dwell: 1.0,
+                to: 2,
+            },
+            Transition {
+                from: 2,
+                time: 3.5,
+                dwell: 0.5,
+                to: 2,
+            },
+            Transition {
+                from: 2,
+                time: 4.0,
+                dwell: 0.5,
+                to: 0,
+            },
+            Transition {
+                from: 0,
+                time: 5.0,
+                dwell: 1.0,
+                to: 0,
+            },
+        ];
+
+        let merged = merge_self_transitions(&transitions);
+
+        assert_eq!(
+            vec![(0, 2.0, 1), (1, 3.0, 2), (2, 4.0, 0)],
+            merged
+                .iter()
+                .map(|t| (t.from(), t.time(), t.to()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![2.0, 1.0, 1.0],
+            merged.iter().map(|t| t.dwell()).collect::<Vec<_>>()
+        );
+        // The visits of the merged trajectory span the self-transitions
+        assert_eq!(
+            vec![vec![2.0], vec![1.0], vec![1.0]],
+            dwell_times(&merged, 3, 0)
+        );
+    }
+
+    #[test]
+    fn inter_event_times_of_known_trajectory() {
+        let transitions = vec![
+            Transition {
+                from: 0,
+                time: 1.0,
+                dwell: 1.0,
+                to: 1,
+            },
+            Transition {
+                from: 1,
+                time: 1.5,
+                dwell: 0.5,
+                to: 0,
+            },
+            Transition {
+                from: 0,
+                time: 3.5,
+                dwell: 2.0,
+                to: 1,
+            },
+            Transition {
+                from: 1,
+                time: 4.0,
+                dwell: 0.5,
+                to: 0,
+            },
+        ];
+
+        assert_eq!(vec![0.5, 2.0, 0.5], inter_event_times(&transitions));
+        let (mean, variance) = inter_event_time_stats(&transitions);
+        assert_eq!(1.0, mean);
+        assert_eq!(0.5, variance);
+    }
+
+    #[test]
+    fn inter_event_times_of_short_trajectories() {
+        let single = vec![Transition {
+            from: 0,
+            time: 1.0,
+            dwell: 1.0,
+            to: 1,
+        }];
+
+        assert!(inter_event_times(&[]).is_empty());
+        assert!(inter_event_times(&single).is_empty());
+        let (mean, variance) = inter_event_time_stats(&single);
+        assert!(mean.is_nan() && variance.is_nan());
+    }
+
+    #[test]
+    fn survival_function_matches_exponential() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, -1.0, -1.0], (2, 2)).unwrap();
+        let mut first_passage_times: Vec<Time> = (0..20000)
+            .map(|_| {
+                let mut stepper = Stepper::new(0, rate_constants.clone());
+                stepper.step(&[], &mut rng).unwrap().time
+            })
+            .collect();
+        let grid = [0.0, 0.5, 1.0, 2.0];
+
+        let survival = survival_function(&first_passage_times, &grid);
+        for (t, fraction) in grid.iter().zip(survival) {
+            assert!((fraction - (-t).exp()).abs() < 0.01);
+        }
+
+        // Censored trajectories never stop surviving
+        first_passage_times.extend([Time::INFINITY; 20000]);
+        let survival = survival_function(&first_passage_times, &grid);
+        for (t, fraction) in grid.iter().zip(survival) {
+            assert!((fraction - 0.5 * (1.0 + (-t).exp())).abs() < 0.01);
+        }
+        assert!(survival_function(&[], &grid).iter().all(|f| f.is_nan()));
+    }
+
+    #[test]
+    fn validate_trajectory_accepts_valid_trajectory() {
+        let transitions = vec![
+            Transition {
+                from: 0,
+                time: 1.0,
+                dwell: 1.0,
+                to: 1,
+            },
+            Transition {
+                from: 1,
+                time: 1.0,
+                dwell: 0.0,
+                to: 1,
+            },
+            Transition {
+                from: 1,
+                time: 2.5,
+                dwell: 1.5,
+                to: 0,
+            },
+        ];
+
+        assert!(validate_trajectory(&transitions).is_ok());
+        assert!(validate_trajectory(&[]).is_ok());
+    }
+
+    #[test]
+    fn validate_trajectory_reports_first_violation() {
+        let mut transitions = vec![
+            Transition {
+                from: 0,
+                time: 1.0,
+                dwell: 1.0,
+                to: 1,
+            },
+            Transition {
+                from: 1,
+                time: 0.5,
+                dwell: 0.5,
+                to: 2,
+            },
+            Transition {
+                from: 0,
+                time: 3.0,
+                dwell: 2.5,
+                to: 1,
+            },
+        ];
+
+        assert!(matches!(
+            validate_trajectory(&transitions),
+            Err(StateMachineError::TimeInversion { index: 1, time, previous })
+                if time == 0.5 && previous == 1.0
+        ));
+
+        transitions[1].time = 2.0;
+        assert!(matches!(
+            validate_trajectory(&transitions),
+            Err(StateMachineError::BrokenChain {
+                index: 2,
+                from: 0,
+                previous_to: 2
+            })
+        ));
+
+        transitions[2].time = f64::NAN;
+        assert!(matches!(
+            validate_trajectory(&transitions),
+            Err(StateMachineError::TimeInversion { index: 2, .. })
+        ));
+    }
+}
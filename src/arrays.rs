@@ -1,10 +1,43 @@
-/// Array operations specific to rate coefficient calculations.
-use super::{CtrlParam, Rate};
+//! Array operations specific to rate coefficient calculations.
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::{CtrlParam, Rate, Time};
 
 #[derive(Debug)]
 pub struct ArrayError;
 
+impl fmt::Display for ArrayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the data does not match the shape of the array")
+    }
+}
+
+/// The fields of a dense array as they are serialized, which are validated before the array is
+/// built.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct ArrayParts<S> {
+    data: Vec<Rate>,
+    shape: S,
+}
+
+/// The fields of a sparse array as they are serialized.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct SparseArrayParts {
+    edges: Vec<(usize, usize)>,
+    data: Vec<Rate>,
+    shape: (usize, usize, usize, usize),
+}
+
+/// A 2D array in row-major order.
+///
+/// With the `serde` feature, arrays are serialized as their data and shape, and deserializing an
+/// array whose data does not match its shape fails.
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "ArrayParts<(usize, usize)>"))]
 pub struct Array2D {
     pub data: Vec<Rate>,
     pub shape: (usize, usize),
@@ -18,23 +51,93 @@ impl Array2D {
 
         Ok(Array2D { data, shape })
     }
+
+    /// Creates a n x n array of rate constants from the half-lives of the transitions.
+    ///
+    /// Each half-life is converted to a rate constant k = ln(2) / t_half. Half-lives that are not
+    /// positive and finite, e.g. zero, negative, NaN or infinite, denote the absence of a
+    /// transition and become the rate constant -1.0.
+    pub fn from_half_lives(half_lives: Vec<Time>, n: usize) -> Result<Array2D, ArrayError> {
+        let data = half_lives
+            .into_iter()
+            .map(|t_half| {
+                if t_half > 0.0 && t_half.is_finite() {
+                    core::f64::consts::LN_2 / t_half
+                } else {
+                    -1.0
+                }
+            })
+            .collect();
+
+        Array2D::new(data, (n, n))
+    }
+
+    /// Creates a n x n array of rate constants from their natural logarithms.
+    ///
+    /// Each element ln(k) is mapped to k = exp(ln(k)), which allows rates spanning many orders of
+    /// magnitude to be specified without writing out tiny numbers. `f64::NEG_INFINITY`, i.e.
+    /// ln(0), and NaN denote the absence of a transition and become the rate constant -1.0.
+    #[cfg(feature = "std")]
+    pub fn from_log_rates(log_rates: Vec<Rate>, n: usize) -> Result<Array2D, ArrayError> {
+        let data = log_rates
+            .into_iter()
+            .map(|log_rate| {
+                if log_rate.is_nan() || log_rate == f64::NEG_INFINITY {
+                    -1.0
+                } else {
+                    log_rate.exp()
+                }
+            })
+            .collect();
+
+        Array2D::new(data, (n, n))
+    }
+
+    /// Returns the `i`-th row of the array, or an error if `i` is out of range.
+    pub fn row(&self, i: usize) -> Result<&[Rate], ArrayError> {
+        let (rows, cols) = self.shape;
+        if i >= rows {
+            return Err(ArrayError);
+        }
+
+        Ok(&self.data[(i * cols)..((i * cols) + cols)])
+    }
 }
 
 /// Raise a vector of control parameters to integer powers of 1 to order.
 pub fn power(data: &[CtrlParam], order: u8) -> Array2D {
-    let mut result = Vec::with_capacity(data.len() * usize::from(order));
+    let mut result = Array2D {
+        data: Vec::with_capacity(data.len() * usize::from(order)),
+        shape: (0, 0),
+    };
+    power_into(data, order, &mut result);
+
+    result
+}
+
+/// Raise a vector of control parameters to integer powers of 1 to order, writing into `out`.
+///
+/// The contents of `out` are overwritten; its allocation is reused when large enough.
+pub fn power_into(data: &[CtrlParam], order: u8, out: &mut Array2D) {
+    out.data.clear();
 
     for i in data.iter() {
-        for j in 1..i32::from(order + 1) {
-            result.push((*i).powi(j));
+        let mut value: CtrlParam = 1.0;
+        for _ in 0..order {
+            value *= *i;
+            out.data.push(value);
         }
     }
 
-    let shape = (data.len(), usize::from(order));
-
-    Array2D::new(result, shape).expect("failed to create 2D array")
+    out.shape = (data.len(), usize::from(order));
 }
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(try_from = "ArrayParts<(usize, usize, usize, usize)>")
+)]
 pub struct Array4D {
     data: Vec<Rate>,
     pub shape: (usize, usize, usize, usize),
@@ -51,38 +154,186 @@ impl Array4D {
 
         Ok(Array4D { data, shape })
     }
+
+    /// Returns the element at index (i, j, k, l).
+    ///
+    /// Panics if any index is out of range for its axis.
+    pub fn get(&self, i: usize, j: usize, k: usize, l: usize) -> Rate {
+        let (_, _, k4, l4) = self.shape;
+        assert!(
+            k < k4 && l < l4,
+            "index ({i}, {j}, {k}, {l}) is out of range for shape {:?}",
+            self.shape
+        );
+
+        self.matrix(i, j)[k * l4 + l]
+    }
+
+    /// Returns the K x L matrix at index (i, j) as a row-major slice.
+    ///
+    /// Panics if any index is out of range for its axis.
+    pub fn matrix(&self, i: usize, j: usize) -> &[Rate] {
+        let (i4, j4, k4, l4) = self.shape;
+        assert!(
+            i < i4 && j < j4,
+            "index ({i}, {j}) is out of range for shape {:?}",
+            self.shape
+        );
+
+        let size = k4 * l4;
+        let offset = (i * j4 + j) * size;
+        &self.data[offset..(offset + size)]
+    }
+}
+
+/// A I x J x K x L array that only stores the I x J coefficients of a few (k, l) pairs.
+///
+/// This is a memory-efficient representation of the rate coefficients of a state machine with
+/// few edges: each edge (k, l) from state k to state l has one I x J block of coefficients, and
+/// the elements of all the other pairs are zero.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "SparseArrayParts"))]
+pub struct SparseArray4D {
+    edges: Vec<(usize, usize)>,
+    data: Vec<Rate>,
+    pub shape: (usize, usize, usize, usize),
+}
+
+impl SparseArray4D {
+    /// Creates a new sparse array from its edges and the I x J block of each edge.
+    ///
+    /// `data` holds the blocks of the edges in the order of `edges`, each in row-major order.
+    /// An error is returned if its length is not `edges.len() * I * J` or if an edge is out of
+    /// range.
+    pub fn new(
+        edges: Vec<(usize, usize)>,
+        data: Vec<Rate>,
+        shape: (usize, usize, usize, usize),
+    ) -> Result<SparseArray4D, ArrayError> {
+        let (i4, j4, k4, l4) = shape;
+        if data.len() != edges.len() * i4 * j4 || edges.iter().any(|(k, l)| *k >= k4 || *l >= l4) {
+            return Err(ArrayError);
+        }
+
+        Ok(SparseArray4D { edges, data, shape })
+    }
+
+    /// Creates a sparse array with one edge per (k, l) pair that has a non-zero coefficient.
+    pub fn from_dense(arr: &Array4D) -> SparseArray4D {
+        let (i4, j4, k4, l4) = arr.shape;
+        let mut edges = Vec::new();
+        let mut data = Vec::new();
+        for k in 0..k4 {
+            for l in 0..l4 {
+                let block = (0..i4).flat_map(|i| (0..j4).map(move |j| (i, j)));
+                if block.clone().any(|(i, j)| arr.get(i, j, k, l) != 0.0) {
+                    edges.push((k, l));
+                    data.extend(block.map(|(i, j)| arr.get(i, j, k, l)));
+                }
+            }
+        }
+
+        SparseArray4D {
+            edges,
+            data,
+            shape: arr.shape,
+        }
+    }
+
+    /// Returns the (k, l) pairs that have coefficients.
+    pub fn edges(&self) -> &[(usize, usize)] {
+        &self.edges
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<ArrayParts<(usize, usize)>> for Array2D {
+    type Error = ArrayError;
+
+    fn try_from(parts: ArrayParts<(usize, usize)>) -> Result<Self, Self::Error> {
+        Array2D::new(parts.data, parts.shape)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<ArrayParts<(usize, usize, usize, usize)>> for Array4D {
+    type Error = ArrayError;
+
+    fn try_from(parts: ArrayParts<(usize, usize, usize, usize)>) -> Result<Self, Self::Error> {
+        Array4D::new(parts.data, parts.shape)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<SparseArrayParts> for SparseArray4D {
+    type Error = ArrayError;
+
+    fn try_from(parts: SparseArrayParts) -> Result<Self, Self::Error> {
+        SparseArray4D::new(parts.edges, parts.data, parts.shape)
+    }
 }
 
 /// Compute the Einstein summation "ijkl->kl" of a I x J 2D array and a I x J x K x L 4D array.
 pub fn tensordot(arr1: &Array2D, arr2: &Array4D) -> Array2D {
-    let (i1, j2) = arr1.shape;
-    let (i2, j2, k2, l2) = arr2.shape;
+    let mut result = Array2D {
+        data: Vec::with_capacity(arr2.shape.2 * arr2.shape.3),
+        shape: (0, 0),
+    };
+    tensordot_into(arr1, arr2, &mut result);
 
-    let mut result = Vec::with_capacity(k2 * l2);
+    result
+}
 
-    for k in 0..k2 {
-        for l in 0..l2 {
-            let mut total = 0f64;
+/// Compute the Einstein summation "ijkl->kl" of a I x J 2D array and a I x J x K x L 4D array,
+/// writing into `out`.
+///
+/// The contents of `out` are overwritten; its allocation is reused when large enough.
+pub fn tensordot_into(arr1: &Array2D, arr2: &Array4D, out: &mut Array2D) {
+    let (i2, j2, k2, l2) = arr2.shape;
 
-            for i in 0..i2 {
-                for j in 0..j2 {
-                    total += arr1.data[i * j2 + j]
-                        * arr2.data[(i * j2 * k2 * l2) + (j * k2 * l2) + (k * l2) + l];
-                }
+    out.data.clear();
+    out.data.resize(k2 * l2, 0.0);
+
+    for i in 0..i2 {
+        for j in 0..j2 {
+            let factor = arr1.data[i * j2 + j];
+            for (total, value) in out.data.iter_mut().zip(arr2.matrix(i, j)) {
+                *total += factor * value;
             }
-            result.push(total);
         }
     }
 
-    Array2D {
-        data: result,
-        shape: (k2, l2),
+    out.shape = (k2, l2);
+}
+
+/// Compute the Einstein summation "ijkl->kl" of a I x J 2D array and a sparse I x J x K x L 4D
+/// array, writing into `out`.
+///
+/// Only the edges of the sparse array are evaluated; all other elements of `out` are zero.
+pub fn tensordot_sparse_into(arr1: &Array2D, arr2: &SparseArray4D, out: &mut Array2D) {
+    let (i2, j2, k2, l2) = arr2.shape;
+    let block_size = i2 * j2;
+
+    out.data.clear();
+    out.data.resize(k2 * l2, 0.0);
+
+    for (block, (k, l)) in arr2.data.chunks_exact(block_size).zip(arr2.edges.iter()) {
+        let mut total = 0f64;
+        for (factor, value) in arr1.data[..block_size].iter().zip(block) {
+            total += factor * value;
+        }
+        out.data[k * l2 + l] += total;
     }
+
+    out.shape = (k2, l2);
 }
 
+#[cfg(test)]
 mod tests {
-    #[cfg(test)]
-    use super::{power, tensordot, Array2D, Array4D};
+    use super::{power, tensordot, tensordot_sparse_into, Array2D, Array4D, SparseArray4D};
+    #[cfg(feature = "std")]
+    use crate::analysis::solve_master_equation;
 
     #[test]
     fn test_power() {
@@ -91,11 +342,23 @@ mod tests {
 
         let result = power(&ctrl_params, 3);
 
-        for (actual, expected) in result.data.into_iter().zip(expected.into_iter()) {
+        for (actual, expected) in result.data.into_iter().zip(expected) {
             assert_eq!(actual, expected)
         }
     }
 
+    #[test]
+    fn test_power_of_max_order() {
+        let ctrl_params: [f64; 2] = [1.0, -1.0];
+
+        let result = power(&ctrl_params, u8::MAX);
+
+        assert_eq!((2, 255), result.shape);
+        assert_eq!(2 * 255, result.data.len());
+        assert_eq!(1.0, result.data[254]);
+        assert_eq!(-1.0, result.data[2 * 255 - 1]);
+    }
+
     #[test]
     fn test_tensordot() {
         let ctrl_params: [f64; 2] = [2.0, 3.0];
@@ -111,8 +374,155 @@ mod tests {
 
         let result = tensordot(&powers, &rate_coefficients);
 
-        for (actual, expected) in result.data.into_iter().zip(expected.into_iter()) {
+        for (actual, expected) in result.data.into_iter().zip(expected) {
             assert_eq!(actual, expected)
         }
     }
+
+    #[test]
+    fn test_array4d_get() {
+        let shape = (2, 3, 4, 5);
+        let data: Vec<f64> = (0..120).map(f64::from).collect();
+        let arr = Array4D::new(data.clone(), shape).unwrap();
+
+        for (i, j, k, l) in [(0, 0, 0, 0), (1, 2, 3, 4), (0, 1, 2, 3), (1, 0, 3, 1)] {
+            let offset = (i * 3 * 4 * 5) + (j * 4 * 5) + (k * 5) + l;
+            assert_eq!(data[offset], arr.get(i, j, k, l));
+        }
+        assert_eq!(&data[80..100], arr.matrix(1, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn test_array4d_get_out_of_range() {
+        let arr = Array4D::new(vec![0.0; 8], (1, 2, 2, 2)).unwrap();
+
+        // The flat offset of (0, 0, 1, 2) is in range, but the index is not
+        arr.get(0, 0, 1, 2);
+    }
+
+    #[test]
+    fn test_tensordot_sparse() {
+        // Two control parameters, order 2, three states with four edges
+        let mut data = vec![0.0; 2 * 2 * 3 * 3];
+        for (index, (k, l)) in [(0, 1), (1, 0), (1, 2), (2, 2)].into_iter().enumerate() {
+            for i in 0..2 {
+                for j in 0..2 {
+                    data[(i * 2 * 9) + (j * 9) + (k * 3) + l] =
+                        (index + 1) as f64 * (i + 2 * j + 1) as f64;
+                }
+            }
+        }
+        let dense = Array4D::new(data, (2, 2, 3, 3)).unwrap();
+        let sparse = SparseArray4D::from_dense(&dense);
+        let powers = power(&[0.5, 3.0], 2);
+        let mut result = Array2D::new(Vec::new(), (0, 0)).unwrap();
+
+        tensordot_sparse_into(&powers, &sparse, &mut result);
+
+        let expected = tensordot(&powers, &dense);
+        assert_eq!(&[(0, 1), (1, 0), (1, 2), (2, 2)], sparse.edges());
+        assert_eq!(expected.shape, result.shape);
+        assert_eq!(expected.data, result.data);
+    }
+
+    #[test]
+    fn test_sparse_array4d_new_validates_edges() {
+        assert!(SparseArray4D::new(vec![(0, 1)], vec![1.0; 2], (1, 2, 2, 2)).is_ok());
+        assert!(SparseArray4D::new(vec![(0, 2)], vec![1.0; 2], (1, 2, 2, 2)).is_err());
+        assert!(SparseArray4D::new(vec![(0, 1)], vec![1.0; 3], (1, 2, 2, 2)).is_err());
+    }
+
+    #[test]
+    fn test_row() {
+        let arr = Array2D::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], (2, 3)).unwrap();
+
+        assert_eq!(&[1.0, 2.0, 3.0], arr.row(0).unwrap());
+        assert_eq!(&[4.0, 5.0, 6.0], arr.row(1).unwrap());
+        assert!(arr.row(2).is_err());
+    }
+
+    #[test]
+    fn test_from_half_lives() {
+        let t_half = 2.0;
+        let half_lives = vec![0.0, t_half, -1.0, f64::NAN];
+
+        let result = Array2D::from_half_lives(half_lives, 2).unwrap();
+
+        assert_eq!(
+            vec![-1.0, std::f64::consts::LN_2 / t_half, -1.0, -1.0],
+            result.data
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_from_half_lives_dynamics() {
+        let t_half = 2.0;
+        let rate_constants = Array2D::from_half_lives(vec![0.0, t_half, 0.0, 0.0], 2).unwrap();
+
+        // Half of the population has left state 0 after one half-life
+        let p = solve_master_equation(&rate_constants, &[1.0, 0.0], &[t_half]).unwrap();
+        assert!((p[0][0] - 0.5).abs() < 1e-8);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_from_log_rates() {
+        let linear = Array2D::new(vec![-1.0, 1e-12, 3.0, -1.0], (2, 2)).unwrap();
+        let log_rates = vec![f64::NEG_INFINITY, 1e-12f64.ln(), 3f64.ln(), f64::NAN];
+
+        let rate_constants = Array2D::from_log_rates(log_rates, 2).unwrap();
+
+        assert_eq!(-1.0, rate_constants.data[0]);
+        assert_eq!(-1.0, rate_constants.data[3]);
+        for (actual, expected) in rate_constants.data.iter().zip(linear.data.iter()) {
+            assert!((actual - expected).abs() <= 1e-12 * expected.abs());
+        }
+
+        // Both matrices produce the same dynamics
+        let times = [0.5, 1.0, 2.0];
+        let p_log = solve_master_equation(&rate_constants, &[0.2, 0.8], &times).unwrap();
+        let p_linear = solve_master_equation(&linear, &[0.2, 0.8], &times).unwrap();
+        for (row_log, row_linear) in p_log.iter().zip(p_linear.iter()) {
+            for (a, b) in row_log.iter().zip(row_linear.iter()) {
+                assert!((a - b).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_half_lives_wrong_shape() {
+        assert!(Array2D::from_half_lives(vec![1.0, 1.0, 1.0], 2).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_round_trip() {
+        let dense = Array4D::new(vec![0.0, 1.0, 2.0, 0.0], (1, 1, 2, 2)).unwrap();
+        let sparse = SparseArray4D::from_dense(&dense);
+
+        let loaded: Array4D =
+            serde_json::from_str(&serde_json::to_string(&dense).unwrap()).unwrap();
+        let loaded_sparse: SparseArray4D =
+            serde_json::from_str(&serde_json::to_string(&sparse).unwrap()).unwrap();
+
+        assert_eq!(dense.shape, loaded.shape);
+        assert_eq!(dense.data, loaded.data);
+        assert_eq!(sparse.edges(), loaded_sparse.edges());
+        assert_eq!(sparse.data, loaded_sparse.data);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_rejects_data_not_matching_shape() {
+        assert!(serde_json::from_str::<Array2D>(r#"{"data":[1.0,2.0],"shape":[2,2]}"#).is_err());
+        assert!(
+            serde_json::from_str::<Array4D>(r#"{"data":[1.0,2.0],"shape":[1,1,2,2]}"#).is_err()
+        );
+        assert!(serde_json::from_str::<SparseArray4D>(
+            r#"{"edges":[[0,2]],"data":[1.0],"shape":[1,1,2,2]}"#
+        )
+        .is_err());
+    }
 }
@@ -0,0 +1,63 @@
+//! Provides the distributions of the times that a state machine waits before a transition.
+use core::num::NonZeroU32;
+
+use rand::Rng;
+use rand_distr::{Distribution, Exp};
+
+use crate::{Rate, Result, Time};
+
+/// Distributions of the waiting time before a transition along a single edge of a state machine.
+///
+/// A stepper draws one waiting time per possible transition out of its current state; the
+/// smallest one determines the next state and the transition time.
+pub trait HoldingTime {
+    /// Draws a random waiting time for a transition that occurs at the given rate.
+    fn sample<R: Rng + ?Sized>(&self, rate: Rate, rng: &mut R) -> Result<Time>;
+}
+
+/// Exponentially distributed waiting times, i.e. a memoryless (Markov) state machine.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Exponential;
+
+impl HoldingTime for Exponential {
+    fn sample<R: Rng + ?Sized>(&self, rate: Rate, rng: &mut R) -> Result<Time> {
+        Ok(Exp::new(rate)?.sample(rng))
+    }
+}
+
+/// Exponentially distributed waiting times drawn by inverse transform sampling.
+///
+/// The waiting time is -ln(1 - u) / rate for a uniform random number u. This is slower than
+/// `Exponential`, but the waiting time is a monotonic function of u, so machines driven by
+/// complementary random numbers, e.g. by an `AntitheticRng`, have negatively correlated waiting
+/// times.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExponentialByInversion;
+
+#[cfg(feature = "std")]
+impl HoldingTime for ExponentialByInversion {
+    fn sample<R: Rng + ?Sized>(&self, rate: Rate, rng: &mut R) -> Result<Time> {
+        let u: f64 = rng.gen();
+
+        Ok(-(1.0 - u).ln() / rate)
+    }
+}
+
+/// Erlang distributed waiting times, i.e. the sum of `shape` exponential waiting times.
+///
+/// The mean waiting time is 1 / rate regardless of the shape, but larger shapes produce narrower
+/// distributions. A shape of 1 is equivalent to `Exponential`.
+#[derive(Clone, Copy, Debug)]
+pub struct Erlang {
+    pub shape: NonZeroU32,
+}
+
+impl HoldingTime for Erlang {
+    fn sample<R: Rng + ?Sized>(&self, rate: Rate, rng: &mut R) -> Result<Time> {
+        let shape = self.shape.get();
+        let exp = Exp::new(rate * f64::from(shape))?;
+
+        Ok((0..shape).map(|_| exp.sample(rng)).sum())
+    }
+}
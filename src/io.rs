@@ -0,0 +1,202 @@
+//! Reading and writing state machines and their outputs in common file formats.
+use std::io::{BufRead, Error, ErrorKind, Write};
+
+use crate::arrays::Array2D;
+use crate::{CtrlParam, Time};
+
+/// Writes the graph of the transitions between states as a Graphviz DOT digraph.
+///
+/// Each state is a node and each positive rate an edge labeled by its value; non-positive rates
+/// denote the absence of a transition and are omitted. The nodes are labeled by `labels` if given,
+/// else by their indices. An error is returned if the number of labels does not match the number
+/// of states.
+pub fn write_dot<W: Write>(
+    mut writer: W,
+    rate_constants: &Array2D,
+    labels: Option<&[String]>,
+) -> std::io::Result<()> {
+    let (rows, _cols) = rate_constants.shape;
+    if let Some(labels) = labels {
+        if labels.len() != rows {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("expected {rows} label(s), but received {}", labels.len()),
+            ));
+        }
+    }
+
+    writeln!(writer, "digraph {{")?;
+    if let Some(labels) = labels {
+        for (state, label) in labels.iter().enumerate() {
+            writeln!(writer, "    {state} [label=\"{}\"];", escape(label))?;
+        }
+    }
+    for from in 0..rows {
+        for (to, rate) in rate_constants.row(from).unwrap_or(&[]).iter().enumerate() {
+            if *rate > 0.0 {
+                writeln!(writer, "    {from} -> {to} [label=\"{rate}\"];")?;
+            }
+        }
+    }
+    writeln!(writer, "}}")?;
+
+    Ok(())
+}
+
+/// Reads a schedule of control parameters from CSV data.
+///
+/// Each row holds a time followed by the control parameters that apply from that time on, e.g.
+/// `0.0,1.5` then `10.0,3.0`. A header row, blank lines, and whitespace around the fields are
+/// ignored. An error is returned if a field is not a number, if the rows have different numbers of
+/// fields, or if the times are not strictly increasing. The result can be passed to
+/// `StepUntilPiecewise::new`.
+pub fn read_ctrl_schedule_csv<R: BufRead>(
+    reader: R,
+) -> std::io::Result<Vec<(Time, Vec<CtrlParam>)>> {
+    let mut schedule: Vec<(Time, Vec<CtrlParam>)> = Vec::new();
+    let mut is_first_row = true;
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row_number = index + 1;
+
+        let fields: core::result::Result<Vec<f64>, _> = line
+            .split(',')
+            .map(|field| field.trim().parse::<f64>())
+            .collect();
+        let fields = match fields {
+            Ok(fields) => fields,
+            // The first row may be a header
+            Err(_) if is_first_row => {
+                is_first_row = false;
+                continue;
+            }
+            Err(err) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("row {row_number}: {err}"),
+                ))
+            }
+        };
+        is_first_row = false;
+
+        if let Some((_, first)) = schedule.first() {
+            if fields.len() != first.len() + 1 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "row {row_number}: expected {} field(s), but received {}",
+                        first.len() + 1,
+                        fields.len()
+                    ),
+                ));
+            }
+        }
+        let time = fields[0];
+        if schedule.last().is_some_and(|(t, _)| time <= *t) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("row {row_number}: the times must be strictly increasing"),
+            ));
+        }
+        schedule.push((time, fields[1..].to_vec()));
+    }
+
+    Ok(schedule)
+}
+
+/// Escapes the characters that would end a quoted DOT string.
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::{read_ctrl_schedule_csv, write_dot};
+    use crate::accumulators::StepUntilPiecewise;
+    use crate::arrays::{Array2D, Array4D};
+    use crate::steppers::Stepper;
+
+    fn three_state_rates() -> Array2D {
+        Array2D::new(
+            vec![-1.0, 0.5, -1.0, 2.0, -1.0, 1.5, 0.0, 3.0, -1.0],
+            (3, 3),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn write_dot_three_states() {
+        let mut out = Vec::new();
+
+        write_dot(&mut out, &three_state_rates(), None).unwrap();
+
+        assert_eq!(
+            "digraph {\n    0 -> 1 [label=\"0.5\"];\n    1 -> 0 [label=\"2\"];\n    1 -> 2 [label=\"1.5\"];\n    2 -> 1 [label=\"3\"];\n}\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn write_dot_with_labels() {
+        let mut out = Vec::new();
+        let labels = ["on".to_string(), "off".to_string(), "\"dark\"".to_string()];
+
+        write_dot(&mut out, &three_state_rates(), Some(&labels)).unwrap();
+
+        let dot = String::from_utf8(out).unwrap();
+        assert!(dot.starts_with(
+            "digraph {\n    0 [label=\"on\"];\n    1 [label=\"off\"];\n    2 [label=\"\\\"dark\\\"\"];\n"
+        ));
+        assert!(dot.contains("    2 -> 1 [label=\"3\"];\n"));
+    }
+
+    #[test]
+    fn write_dot_rejects_wrong_number_of_labels() {
+        let labels = ["on".to_string()];
+
+        assert!(write_dot(Vec::new(), &three_state_rates(), Some(&labels)).is_err());
+    }
+
+    #[test]
+    fn read_ctrl_schedule_csv_and_run_machine() {
+        let csv = "time,intensity\n0.0, 1.0\n10.0, 0.0\n\n20.0, 2.0\n";
+
+        let schedule = read_ctrl_schedule_csv(csv.as_bytes()).unwrap();
+
+        assert_eq!(
+            vec![(0.0, vec![1.0]), (10.0, vec![0.0]), (20.0, vec![2.0])],
+            schedule
+        );
+
+        // The rates are proportional to the intensity, so nothing happens while it is zero
+        let stepper = Stepper::new(0, Array2D::new(vec![-1.0; 4], (2, 2)).unwrap())
+            .with_rate_coefficients(Array4D::new(vec![-1.0, 1.0, 1.0, -1.0], (1, 1, 2, 2)).unwrap())
+            .unwrap();
+        let mut acc = StepUntilPiecewise::new(stepper, schedule, 30.0).unwrap();
+        let transitions = acc.accumulate(&mut StdRng::seed_from_u64(6)).unwrap();
+
+        assert!(!transitions.is_empty());
+        assert!(transitions.iter().all(|t| t.time <= 10.0 || t.time > 20.0));
+    }
+
+    #[test]
+    fn read_ctrl_schedule_csv_rejects_invalid_rows() {
+        for csv in [
+            "0.0,1.0\n2.0,1.0\n1.0,1.0\n",
+            "0.0,1.0\n1.0,1.0,2.0\n",
+            "0.0,1.0\n1.0,high\n",
+        ] {
+            let err = read_ctrl_schedule_csv(csv.as_bytes()).unwrap_err();
+
+            assert_eq!(ErrorKind::InvalidData, err.kind());
+        }
+    }
+}
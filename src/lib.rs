@@ -8,55 +8,303 @@
 //!
 //! Importantly, RATS allows you to specify transition probabilities that depend on external
 //! control parameters, such as the degree of laser irradiation incident on a flourophore.
-use ::thiserror::Error;
-use ndarray::ArrayView1;
-use rand::prelude::*;
+//!
+//! The core state machine logic (`Step`, `Accumulate` and their implementations) does not depend
+//! on the standard library. Disable the default features to use it in `no_std` environments that
+//! provide an allocator and a random number generator. Only the `rlib` is `no_std`; the `cdylib`
+//! imported by Python needs the `python` feature, so build the core alone with
+//! `cargo rustc --lib --no-default-features --crate-type rlib`.
+// The tests use the standard library, so the no_std build is checked on a target without it
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+
+#[cfg(feature = "std")]
+use rand::distributions::{Distribution, WeightedIndex};
+#[cfg(feature = "std")]
+use rand::SeedableRng;
+#[cfg(feature = "std")]
+use rand_chacha::ChaCha12Rng;
 use rand_distr::ExpError;
+#[cfg(feature = "std")]
 use rayon::prelude::*;
 
+use crate::accumulators::StepUntil;
+use crate::arrays::Array2D;
+#[cfg(feature = "std")]
+use crate::rngs::AntitheticRng;
+use crate::steppers::Stepper;
+
 type CtrlParam = f64;
 type Rate = f64;
 type State = usize;
 type Time = f64;
 
-type Result<T> = std::result::Result<T, StateMachineError>;
+type Result<T> = core::result::Result<T, StateMachineError>;
 
 /// Error type returned when a function or method fails.
-#[derive(Debug, Error)]
+#[derive(Debug)]
 pub enum StateMachineError {
-    #[error("array has the wrong number of elements: expected {expected:?} element(s), but received {actual:?}")]
-    NumElems { actual: usize, expected: usize },
-    #[error(transparent)]
-    RngError(#[from] ExpError),
-    #[error("The StateMachine has stopped")]
+    NumElems {
+        actual: usize,
+        expected: usize,
+    },
+    BrokenChain {
+        index: usize,
+        from: State,
+        previous_to: State,
+    },
+    InvalidChunkSize,
+    InvalidCutoff(Time),
+    InvalidDistribution,
+    InvalidEvents,
+    InvalidMinDwellTime(Time),
+    InvalidRateBound(Rate),
+    InvalidSchedule,
+    InvalidStateSets,
+    InvalidStartTime(Time),
+    InvalidTimes,
+    InvalidWalkersPerBin,
+    Interrupted,
+    RateBoundExceeded {
+        rate: Rate,
+        bound: Rate,
+    },
+    RateMismatch(Vec<(State, State, Rate, Rate)>),
+    RngError(ExpError),
+    SingularMatrix,
+    StateOutOfRange {
+        state: State,
+        num_states: usize,
+    },
     Stopped,
+    TimeInversion {
+        index: usize,
+        time: Time,
+        previous: Time,
+    },
+    WaitingTimeUnderflow(Time, State),
+}
+
+impl fmt::Display for StateMachineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateMachineError::NumElems { actual, expected } => write!(
+                f,
+                "array has the wrong number of elements: expected {expected:?} element(s), but received {actual:?}"
+            ),
+            StateMachineError::BrokenChain {
+                index,
+                from,
+                previous_to,
+            } => write!(
+                f,
+                "transition {index} starts in state {from}, but the previous transition ended in state {previous_to}"
+            ),
+            StateMachineError::InvalidChunkSize => write!(f, "the chunk size must be positive"),
+            StateMachineError::InvalidCutoff(t_cutoff) => write!(
+                f,
+                "t_cutoff must be positive and finite, or infinite, but received {t_cutoff}"
+            ),
+            StateMachineError::InvalidDistribution => write!(
+                f,
+                "the distribution must be a non-empty list of non-negative, finite weights with a positive sum"
+            ),
+            StateMachineError::InvalidEvents => write!(
+                f,
+                "the event times must be non-negative, finite, and non-decreasing"
+            ),
+            StateMachineError::InvalidMinDwellTime(min_dwell_time) => write!(
+                f,
+                "the minimum dwell time must be non-negative and finite, but received {min_dwell_time}"
+            ),
+            StateMachineError::InvalidRateBound(bound) => write!(
+                f,
+                "the rate bound must be positive and finite, but received {bound}"
+            ),
+            StateMachineError::InvalidSchedule => write!(
+                f,
+                "the schedule must be non-empty, start at time zero, and have strictly increasing, finite times"
+            ),
+            StateMachineError::InvalidStateSets => write!(
+                f,
+                "the reactant and product state sets must be non-empty and disjoint"
+            ),
+            StateMachineError::InvalidStartTime(t_start) => {
+                write!(f, "t_start must be finite, but received {t_start}")
+            }
+            StateMachineError::InvalidTimes => write!(
+                f,
+                "the times must be non-negative, finite, and non-decreasing"
+            ),
+            StateMachineError::InvalidWalkersPerBin => {
+                write!(f, "the number of walkers per bin must be positive")
+            }
+            StateMachineError::Interrupted => write!(f, "the accumulation was interrupted"),
+            StateMachineError::RateBoundExceeded { rate, bound } => write!(
+                f,
+                "the total rate {rate} out of the current state exceeds the rate bound {bound}"
+            ),
+            StateMachineError::RateMismatch(entries) => {
+                write!(f, "the estimated rates deviate from the expected ones:")?;
+                for (from, to, expected, estimated) in entries {
+                    write!(
+                        f,
+                        " ({from} -> {to}: expected {expected}, estimated {estimated})"
+                    )?;
+                }
+                Ok(())
+            }
+            StateMachineError::RngError(err) => err.fmt(f),
+            StateMachineError::SingularMatrix => write!(
+                f,
+                "the linear system has no unique solution; check that the chain is irreducible"
+            ),
+            StateMachineError::StateOutOfRange { state, num_states } => write!(
+                f,
+                "state {state} is out of range for a machine with {num_states} state(s)"
+            ),
+            StateMachineError::Stopped => write!(f, "The StateMachine has stopped"),
+            StateMachineError::TimeInversion {
+                index,
+                time,
+                previous,
+            } => write!(
+                f,
+                "transition {index} occurs at time {time}, before the previous transition at time {previous}"
+            ),
+            StateMachineError::WaitingTimeUnderflow(time, state) => write!(
+                f,
+                "the waiting time {time:e} out of state {state} is too small to simulate stably; the rates out of this state are too large"
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for StateMachineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StateMachineError::RngError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ExpError> for StateMachineError {
+    fn from(err: ExpError) -> Self {
+        StateMachineError::RngError(err)
+    }
+}
+
+/// Floating point types that represent the simulated time, i.e. `f32` and `f64`.
+///
+/// Waiting times are sampled in double precision and converted to the time type, so `f32` halves
+/// the memory needed to store transitions at the cost of a coarser resolution of late times.
+pub trait Float:
+    Copy
+    + PartialOrd
+    + fmt::Debug
+    + fmt::Display
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + AddAssign
+    + SubAssign
+    + Send
+    + Sync
+    + 'static
+{
+    const ZERO: Self;
+    const MIN_POSITIVE: Self;
+
+    /// Converts a double precision value, rounding it to the nearest representable value.
+    fn from_f64(value: f64) -> Self;
+
+    /// Converts the value to double precision.
+    fn to_f64(self) -> f64;
+}
+
+impl Float for f32 {
+    const ZERO: Self = 0.0;
+    const MIN_POSITIVE: Self = f32::MIN_POSITIVE;
+
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+
+    fn to_f64(self) -> f64 {
+        f64::from(self)
+    }
+}
+
+impl Float for f64 {
+    const ZERO: Self = 0.0;
+    const MIN_POSITIVE: Self = f64::MIN_POSITIVE;
+
+    fn from_f64(value: f64) -> Self {
+        value
+    }
+
+    fn to_f64(self) -> f64 {
+        self
+    }
 }
 
 /// A transition of a state machine from one state to another.
 ///
-/// Transitions can occur at any point in time, i.e. the time dimension is continuous.
+/// Transitions can occur at any point in time, i.e. the time dimension is continuous. The times
+/// are of type `T`, by default `f64`.
 #[derive(Clone, Debug)]
-pub struct Transition {
+pub struct Transition<T: Float = Time> {
     from: State,
-    time: Time,
+    time: T,
+    dwell: T,
     to: State,
 }
 
-impl Transition {
+impl<T: Float> Transition<T> {
     /// Returns the state from which the state machine transitioned
     pub fn from(&self) -> State {
         self.from
     }
 
     /// Returns the time at which the state machine transitioned
-    pub fn time(&self) -> Time {
+    pub fn time(&self) -> T {
         self.time
     }
 
+    /// Returns the time the state machine spent in the `from` state before transitioning.
+    ///
+    /// This is the waiting time sampled by `Step::step`. Unlike `time`, it is not offset when
+    /// accumulators make the transition times cumulative, so in a trajectory it is the time since
+    /// the previous transition or, for the first one, since the start of the run.
+    pub fn dwell(&self) -> T {
+        self.dwell
+    }
+
     /// Returns the state to which the state machine transitioned
     pub fn to(&self) -> State {
         self.to
     }
+
+    /// Returns the transition with its `from` and `to` states relabeled by `f`, e.g. to map the
+    /// states of a machine onto macrostates.
+    pub fn map_states<F: Fn(State) -> State>(self, f: F) -> Self {
+        Transition {
+            from: f(self.from),
+            to: f(self.to),
+            ..self
+        }
+    }
+}
+
+impl<T: Float> fmt::Display for Transition<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[t={}] {} -> {}", self.time, self.from, self.to)
+    }
 }
 
 /// State machines that may undergo a transition from one state to another.
@@ -69,7 +317,9 @@ impl Transition {
 /// - **ctrl_params** A 1D slice of zero or more control parameters that determine the transition
 ///   probabilities from the machine's current state to all the possible subsequent states
 /// - **rng** A random number generator
-pub trait Step {
+///
+/// The times of the transitions are of type `T`, by default `f64`.
+pub trait Step<T: Float = Time> {
     /// Returns the current state of the state machine.
     fn current_state(&self) -> State;
 
@@ -78,19 +328,45 @@ pub trait Step {
         &mut self,
         ctrl_params: &[CtrlParam],
         rng: &mut R,
-    ) -> Result<Transition>;
+    ) -> Result<Transition<T>>;
 }
 
 /// Types that accumulate transitions from a `Step` type until a stop conditioned is reached.
-pub trait Accumulate {
+pub trait Accumulate<T: Float = Time> {
     fn accumulate<R: rand::Rng + ?Sized>(
         &mut self,
         ctrl_params: &[f64],
         rng: &mut R,
-    ) -> Result<&[Transition]>;
+    ) -> Result<&[Transition<T>]>;
+}
+
+/// Simulates a memoryless state machine from `starting_state` until `t_cutoff` is exceeded.
+///
+/// This is a shortcut for the common case that creates a `Stepper` from `rate_constants` and runs
+/// it with a random number generator seeded with `seed`, so the trajectory is reproducible. The
+/// times of the transitions are cumulative. The trajectory ends early if the machine reaches an
+/// absorbing state.
+#[cfg(feature = "std")]
+pub fn simulate(
+    rate_constants: Array2D,
+    starting_state: State,
+    t_cutoff: Time,
+    seed: u64,
+) -> Result<Vec<Transition>> {
+    let mut rng = ChaCha12Rng::seed_from_u64(seed);
+
+    Ok(
+        StepUntil::new(Stepper::new(starting_state, rate_constants), t_cutoff)?
+            .accumulate(&[], &mut rng)?
+            .to_vec(),
+    )
 }
 
 /// Accumulates transitions from a collection of state machines in parallel.
+///
+/// Empty inputs are valid: running zero machines returns an empty vector. An error is returned
+/// only if the numbers of accumulators and control parameter slices differ.
+#[cfg(feature = "std")]
 pub fn par_accumulate<A: Accumulate + Send>(
     accumulators: &mut [A],
     ctrl_params: &[&[f64]],
@@ -103,29 +379,401 @@ pub fn par_accumulate<A: Accumulate + Send>(
     };
 
     // This creates an object of type MultiZip from the Rayon crate
+    par_accumulate_iter(
+        (accumulators, ctrl_params)
+            .into_par_iter()
+            .map(|(accumulator, ctrl_params)| (accumulator, *ctrl_params)),
+    )
+}
+
+/// Accumulates transitions from a collection of state machines in parallel, `chunk_size`
+/// machines at a time, to limit the peak memory of huge ensembles.
+///
+/// `sink` is called with the index and the transitions of each machine of a chunk once the whole
+/// chunk has run, in the order of the machines; the transitions are dropped afterwards unless the
+/// sink keeps them. An error is returned if the numbers of accumulators and control parameter
+/// slices differ, if `chunk_size` is zero, or as soon as a machine fails, in which case the later
+/// chunks are not run.
+#[cfg(feature = "std")]
+pub fn par_accumulate_chunked<A, F>(
+    accumulators: &mut [A],
+    ctrl_params: &[&[f64]],
+    chunk_size: usize,
+    mut sink: F,
+) -> Result<()>
+where
+    A: Accumulate + Send,
+    F: FnMut(usize, Vec<Transition>),
+{
+    if accumulators.len() != ctrl_params.len() {
+        return Err(StateMachineError::NumElems {
+            actual: ctrl_params.len(),
+            expected: accumulators.len(),
+        });
+    };
+    if chunk_size == 0 {
+        return Err(StateMachineError::InvalidChunkSize);
+    }
+
+    let chunks = accumulators
+        .chunks_mut(chunk_size)
+        .zip(ctrl_params.chunks(chunk_size));
+    for (chunk_index, (accumulators, ctrl_params)) in chunks.enumerate() {
+        let results = par_accumulate(accumulators, ctrl_params)?;
+        for (index, transitions) in results.into_iter().enumerate() {
+            sink(chunk_index * chunk_size + index, transitions);
+        }
+    }
+
+    Ok(())
+}
+
+/// Accumulates transitions in parallel from pairs of accumulators and their control parameters.
+///
+/// This is the generic form of `par_accumulate` for collections that are not slices, e.g.
+/// `machines.par_iter_mut().zip(params.par_iter().map(Vec::as_slice))`. The results are in the
+/// order of the iterator.
+#[cfg(feature = "std")]
+pub fn par_accumulate_iter<'a, A, I>(pairs: I) -> Result<Vec<Vec<Transition>>>
+where
+    A: Accumulate + Send + 'a,
+    I: IndexedParallelIterator<Item = (&'a mut A, &'a [f64])>,
+{
+    pairs
+        .map_init(rand::thread_rng, |rng, (accumulator, ctrl_params)| {
+            Ok(accumulator.accumulate(ctrl_params, rng)?.to_vec())
+        })
+        .collect::<Result<Vec<Vec<Transition>>>>()
+}
+
+/// Summary statistics of the transitions of an ensemble of state machines.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EnsembleStats {
+    /// The number of transitions of all machines.
+    pub total_transitions: usize,
+    /// The sum over all machines of the time of their last transition.
+    pub total_time: Time,
+    /// The number of times any machine entered each state.
+    pub visit_counts: Vec<usize>,
+}
+
+#[cfg(feature = "std")]
+impl EnsembleStats {
+    /// Computes the statistics of the transitions of a single machine.
+    fn from_transitions(transitions: &[Transition], num_states: usize) -> Result<Self> {
+        let mut visit_counts = vec![0; num_states];
+        for transition in transitions {
+            let state = transition.to;
+            *visit_counts
+                .get_mut(state)
+                .ok_or(StateMachineError::StateOutOfRange { state, num_states })? += 1;
+        }
+
+        Ok(EnsembleStats {
+            total_transitions: transitions.len(),
+            total_time: transitions.last().map_or(0.0, |transition| transition.time),
+            visit_counts,
+        })
+    }
+
+    /// Adds the statistics of `other` to these ones.
+    fn merge(mut self, other: EnsembleStats) -> Self {
+        self.total_transitions += other.total_transitions;
+        self.total_time += other.total_time;
+        for (total, count) in self.visit_counts.iter_mut().zip(other.visit_counts) {
+            *total += count;
+        }
+
+        self
+    }
+}
+
+/// Accumulates transitions in parallel like `par_accumulate` and also returns their summary
+/// statistics.
+///
+/// The statistics of each machine are computed by the thread that runs it, which is cheaper than
+/// a separate pass over the results. An error is returned if a machine enters a state that is
+/// greater than or equal to `num_states`.
+#[cfg(feature = "std")]
+pub fn par_accumulate_stats<A: Accumulate + Send>(
+    accumulators: &mut [A],
+    ctrl_params: &[&[f64]],
+    num_states: usize,
+) -> Result<(Vec<Vec<Transition>>, EnsembleStats)> {
+    if accumulators.len() != ctrl_params.len() {
+        return Err(StateMachineError::NumElems {
+            actual: ctrl_params.len(),
+            expected: accumulators.len(),
+        });
+    };
+
+    let (results, stats): (Vec<Vec<Transition>>, Vec<EnsembleStats>) = (accumulators, ctrl_params)
+        .into_par_iter()
+        .map_init(rand::thread_rng, |rng, item| {
+            let transitions = item.0.accumulate(item.1, rng)?;
+            let stats = EnsembleStats::from_transitions(transitions, num_states)?;
+            Ok((transitions.to_vec(), stats))
+        })
+        .collect::<Result<Vec<(Vec<Transition>, EnsembleStats)>>>()?
+        .into_iter()
+        .unzip();
+
+    let empty = EnsembleStats {
+        visit_counts: vec![0; num_states],
+        ..Default::default()
+    };
+    let stats = stats.into_iter().fold(empty, EnsembleStats::merge);
+
+    Ok((results, stats))
+}
+
+/// Returns the random number generator of the machine at `index` in a seeded ensemble.
+///
+/// Every machine draws from its own stream of a generator seeded with `seed`, so the random
+/// numbers of a machine depend only on the seed and its index, not on the thread it runs on.
+#[cfg(feature = "std")]
+pub(crate) fn machine_rng(seed: u64, index: usize) -> ChaCha12Rng {
+    let mut rng = ChaCha12Rng::seed_from_u64(seed);
+    rng.set_stream(index as u64);
+
+    rng
+}
+
+/// Accumulates transitions from a collection of state machines in parallel with reproducible
+/// results.
+///
+/// Each machine uses its own random number generator derived from `seed` and its index, not from
+/// the thread that runs it. The results are therefore bitwise identical from one call to the next
+/// regardless of the number of threads in the Rayon pool.
+#[cfg(feature = "std")]
+pub fn par_accumulate_seeded<A: Accumulate + Send>(
+    accumulators: &mut [A],
+    ctrl_params: &[&[f64]],
+    seed: u64,
+) -> Result<Vec<Vec<Transition>>> {
+    if accumulators.len() != ctrl_params.len() {
+        return Err(StateMachineError::NumElems {
+            actual: ctrl_params.len(),
+            expected: accumulators.len(),
+        });
+    };
+
     (accumulators, ctrl_params)
         .into_par_iter()
-        .map_init(
-            || rand::thread_rng(),
-            |rng, item| Ok(item.0.accumulate(*item.1, rng)?.to_vec()),
-        )
+        .enumerate()
+        .map(|(index, item)| {
+            let mut rng = machine_rng(seed, index);
+            Ok(item.0.accumulate(item.1, &mut rng)?.to_vec())
+        })
+        .collect::<Result<Vec<Vec<Transition>>>>()
+}
+
+/// Accumulates transitions from pairs of state machines in parallel with antithetic variates.
+///
+/// Machines `2i` and `2i + 1` form a pair: the first is driven by the same generator as machine
+/// `i` of `par_accumulate_seeded`, and the second by its complement, an `AntitheticRng`. If the
+/// machines of a pair are identical and sample their waiting times by inversion, e.g. with
+/// `ExponentialByInversion`, their trajectories are negatively correlated, which reduces the
+/// variance of estimates of means over the ensemble at equal numbers of machines. A trailing
+/// unpaired machine runs with an ordinary generator.
+#[cfg(feature = "std")]
+pub fn par_accumulate_antithetic<A: Accumulate + Send>(
+    accumulators: &mut [A],
+    ctrl_params: &[&[f64]],
+    seed: u64,
+) -> Result<Vec<Vec<Transition>>> {
+    if accumulators.len() != ctrl_params.len() {
+        return Err(StateMachineError::NumElems {
+            actual: ctrl_params.len(),
+            expected: accumulators.len(),
+        });
+    };
+
+    (accumulators, ctrl_params)
+        .into_par_iter()
+        .enumerate()
+        .map(|(index, (accumulator, ctrl_params))| {
+            let mut rng = machine_rng(seed, index / 2);
+            let transitions = if index % 2 == 0 {
+                accumulator.accumulate(ctrl_params, &mut rng)?
+            } else {
+                accumulator.accumulate(ctrl_params, &mut AntitheticRng::new(rng))?
+            };
+
+            Ok(transitions.to_vec())
+        })
         .collect::<Result<Vec<Vec<Transition>>>>()
 }
 
+/// Runs `replicates` independent copies of a prototype accumulator for each set of control
+/// parameters in parallel with reproducible results.
+///
+/// The results are grouped as `[param_set][replicate]`. The copy with replicate `r` of parameter
+/// set `p` is seeded like the machine with index `p * replicates + r` in `par_accumulate_seeded`.
+#[cfg(feature = "std")]
+pub fn par_accumulate_replicates<A: Accumulate + Clone + Send>(
+    proto_accumulator: &A,
+    ctrl_params: &[&[f64]],
+    replicates: usize,
+    seed: u64,
+) -> Result<Vec<Vec<Vec<Transition>>>> {
+    let mut accumulators = vec![proto_accumulator.clone(); ctrl_params.len() * replicates];
+
+    let results = accumulators
+        .par_iter_mut()
+        .enumerate()
+        .map(|(index, accumulator)| {
+            let mut rng = machine_rng(seed, index);
+            Ok(accumulator
+                .accumulate(ctrl_params[index / replicates], &mut rng)?
+                .to_vec())
+        })
+        .collect::<Result<Vec<Vec<Transition>>>>()?;
+
+    let mut results = results.into_iter();
+    Ok(ctrl_params
+        .iter()
+        .map(|_| results.by_ref().take(replicates).collect())
+        .collect())
+}
+
+/// Draws the starting state of each machine of an ensemble from a distribution.
+///
+/// `distribution` holds the weight of each state; the weights need not sum to one. The
+/// assignments are deterministic for a given `seed`, so they can be inspected, saved, and reused,
+/// e.g. with `par_accumulate_from_states`.
+#[cfg(feature = "std")]
+pub fn assign_starting_states(
+    n_machines: usize,
+    distribution: &[f64],
+    seed: u64,
+) -> Result<Vec<State>> {
+    let states =
+        WeightedIndex::new(distribution).map_err(|_| StateMachineError::InvalidDistribution)?;
+    let mut rng = ChaCha12Rng::seed_from_u64(seed);
+
+    Ok((0..n_machines).map(|_| states.sample(&mut rng)).collect())
+}
+
+/// Draws the starting state of each machine of an ensemble from a distribution that depends on
+/// the control parameters of the machine.
+///
+/// `initial_distribution_from_params` returns the weights of the states for the control
+/// parameters of a machine, e.g. the thermal occupation at a temperature passed as a control
+/// parameter. Machine `i` uses `ctrl_params[i]`, the same control parameters as in
+/// `par_accumulate`. The weights are validated like in `assign_starting_states`, and the
+/// assignments are deterministic for a given `seed`.
+#[cfg(feature = "std")]
+pub fn assign_starting_states_from_params<F>(
+    ctrl_params: &[&[CtrlParam]],
+    initial_distribution_from_params: F,
+    seed: u64,
+) -> Result<Vec<State>>
+where
+    F: Fn(&[CtrlParam]) -> Vec<f64>,
+{
+    let mut rng = ChaCha12Rng::seed_from_u64(seed);
+
+    ctrl_params
+        .iter()
+        .map(|params| {
+            let states = WeightedIndex::new(initial_distribution_from_params(params))
+                .map_err(|_| StateMachineError::InvalidDistribution)?;
+            Ok(states.sample(&mut rng))
+        })
+        .collect()
+}
+
+/// Runs one state machine per starting state in parallel.
+///
+/// All machines share the same rate constants, cutoff time and control parameters. The results
+/// are aligned with `starts`, which makes it easy to compare, e.g., first-passage times from
+/// every state.
+#[cfg(feature = "std")]
+pub fn par_accumulate_from_states(
+    proto_matrix: &Array2D,
+    t_cutoff: Time,
+    starts: &[State],
+    ctrl_params: &[f64],
+    seed: u64,
+) -> Result<Vec<Vec<Transition>>> {
+    let num_states = proto_matrix.shape.0;
+    let mut accumulators = starts
+        .iter()
+        .map(|&state| {
+            if state >= num_states {
+                return Err(StateMachineError::StateOutOfRange { state, num_states });
+            }
+            StepUntil::new(Stepper::new(state, proto_matrix.clone()), t_cutoff)
+        })
+        .collect::<Result<Vec<StepUntil<Stepper>>>>()?;
+    let ctrl_params = vec![ctrl_params; starts.len()];
+
+    par_accumulate_seeded(&mut accumulators, &ctrl_params, seed)
+}
+
+/// Builds one accumulator per rate matrix for an ensemble whose machines differ in their rates.
+///
+/// Machine `i` starts in `starts[i]` and uses `matrices[i]`; all of them run for `t_cutoff`. The
+/// result can be passed to `par_accumulate` and its variants like any other ensemble.
+pub fn build_heterogeneous_ensemble(
+    matrices: Vec<Array2D>,
+    starts: Vec<State>,
+    t_cutoff: Time,
+) -> Result<Vec<StepUntil<Stepper>>> {
+    if starts.len() != matrices.len() {
+        return Err(StateMachineError::NumElems {
+            actual: starts.len(),
+            expected: matrices.len(),
+        });
+    }
+
+    matrices
+        .into_iter()
+        .zip(starts)
+        .map(|(matrix, state)| {
+            let num_states = matrix.shape.0;
+            if state >= num_states {
+                return Err(StateMachineError::StateOutOfRange { state, num_states });
+            }
+            StepUntil::new(Stepper::new(state, matrix), t_cutoff)
+        })
+        .collect()
+}
+
 pub mod accumulators;
-mod arrays;
+#[cfg(feature = "std")]
+pub mod analysis;
+pub mod arrays;
+pub mod holding_times;
+#[cfg(feature = "std")]
+pub mod io;
+pub mod rate_models;
+pub mod rngs;
 pub mod steppers;
 
+#[cfg(feature = "python")]
 mod python_module;
 
+#[cfg(test)]
 mod tests {
-    #[cfg(test)]
-    use super::par_accumulate;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha12Rng;
+
+    #[cfg(feature = "std")]
+    use super::{
+        assign_starting_states, assign_starting_states_from_params, par_accumulate,
+        par_accumulate_antithetic, par_accumulate_chunked, par_accumulate_from_states,
+        par_accumulate_iter, par_accumulate_replicates, par_accumulate_seeded,
+        par_accumulate_stats, simulate,
+    };
+    use super::{build_heterogeneous_ensemble, Accumulate, StateMachineError, Transition};
     use crate::accumulators::StepUntil;
     use crate::arrays::Array2D;
     use crate::steppers::Stepper;
-    use crate::Rate;
 
+    #[cfg(feature = "std")]
     #[test]
     fn par_accumulate_state_machines() {
         let n = 10;
@@ -137,7 +785,8 @@ mod tests {
         let ctrl_params = vec![1.0];
         let mut ctrl_params_per_machine: Vec<&[f64]> = Vec::with_capacity(n);
         for _ in 0..n {
-            accumulators.push(StepUntil::new(Stepper::new(0, rate_constants.clone()), 1.0));
+            accumulators
+                .push(StepUntil::new(Stepper::new(0, rate_constants.clone()), 1.0).unwrap());
             ctrl_params_per_machine.push(ctrl_params.as_slice());
         }
 
@@ -148,4 +797,452 @@ mod tests {
 
         assert_eq!(n, results.unwrap().len())
     }
+
+    #[test]
+    fn transition_display() {
+        let transition = Transition {
+            from: 0,
+            time: 1.234,
+            dwell: 1.234,
+            to: 2,
+        };
+
+        assert_eq!("[t=1.234] 0 -> 2", transition.to_string());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn par_accumulate_seeded_is_reproducible() {
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 1.0, -1.0],
+            shape: (2, 2),
+        };
+        let ctrl_params: Vec<&[f64]> = vec![&[]; 4];
+        let run = || {
+            let mut accumulators: Vec<StepUntil<Stepper>> = (0..4)
+                .map(|_| StepUntil::new(Stepper::new(0, rate_constants.clone()), 5.0).unwrap())
+                .collect();
+            par_accumulate_seeded(&mut accumulators, &ctrl_params, 42).unwrap()
+        };
+
+        let first = run();
+        let second = run();
+
+        assert!(first.iter().any(|transitions| !transitions.is_empty()));
+        for (a, b) in first.iter().zip(second.iter()) {
+            assert_eq!(a.len(), b.len());
+            for (a, b) in a.iter().zip(b.iter()) {
+                assert_eq!((a.from(), a.time(), a.to()), (b.from(), b.time(), b.to()));
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn par_accumulate_seeded_is_independent_of_thread_count() {
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 2.0, 0.5, -1.0, 1.0, 3.0, 1.0, -1.0],
+            shape: (3, 3),
+        };
+        let ctrl_params: Vec<&[f64]> = vec![&[]; 16];
+        let run = |num_threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(num_threads)
+                .build()
+                .unwrap();
+            pool.install(|| {
+                let mut accumulators: Vec<StepUntil<Stepper>> = (0..16)
+                    .map(|_| StepUntil::new(Stepper::new(0, rate_constants.clone()), 10.0).unwrap())
+                    .collect();
+                par_accumulate_seeded(&mut accumulators, &ctrl_params, 7).unwrap()
+            })
+        };
+
+        let single = run(1);
+        let multi = run(4);
+
+        assert_eq!(16, single.len());
+        assert_eq!(single.len(), multi.len());
+        for (a, b) in single.iter().zip(multi.iter()) {
+            assert_eq!(a.len(), b.len());
+            for (a, b) in a.iter().zip(b.iter()) {
+                assert_eq!(
+                    (a.from(), a.time().to_bits(), a.to()),
+                    (b.from(), b.time().to_bits(), b.to())
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn par_accumulate_iter_from_custom_collection() {
+        use rayon::prelude::*;
+
+        /// A collection that keeps each machine together with its own control parameters.
+        struct Ensemble {
+            machines: Vec<StepUntil<Stepper>>,
+            params: Vec<Vec<f64>>,
+        }
+
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 1.0, -1.0],
+            shape: (2, 2),
+        };
+        let mut ensemble = Ensemble {
+            machines: (0..6)
+                .map(|_| StepUntil::new(Stepper::new(0, rate_constants.clone()), 5.0).unwrap())
+                .collect(),
+            params: vec![Vec::new(); 6],
+        };
+
+        let results = par_accumulate_iter(
+            ensemble
+                .machines
+                .par_iter_mut()
+                .zip(ensemble.params.par_iter().map(Vec::as_slice)),
+        )
+        .unwrap();
+
+        assert_eq!(6, results.len());
+        for transitions in results {
+            assert!(transitions.iter().all(|t| t.time() <= 5.0));
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn par_accumulate_stats_match_results() {
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 2.0, 0.5, -1.0, 1.0, 3.0, 1.0, -1.0],
+            shape: (3, 3),
+        };
+        let mut accumulators: Vec<StepUntil<Stepper>> = (0..8)
+            .map(|_| StepUntil::new(Stepper::new(0, rate_constants.clone()), 10.0).unwrap())
+            .collect();
+        let ctrl_params: Vec<&[f64]> = vec![&[]; 8];
+
+        let (results, stats) = par_accumulate_stats(&mut accumulators, &ctrl_params, 3).unwrap();
+
+        let mut visit_counts = vec![0; 3];
+        for transition in results.iter().flatten() {
+            visit_counts[transition.to()] += 1;
+        }
+        let total_time: f64 = results
+            .iter()
+            .map(|transitions| transitions.last().map_or(0.0, |t| t.time()))
+            .sum();
+        assert_eq!(8, results.len());
+        assert_eq!(
+            results.iter().map(Vec::len).sum::<usize>(),
+            stats.total_transitions
+        );
+        assert!((total_time - stats.total_time).abs() < 1e-9);
+        assert_eq!(visit_counts, stats.visit_counts);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn par_accumulate_stats_rejects_out_of_range_states() {
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 1.0, -1.0],
+            shape: (2, 2),
+        };
+        let mut accumulators = vec![StepUntil::new(Stepper::new(0, rate_constants), 10.0).unwrap()];
+
+        let result = par_accumulate_stats(&mut accumulators, &[&[]], 1);
+
+        assert!(matches!(
+            result,
+            Err(StateMachineError::StateOutOfRange { num_states: 1, .. })
+        ));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn par_accumulate_antithetic_reduces_variance() {
+        use crate::holding_times::ExponentialByInversion;
+
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, 1.0, -1.0], (2, 2)).unwrap();
+        let num_machines = 200;
+        let ctrl_params: Vec<&[f64]> = vec![&[]; num_machines];
+        // Estimates the mean time of the first transition
+        let estimate = |results: Vec<Vec<Transition>>| {
+            results
+                .iter()
+                .map(|transitions| transitions.first().map_or(5.0, |t| t.time()))
+                .sum::<f64>()
+                / num_machines as f64
+        };
+        let variance = |estimates: &[f64]| {
+            let mean = estimates.iter().sum::<f64>() / estimates.len() as f64;
+            estimates.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / estimates.len() as f64
+        };
+
+        let new_accumulators = || -> Vec<_> {
+            (0..num_machines)
+                .map(|_| {
+                    let stepper = Stepper::with_holding_time(
+                        0,
+                        rate_constants.clone(),
+                        ExponentialByInversion,
+                    );
+                    StepUntil::new(stepper, 5.0).unwrap()
+                })
+                .collect()
+        };
+
+        let mut independent = Vec::new();
+        let mut antithetic = Vec::new();
+        for seed in 0..100 {
+            independent.push(estimate(
+                par_accumulate_seeded(&mut new_accumulators(), &ctrl_params, seed).unwrap(),
+            ));
+            antithetic.push(estimate(
+                par_accumulate_antithetic(&mut new_accumulators(), &ctrl_params, seed).unwrap(),
+            ));
+        }
+
+        assert!(variance(&antithetic) < 0.7 * variance(&independent));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn par_accumulate_chunked_calls_sink_once_per_machine() {
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, 1.0, -1.0], (2, 2)).unwrap();
+        let mut accumulators: Vec<_> = (0..10)
+            .map(|_| StepUntil::new(Stepper::new(0, rate_constants.clone()), 5.0).unwrap())
+            .collect();
+        let ctrl_params: Vec<&[f64]> = vec![&[]; 10];
+
+        let mut indices = Vec::new();
+        par_accumulate_chunked(&mut accumulators, &ctrl_params, 3, |index, transitions| {
+            assert!(transitions.iter().all(|t| t.time() <= 5.0));
+            indices.push(index);
+        })
+        .unwrap();
+
+        assert_eq!((0..10).collect::<Vec<_>>(), indices);
+        assert!(matches!(
+            par_accumulate_chunked(&mut accumulators, &ctrl_params[..9], 3, |_, _| {}),
+            Err(StateMachineError::NumElems {
+                actual: 9,
+                expected: 10
+            })
+        ));
+        assert!(matches!(
+            par_accumulate_chunked(&mut accumulators, &ctrl_params, 0, |_, _| {}),
+            Err(StateMachineError::InvalidChunkSize)
+        ));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn par_accumulate_replicates_groups_results() {
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, 1.0, -1.0], (2, 2)).unwrap();
+        let proto = StepUntil::new(Stepper::new(0, rate_constants), 10.0).unwrap();
+        let ctrl_params: [&[f64]; 2] = [&[], &[]];
+
+        let results = par_accumulate_replicates(&proto, &ctrl_params, 3, 11).unwrap();
+
+        assert_eq!(2, results.len());
+        assert!(results.iter().all(|replicates| replicates.len() == 3));
+        // The replicates are independent
+        assert_ne!(results[0][0][0].time, results[0][1][0].time);
+        assert_ne!(results[0][0][0].time, results[1][0][0].time);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn simulate_two_state_machine() {
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, 2.0, -1.0], (2, 2)).unwrap();
+
+        let transitions = simulate(rate_constants.clone(), 0, 10.0, 5).unwrap();
+
+        assert!(!transitions.is_empty());
+        assert_eq!(0, transitions[0].from);
+        assert!(transitions.iter().all(|t| t.time <= 10.0));
+        assert!(transitions.windows(2).all(|w| w[0].to == w[1].from));
+        let times =
+            |transitions: &[Transition]| transitions.iter().map(|t| t.time).collect::<Vec<_>>();
+        assert_eq!(
+            times(&transitions),
+            times(&simulate(rate_constants, 0, 10.0, 5).unwrap())
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn simulate_stops_on_absorption() {
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, -1.0, -1.0], (2, 2)).unwrap();
+
+        let transitions = simulate(rate_constants, 0, f64::INFINITY, 5).unwrap();
+
+        assert_eq!(1, transitions.len());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn assign_starting_states_follows_distribution() {
+        let n = 100_000;
+        let distribution = [0.2, 0.5, 0.3];
+
+        let states = assign_starting_states(n, &distribution, 17).unwrap();
+
+        assert_eq!(n, states.len());
+        assert_eq!(
+            states,
+            assign_starting_states(n, &distribution, 17).unwrap()
+        );
+        for (state, p) in distribution.iter().enumerate() {
+            let frequency = states.iter().filter(|s| **s == state).count() as f64 / n as f64;
+            assert!((frequency - p).abs() < 0.01);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn assign_starting_states_rejects_invalid_distributions() {
+        for distribution in [&[][..], &[0.0, 0.0][..], &[1.0, -1.0][..], &[f64::NAN][..]] {
+            assert!(matches!(
+                assign_starting_states(10, distribution, 0),
+                Err(StateMachineError::InvalidDistribution)
+            ));
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn assign_starting_states_from_params_follows_temperature() {
+        // Thermal occupation of a ground and an excited state with an energy gap of 1, where the
+        // only control parameter is the temperature
+        let boltzmann = |params: &[f64]| vec![1.0, (-1.0 / params[0]).exp()];
+        let excited_fraction = |temperature: f64| {
+            let temperature = [temperature];
+            let ctrl_params = vec![temperature.as_slice(); 100_000];
+            let states = assign_starting_states_from_params(&ctrl_params, boltzmann, 5).unwrap();
+            states.iter().filter(|state| **state == 1).count() as f64 / states.len() as f64
+        };
+
+        let cold = excited_fraction(0.5);
+        let hot = excited_fraction(5.0);
+
+        let expected = |temperature: f64| {
+            let weight = (-1.0 / temperature).exp();
+            weight / (1.0 + weight)
+        };
+        assert!((cold - expected(0.5)).abs() < 0.01);
+        assert!((hot - expected(5.0)).abs() < 0.01);
+        assert!(hot > cold);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn assign_starting_states_from_params_rejects_invalid_distributions() {
+        let result = assign_starting_states_from_params(
+            &[&[1.0], &[-1.0]],
+            |params| vec![1.0, params[0]],
+            0,
+        );
+
+        assert!(matches!(
+            result,
+            Err(StateMachineError::InvalidDistribution)
+        ));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn par_accumulate_from_every_state() {
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, -1.0, -1.0, -1.0, 1.0, 1.0, -1.0, -1.0],
+            shape: (3, 3),
+        };
+        let starts = [0, 1, 2];
+
+        let results = par_accumulate_from_states(&rate_constants, 10.0, &starts, &[], 7).unwrap();
+
+        assert_eq!(starts.len(), results.len());
+        for (start, transitions) in starts.iter().zip(results.iter()) {
+            if let Some(first) = transitions.first() {
+                assert_eq!(*start, first.from());
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn par_accumulate_from_states_out_of_range() {
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 1.0, -1.0],
+            shape: (2, 2),
+        };
+
+        let result = par_accumulate_from_states(&rate_constants, 1.0, &[0, 2], &[], 7);
+
+        assert!(result.is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn build_heterogeneous_ensemble_uses_each_matrix() {
+        let slow = Array2D::new(vec![-1.0, 1.0, 1.0, -1.0], (2, 2)).unwrap();
+        let fast = Array2D::new(vec![-1.0, 20.0, 20.0, -1.0], (2, 2)).unwrap();
+        let mut accumulators =
+            build_heterogeneous_ensemble(vec![slow, fast], vec![0, 1], 100.0).unwrap();
+        let ctrl_params: Vec<&[f64]> = vec![&[], &[]];
+
+        let results = par_accumulate_seeded(&mut accumulators, &ctrl_params, 3).unwrap();
+
+        assert_eq!(0, results[0][0].from());
+        assert_eq!(1, results[1][0].from());
+        // The mean waiting times are 1 and 0.05, so about 100 and 2000 transitions
+        assert!(results[0].len() < 200);
+        assert!(results[1].len() > 1500);
+    }
+
+    #[test]
+    fn build_heterogeneous_ensemble_rejects_invalid_inputs() {
+        let matrix = Array2D::new(alloc::vec![-1.0, 1.0, 1.0, -1.0], (2, 2)).unwrap();
+
+        assert!(matches!(
+            build_heterogeneous_ensemble(alloc::vec![matrix.clone()], alloc::vec![0, 1], 1.0),
+            Err(StateMachineError::NumElems {
+                actual: 2,
+                expected: 1
+            })
+        ));
+        assert!(matches!(
+            build_heterogeneous_ensemble(alloc::vec![matrix], alloc::vec![2], 1.0),
+            Err(StateMachineError::StateOutOfRange {
+                state: 2,
+                num_states: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn core_steps_without_std() {
+        // Only uses items that are available when the crate is built without its default features
+        let mut rng = ChaCha12Rng::seed_from_u64(42);
+        let rate_constants = Array2D::new(alloc::vec![-1.0, 1.0, 1.0, -1.0], (2, 2)).unwrap();
+        let mut accumulator = StepUntil::new(Stepper::new(0, rate_constants), 10.0).unwrap();
+
+        let transitions = accumulator.accumulate(&[], &mut rng).unwrap();
+
+        assert!(!transitions.is_empty());
+        assert_eq!(0, transitions[0].from());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn par_accumulate_empty_inputs() {
+        let mut accumulators: Vec<StepUntil<Stepper>> = Vec::new();
+
+        let results = par_accumulate(&mut accumulators, &[]).unwrap();
+        let seeded_results = par_accumulate_seeded(&mut accumulators, &[], 0).unwrap();
+
+        assert!(results.is_empty());
+        assert!(seeded_results.is_empty());
+    }
 }
@@ -1,20 +1,27 @@
 use std::ops::DerefMut;
 
-use numpy::{PyReadonlyArray1, PyReadonlyArray2, PyReadonlyArray4};
+use numpy::{PyArray1, PyReadonlyArray1, PyReadonlyArray2, PyReadonlyArray4};
 use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
-use rand::prelude::*;
+use pyo3::types::PyDict;
 use rayon::prelude::*;
 
 use crate::accumulators::StepUntil;
-use crate::arrays::Array2D;
+use crate::arrays::{Array2D, Array4D};
 use crate::steppers::Stepper;
-use crate::{Accumulate, StateMachineError, Transition};
+use crate::{machine_rng, Accumulate, StateMachineError, Transition};
 use crate::{Rate, State, Step, Time};
 
+/// The default number of steps between checks for Python signals, e.g. KeyboardInterrupt.
+const SIGNAL_CHECK_STRIDE: usize = 10_000;
+
 #[pyclass(name = "StateMachine")]
 pub struct PyStateMachine {
     accumulator: StepUntil<Stepper>,
+
+    /// The number of steps between checks for Python signals in `accumulate`; 0 disables them.
+    #[pyo3(get, set)]
+    signal_check_stride: usize,
 }
 
 #[pymethods]
@@ -24,6 +31,7 @@ impl PyStateMachine {
         starting_state: State,
         rate_constants: PyReadonlyArray2<Rate>,
         rate_coefficients: Option<PyReadonlyArray4<Rate>>,
+        labels: Option<Vec<String>>,
     ) -> PyResult<Self> {
         if rate_constants.shape()[0] != rate_constants.shape()[1] {
             return Err(PyValueError::new_err(
@@ -31,7 +39,7 @@ impl PyStateMachine {
             ));
         };
 
-        if let Some(rate_coefficients) = rate_coefficients {
+        if let Some(rate_coefficients) = &rate_coefficients {
             if (rate_coefficients.shape()[2] != rate_coefficients.shape()[3])
                 || (rate_coefficients.shape()[2] != rate_constants.shape()[0])
                 || (rate_coefficients.shape()[3] != rate_constants.shape()[0])
@@ -42,7 +50,7 @@ impl PyStateMachine {
             };
         }
 
-        if starting_state > rate_constants.shape()[0] {
+        if starting_state >= rate_constants.shape()[0] {
             return Err(PyValueError::new_err("starting_state must be between 0 and the N - 1, where N x N is the shape of rate_constants"));
         };
 
@@ -53,10 +61,28 @@ impl PyStateMachine {
             rcs.push(*elem);
         }
 
-        let stepper = Stepper::new(0, Array2D { data: rcs, shape });
-        let accumulator = StepUntil::new(stepper, 1.0);
+        let mut stepper = Stepper::new(starting_state, Array2D { data: rcs, shape });
+        if let Some(rate_coefficients) = rate_coefficients {
+            // Iterating over the array view yields the elements in logical (row-major) order
+            // regardless of the memory layout of the numpy array
+            let rate_coefficients = rate_coefficients.as_array();
+            let shape = rate_coefficients.dim();
+            let data: Vec<Rate> = rate_coefficients.iter().copied().collect();
+            let rate_coefficients = Array4D::new(data, shape).map_err(|_| {
+                PyValueError::new_err("rate_coefficients has an inconsistent shape")
+            })?;
+
+            stepper = stepper.with_rate_coefficients(rate_coefficients)?;
+        }
+        if let Some(labels) = labels {
+            stepper = stepper.with_labels(labels)?;
+        }
+        let accumulator = StepUntil::new(stepper, 1.0)?;
 
-        Ok(PyStateMachine { accumulator })
+        Ok(PyStateMachine {
+            accumulator,
+            signal_check_stride: SIGNAL_CHECK_STRIDE,
+        })
     }
 
     #[getter]
@@ -64,9 +90,77 @@ impl PyStateMachine {
         Ok(self.accumulator.stepper().current_state())
     }
 
-    fn accumulate(&mut self, ctrl_params: PyReadonlyArray1<f64>) -> PyResult<Vec<PyTransition>> {
-        let ctrl_params = ctrl_params.as_slice()?;
-        self.base_accumulate(ctrl_params)
+    #[getter]
+    fn stopped(&self) -> bool {
+        self.accumulator.stepper().is_stopped()
+    }
+
+    #[getter]
+    fn has_rate_coefficients(&self) -> bool {
+        self.accumulator.stepper().has_rate_coefficients()
+    }
+
+    #[getter]
+    fn labels(&self) -> Option<Vec<String>> {
+        self.accumulator.stepper().labels().map(<[String]>::to_vec)
+    }
+
+    fn label_of(&self, state: State) -> Option<String> {
+        self.accumulator.stepper().label_of(state).map(String::from)
+    }
+
+    fn accumulate(
+        &mut self,
+        py: Python<'_>,
+        ctrl_params: PyReadonlyArray1<f64>,
+    ) -> PyResult<Vec<PyTransition>> {
+        let transitions = self.accumulate_checking_signals(py, ctrl_params.as_slice()?)?;
+
+        Ok(transitions
+            .iter()
+            .cloned()
+            .map(PyTransition::from)
+            .collect())
+    }
+
+    /// Accumulates transitions like `accumulate`, but returns them as a numpy structured array
+    /// with the fields `from` (u4), `time` (f8) and `to` (u4).
+    ///
+    /// The result can be passed directly to `pandas.DataFrame` to obtain typed columns.
+    fn accumulate_records<'py>(
+        &mut self,
+        py: Python<'py>,
+        ctrl_params: PyReadonlyArray1<f64>,
+    ) -> PyResult<&'py PyAny> {
+        let transitions = self.accumulate_checking_signals(py, ctrl_params.as_slice()?)?;
+
+        let to_u32 = |state: State| {
+            u32::try_from(state)
+                .map_err(|_| PyValueError::new_err(format!("state {state} does not fit in a u4")))
+        };
+        let from = transitions
+            .iter()
+            .map(|transition| to_u32(transition.from))
+            .collect::<PyResult<Vec<u32>>>()?;
+        let time: Vec<Time> = transitions
+            .iter()
+            .map(|transition| transition.time)
+            .collect();
+        let to = transitions
+            .iter()
+            .map(|transition| to_u32(transition.to))
+            .collect::<PyResult<Vec<u32>>>()?;
+
+        let dtype = vec![("from", "u4"), ("time", "f8"), ("to", "u4")];
+        let records = py
+            .import("numpy")?
+            .getattr("zeros")?
+            .call1((transitions.len(), dtype))?;
+        records.set_item("from", PyArray1::from_vec(py, from))?;
+        records.set_item("time", PyArray1::from_vec(py, time))?;
+        records.set_item("to", PyArray1::from_vec(py, to))?;
+
+        Ok(records)
     }
 
     fn step(&mut self, ctrl_params: PyReadonlyArray1<f64>) -> PyResult<PyTransition> {
@@ -81,21 +175,57 @@ impl PyStateMachine {
 }
 
 impl PyStateMachine {
+    /// Accumulates transitions while periodically checking for Python signals.
+    ///
+    /// A pending signal, e.g. KeyboardInterrupt, stops the accumulation and its error is returned.
+    fn accumulate_checking_signals(
+        &mut self,
+        py: Python<'_>,
+        ctrl_params: &[f64],
+    ) -> PyResult<&[Transition]> {
+        let mut rng = rand::thread_rng();
+
+        // Signal handlers such as the one for KeyboardInterrupt only run when Python code checks
+        // for signals, so check periodically and keep the error to re-raise it
+        let mut signal: Option<PyErr> = None;
+        let result = self.accumulator.accumulate_interruptible(
+            ctrl_params,
+            &mut rng,
+            self.signal_check_stride,
+            || match py.check_signals() {
+                Ok(()) => true,
+                Err(err) => {
+                    signal = Some(err);
+                    false
+                }
+            },
+        );
+
+        match (result, signal) {
+            (_, Some(err)) => Err(err),
+            (result, None) => Ok(result?),
+        }
+    }
+
     /// Runs the accumulate method state machine and collects all the transitions that occur.
     ///
     /// Arguments to this function may be sent to other threads.
     ///
     /// # Arguments
     /// - ctrl_params: The control parameters that determine the state machine's transition rates.
+    /// - rng: The random number generator of the state machine.
     ///
-    fn base_accumulate(&mut self, ctrl_params: &[f64]) -> Result<Vec<PyTransition>, PyErr> {
-        let mut rng = rand::thread_rng();
+    fn base_accumulate<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[f64],
+        rng: &mut R,
+    ) -> Result<Vec<PyTransition>, PyErr> {
         let transitions: Vec<PyTransition> = self
             .accumulator
-            .accumulate(ctrl_params, &mut rng)?
+            .accumulate(ctrl_params, rng)?
             .to_vec()
             .into_iter()
-            .map(|item| PyTransition::from(item))
+            .map(PyTransition::from)
             .collect();
 
         Ok(transitions)
@@ -111,6 +241,9 @@ pub struct PyTransition {
     #[pyo3(get)]
     time: Time,
 
+    #[pyo3(get)]
+    dwell: Time,
+
     #[pyo3(get)]
     to_state: State,
 }
@@ -120,16 +253,40 @@ impl From<Transition> for PyTransition {
         PyTransition {
             from_state: item.from,
             time: item.time,
+            dwell: item.dwell,
             to_state: item.to,
         }
     }
 }
 
+/// Accumulates the transitions of several state machines in parallel.
+///
+/// If `seeds` is given, each machine draws its random numbers from a generator seeded with its
+/// own seed, so the results are reproducible. If `seed` is given instead, the generator of each
+/// machine is derived from `seed` and the index of the machine, like in `par_accumulate_seeded`.
+/// Otherwise, the generators are seeded randomly.
 #[pyfunction]
 pub fn par_accumulate(
     machines: Vec<&PyCell<PyStateMachine>>,
     ctrl_params: Vec<PyReadonlyArray1<f64>>,
+    seeds: Option<Vec<u64>>,
+    seed: Option<u64>,
 ) -> PyResult<Vec<Vec<PyTransition>>> {
+    if seeds.is_some() && seed.is_some() {
+        return Err(PyValueError::new_err(
+            "seeds and seed are mutually exclusive",
+        ));
+    }
+    if let Some(seeds) = &seeds {
+        if seeds.len() != machines.len() {
+            return Err(PyValueError::new_err(format!(
+                "expected one seed per machine, i.e. {} seed(s), but received {}",
+                machines.len(),
+                seeds.len()
+            )));
+        }
+    }
+
     let ctrl_params: Vec<&[f64]> = ctrl_params
         .iter()
         .map(|item| item.as_slice())
@@ -147,10 +304,49 @@ pub fn par_accumulate(
         .map(|refr| refr.deref_mut())
         .collect::<Vec<&mut PyStateMachine>>();
 
-    Ok((machines.as_mut_slice(), ctrl_params.as_slice())
-        .into_par_iter()
-        .map(|item| item.0.base_accumulate(*item.1))
-        .collect::<Result<Vec<Vec<PyTransition>>, _>>()?)
+    match (seeds, seed) {
+        (Some(seeds), _) => (
+            machines.as_mut_slice(),
+            ctrl_params.as_slice(),
+            seeds.as_slice(),
+        )
+            .into_par_iter()
+            .map(|item| item.0.base_accumulate(item.1, &mut machine_rng(*item.2, 0)))
+            .collect::<Result<Vec<Vec<PyTransition>>, _>>(),
+        (None, Some(seed)) => (machines.as_mut_slice(), ctrl_params.as_slice())
+            .into_par_iter()
+            .enumerate()
+            .map(|(index, item)| {
+                item.0
+                    .base_accumulate(item.1, &mut machine_rng(seed, index))
+            })
+            .collect::<Result<Vec<Vec<PyTransition>>, _>>(),
+        (None, None) => (machines.as_mut_slice(), ctrl_params.as_slice())
+            .into_par_iter()
+            .map_init(rand::thread_rng, |rng, item| {
+                item.0.base_accumulate(item.1, rng)
+            })
+            .collect::<Result<Vec<Vec<PyTransition>>, _>>(),
+    }
+}
+
+/// Returns the version of the library.
+#[pyfunction]
+pub fn version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+/// Returns details about how the library was built, e.g. for bug reports.
+///
+/// The keys are `version`, the version of the library, and `num_threads`, the number of threads
+/// on which `par_accumulate` runs the machines.
+#[pyfunction]
+pub fn build_info(py: Python<'_>) -> PyResult<&PyDict> {
+    let info = PyDict::new(py);
+    info.set_item("version", version())?;
+    info.set_item("num_threads", rayon::current_num_threads())?;
+
+    Ok(info)
 }
 
 impl From<StateMachineError> for PyErr {
@@ -160,8 +356,40 @@ impl From<StateMachineError> for PyErr {
                 actual: _,
                 expected: _,
             } => PyValueError::new_err(err.to_string()),
+            StateMachineError::BrokenChain {
+                index: _,
+                from: _,
+                previous_to: _,
+            } => PyValueError::new_err(err.to_string()),
+            StateMachineError::InvalidChunkSize => PyValueError::new_err(err.to_string()),
+            StateMachineError::InvalidCutoff(_) => PyValueError::new_err(err.to_string()),
+            StateMachineError::InvalidDistribution => PyValueError::new_err(err.to_string()),
+            StateMachineError::InvalidEvents => PyValueError::new_err(err.to_string()),
+            StateMachineError::InvalidMinDwellTime(_) => PyValueError::new_err(err.to_string()),
+            StateMachineError::InvalidRateBound(_) => PyValueError::new_err(err.to_string()),
+            StateMachineError::InvalidSchedule => PyValueError::new_err(err.to_string()),
+            StateMachineError::InvalidStateSets => PyValueError::new_err(err.to_string()),
+            StateMachineError::InvalidStartTime(_) => PyValueError::new_err(err.to_string()),
+            StateMachineError::InvalidTimes => PyValueError::new_err(err.to_string()),
+            StateMachineError::InvalidWalkersPerBin => PyValueError::new_err(err.to_string()),
+            StateMachineError::Interrupted => PyValueError::new_err(err.to_string()),
+            StateMachineError::RateBoundExceeded { rate: _, bound: _ } => {
+                PyValueError::new_err(err.to_string())
+            }
+            StateMachineError::RateMismatch(_) => PyValueError::new_err(err.to_string()),
             StateMachineError::RngError(_) => PyValueError::new_err(err.to_string()),
+            StateMachineError::SingularMatrix => PyValueError::new_err(err.to_string()),
+            StateMachineError::StateOutOfRange {
+                state: _,
+                num_states: _,
+            } => PyValueError::new_err(err.to_string()),
             StateMachineError::Stopped => PyValueError::new_err(err.to_string()),
+            StateMachineError::TimeInversion {
+                index: _,
+                time: _,
+                previous: _,
+            } => PyValueError::new_err(err.to_string()),
+            StateMachineError::WaitingTimeUnderflow(_, _) => PyValueError::new_err(err.to_string()),
         }
     }
 }
@@ -173,5 +401,7 @@ fn python_lib(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add_class::<PyStateMachine>()?;
     m.add_class::<PyTransition>()?;
     m.add_function(wrap_pyfunction!(par_accumulate, m)?)?;
+    m.add_function(wrap_pyfunction!(version, m)?)?;
+    m.add_function(wrap_pyfunction!(build_info, m)?)?;
     Ok(())
 }
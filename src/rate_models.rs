@@ -0,0 +1,92 @@
+//! Provides the rate laws that determine the rates of a state machine from its control
+//! parameters.
+use crate::arrays::{Array2D, Array4D};
+use crate::{CtrlParam, Rate, State};
+
+/// A law that determines the rates of the transitions out of each state of a state machine.
+///
+/// Implement this trait to express arbitrary dependencies of the rates on the control parameters,
+/// e.g. the Arrhenius or Hill equations, and pass the model to `Stepper::with_rate_model`. As for
+/// rate constants, non-positive rates denote the absence of a transition.
+pub trait RateModel {
+    /// Returns the number of states of the state machine.
+    fn num_states(&self) -> usize;
+
+    /// Writes the rates of the transitions from `state` to every state into `out`, which has one
+    /// element per state.
+    fn rates(&self, state: State, ctrl_params: &[CtrlParam], out: &mut [Rate]);
+}
+
+/// Rates that do not depend on the control parameters.
+impl RateModel for Array2D {
+    fn num_states(&self) -> usize {
+        self.shape.0
+    }
+
+    fn rates(&self, state: State, _ctrl_params: &[CtrlParam], out: &mut [Rate]) {
+        out.copy_from_slice(&self.data[(state * self.shape.1)..((state + 1) * self.shape.1)]);
+    }
+}
+
+/// Rates that are polynomials of the control parameters, i.e. the rate from state k to state l is
+/// sum_ij c_i^(j+1) * rate_coefficients[i, j, k, l].
+impl RateModel for Array4D {
+    fn num_states(&self) -> usize {
+        self.shape.2
+    }
+
+    fn rates(&self, state: State, ctrl_params: &[CtrlParam], out: &mut [Rate]) {
+        let (i4, j4, _, l4) = self.shape;
+        out.fill(0.0);
+        for (i, ctrl_param) in ctrl_params.iter().enumerate().take(i4) {
+            let mut power = *ctrl_param;
+            for j in 0..j4 {
+                let row = &self.matrix(i, j)[(state * l4)..((state + 1) * l4)];
+                for (rate, coefficient) in out.iter_mut().zip(row) {
+                    *rate += power * coefficient;
+                }
+                power *= ctrl_param;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateModel;
+    use crate::arrays::{power, tensordot, Array2D, Array4D};
+
+    #[test]
+    fn constant_rates() {
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, 2.0, -1.0], (2, 2)).unwrap();
+        let mut out = [0.0; 2];
+
+        rate_constants.rates(1, &[5.0], &mut out);
+
+        assert_eq!(2, RateModel::num_states(&rate_constants));
+        assert_eq!([2.0, -1.0], out);
+    }
+
+    #[test]
+    fn polynomial_rates_match_tensordot() {
+        let rate_coefficients = Array4D::new(
+            vec![
+                0.0, 0.0, 2.0, 0.0, 0.0, 2.0, 0.5, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0, 4.0, 0.0,
+                0.0, 2.0, 0.5, 0.0, 0.0, 2.0, 2.0, 0.0,
+            ],
+            (2, 3, 2, 2),
+        )
+        .unwrap();
+        let ctrl_params = [2.0, 3.0];
+        let expected = tensordot(&power(&ctrl_params, 3), &rate_coefficients);
+
+        for state in 0..2 {
+            let mut out = [0.0; 2];
+            rate_coefficients.rates(state, &ctrl_params, &mut out);
+
+            for (actual, expected) in out.iter().zip(expected.row(state).unwrap()) {
+                assert!((actual - expected).abs() < 1e-12);
+            }
+        }
+    }
+}
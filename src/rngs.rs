@@ -0,0 +1,235 @@
+//! Random number generators for recording and replaying the random draws of a simulation.
+//!
+//! Wrapping the generator passed to `Step::step` or `Accumulate::accumulate` in a `RecordingRng`
+//! records every random number that the run consumes. Passing a `ReplayRng` built from the
+//! recording to an identical machine reproduces the run exactly, e.g. to debug one anomalous
+//! trajectory out of a large ensemble.
+//!
+//! An `AntitheticRng` complements the output of another generator, which is used to run pairs of
+//! machines with antithetic variates.
+use alloc::vec::Vec;
+use core::num::NonZeroU32;
+
+use rand::{Error, RngCore};
+
+/// A random number generator that records the output of another one.
+///
+/// The output is recorded as a stream of bytes in the order in which it was drawn.
+pub struct RecordingRng<R: RngCore> {
+    inner: R,
+    recording: Vec<u8>,
+}
+
+impl<R: RngCore> RecordingRng<R> {
+    /// Creates a new generator that draws from `inner` and records its output.
+    pub fn new(inner: R) -> Self {
+        RecordingRng {
+            inner,
+            recording: Vec::new(),
+        }
+    }
+
+    /// Returns the bytes drawn so far.
+    pub fn recording(&self) -> &[u8] {
+        &self.recording
+    }
+
+    /// Consumes the generator and returns the bytes drawn from it.
+    pub fn into_recording(self) -> Vec<u8> {
+        self.recording
+    }
+}
+
+impl<R: RngCore> RngCore for RecordingRng<R> {
+    fn next_u32(&mut self) -> u32 {
+        let value = self.inner.next_u32();
+        self.recording.extend_from_slice(&value.to_le_bytes());
+
+        value
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let value = self.inner.next_u64();
+        self.recording.extend_from_slice(&value.to_le_bytes());
+
+        value
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.inner.fill_bytes(dest);
+        self.recording.extend_from_slice(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.inner.try_fill_bytes(dest)?;
+        self.recording.extend_from_slice(dest);
+
+        Ok(())
+    }
+}
+
+/// A random number generator that returns the bitwise complement of the output of another one.
+///
+/// Uniform random numbers u in [0, 1) drawn from it are the complements 1 - u - 2^-53 of the ones
+/// drawn from the inner generator, so two machines driven by a generator and its antithetic
+/// counterpart sample negatively correlated waiting times if these are monotonic in u, as with
+/// `ExponentialByInversion`.
+pub struct AntitheticRng<R: RngCore> {
+    inner: R,
+}
+
+impl<R: RngCore> AntitheticRng<R> {
+    /// Creates a new generator that complements the output of `inner`.
+    pub fn new(inner: R) -> Self {
+        AntitheticRng { inner }
+    }
+}
+
+impl<R: RngCore> RngCore for AntitheticRng<R> {
+    fn next_u32(&mut self) -> u32 {
+        !self.inner.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        !self.inner.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.inner.fill_bytes(dest);
+        dest.iter_mut().for_each(|byte| *byte = !*byte);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.inner.try_fill_bytes(dest)?;
+        dest.iter_mut().for_each(|byte| *byte = !*byte);
+
+        Ok(())
+    }
+}
+
+/// A random number generator that replays the output recorded by a `RecordingRng`.
+///
+/// The replay is only exact if the random numbers are requested in the same way as during the
+/// recording, i.e. by an identical machine starting from the same state. Drawing more numbers
+/// than were recorded panics, or returns an error from `try_fill_bytes`.
+pub struct ReplayRng {
+    recording: Vec<u8>,
+    position: usize,
+}
+
+impl ReplayRng {
+    /// Creates a new generator that replays `recording`.
+    pub fn new(recording: Vec<u8>) -> Self {
+        ReplayRng {
+            recording,
+            position: 0,
+        }
+    }
+
+    /// Returns the number of recorded bytes that have not been replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.recording.len() - self.position
+    }
+
+    /// Returns the next `n` recorded bytes, or `None` if fewer remain.
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        let bytes = self.recording.get(self.position..(self.position + n))?;
+        self.position += n;
+
+        Some(bytes)
+    }
+}
+
+impl RngCore for ReplayRng {
+    fn next_u32(&mut self) -> u32 {
+        let bytes = self.take(4).expect("the recording is exhausted");
+        u32::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let bytes = self.take(8).expect("the recording is exhausted");
+        u64::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.try_fill_bytes(dest)
+            .expect("the recording is exhausted")
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        let bytes = self
+            .take(dest.len())
+            .ok_or_else(|| Error::from(NonZeroU32::new(Error::CUSTOM_START).unwrap()))?;
+        dest.copy_from_slice(bytes);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::{AntitheticRng, RecordingRng, ReplayRng};
+    use crate::accumulators::StepUntil;
+    use crate::arrays::Array2D;
+    use crate::steppers::Stepper;
+    use crate::Accumulate;
+
+    fn accumulator() -> StepUntil<Stepper> {
+        let rate_constants =
+            Array2D::new(vec![-1.0, 1.0, 2.0, 0.5, -1.0, 1.0, 3.0, 1.0, -1.0], (3, 3)).unwrap();
+
+        StepUntil::new(Stepper::new(0, rate_constants), 10.0).unwrap()
+    }
+
+    #[test]
+    fn replay_reproduces_recorded_run() {
+        let mut recording_rng = RecordingRng::new(StdRng::seed_from_u64(1));
+        let mut recorded = accumulator();
+        let recorded = recorded
+            .accumulate(&[], &mut recording_rng)
+            .unwrap()
+            .to_vec();
+
+        let mut replay_rng = ReplayRng::new(recording_rng.into_recording());
+        let mut replayed = accumulator();
+        let replayed = replayed.accumulate(&[], &mut replay_rng).unwrap();
+
+        assert!(!recorded.is_empty());
+        assert_eq!(recorded.len(), replayed.len());
+        for (a, b) in recorded.iter().zip(replayed.iter()) {
+            assert_eq!(
+                (a.from(), a.time().to_bits(), a.to()),
+                (b.from(), b.time().to_bits(), b.to())
+            );
+        }
+        assert_eq!(0, replay_rng.remaining());
+    }
+
+    #[test]
+    fn antithetic_rng_complements_uniform_numbers() {
+        use rand::Rng;
+
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut antithetic = AntitheticRng::new(StdRng::seed_from_u64(2));
+
+        for _ in 0..100 {
+            let u: f64 = rng.gen();
+            let v: f64 = antithetic.gen();
+
+            assert!((u + v - 1.0).abs() < 1e-15);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "exhausted")]
+    fn replay_panics_when_exhausted() {
+        use rand::RngCore;
+
+        let mut rng = ReplayRng::new(vec![0; 4]);
+        rng.next_u32();
+        rng.next_u32();
+    }
+}
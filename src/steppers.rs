@@ -1,165 +1,2260 @@
 //! Provides concrete implementations of StateMachines that implement the Step trait.
-use rand::prelude::*;
-use rand_distr::Exp;
+use alloc::borrow::Cow;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 
-use crate::arrays::{power, tensordot, Array2D, Array4D};
-use crate::{CtrlParam, Result, State, StateMachineError, Step, Time, Transition};
+use ndarray::Array2;
 
-/// A memoryless state machine that steps to a new random state at random times.
-pub struct Stepper {
+use crate::arrays::{
+    power_into, tensordot_into, tensordot_sparse_into, Array2D, Array4D, SparseArray4D,
+};
+use crate::holding_times::{Exponential, HoldingTime};
+use crate::rate_models::RateModel;
+use crate::{CtrlParam, Float, Rate, Result, State, StateMachineError, Step, Time, Transition};
+
+/// A state machine that steps to a new random state at random times.
+///
+/// The waiting times before transitions are drawn from the distribution `H`. The default,
+/// `Exponential`, makes the machine memoryless. The simulated time is of type `T`, by default
+/// `f64`; see `with_time_type`.
+#[derive(Clone)]
+pub struct Stepper<H: HoldingTime = Exponential, T: Float = Time> {
     current_state: State,
+    current_time: T,
+    holding_time: H,
     rate_constants: Array2D,
-    rate_coefficients: Option<Array4D>,
+    rate_coefficients: Option<RateCoefficients>,
     stopped: bool,
+    labels: Option<Vec<String>>,
+    categories: Option<Vec<u8>>,
+    min_dwell_time: Time,
+
+    // Rate matrices for specific control parameters, keyed by the bits of the parameters
+    precomputed_rates: BTreeMap<Vec<u64>, Array2D>,
+
+    // Scratch buffers that hold the control parameter powers, the rates computed from the rate
+    // coefficients, and the key of the control parameters. They are reused across steps to avoid
+    // allocating on every step.
+    powers: Array2D,
+    rates: Array2D,
+    ctrl_key: Vec<u64>,
 }
 
 impl Stepper {
+    /// Creates a new memoryless stepper that starts in `current_state`.
+    ///
+    /// The stepper is created stopped if `current_state` is absorbing, i.e. if none of its rate
+    /// constants is positive.
     pub fn new(current_state: State, rate_constants: Array2D) -> Self {
-        // TODO Accept this as an input instead
+        Stepper::with_holding_time(current_state, rate_constants, Exponential)
+    }
+
+    /// Creates a new memoryless stepper from the off-diagonal elements of a generator matrix.
+    ///
+    /// This converts a N x N generator (transition rate) matrix Q into the rate constants used by
+    /// this crate: positive off-diagonal elements are the rates of the corresponding transitions,
+    /// and all other elements, including the diagonal, become -1.0, i.e. no transition. The
+    /// diagonal of the input is ignored, so it need not hold the negative row sums.
+    ///
+    /// Returns an error if the matrix is not square or if `current_state` is not one of its
+    /// states.
+    pub fn new_generator(current_state: State, generator: Array2D) -> Result<Self> {
+        let (rows, cols) = generator.shape;
+        if rows != cols {
+            return Err(StateMachineError::NumElems {
+                actual: cols,
+                expected: rows,
+            });
+        }
+        if current_state >= rows {
+            return Err(StateMachineError::StateOutOfRange {
+                state: current_state,
+                num_states: rows,
+            });
+        }
+
+        let data = generator
+            .data
+            .iter()
+            .enumerate()
+            .map(|(index, &rate)| {
+                if index / cols != index % cols && rate > 0.0 {
+                    rate
+                } else {
+                    -1.0
+                }
+            })
+            .collect();
+
+        Ok(Stepper::new(
+            current_state,
+            Array2D {
+                data,
+                shape: (rows, cols),
+            },
+        ))
+    }
+
+    /// Creates a new memoryless stepper from a N x N ndarray of rate constants.
+    ///
+    /// Returns an error if the array is not square or if `current_state` is not one of its
+    /// states.
+    pub fn from_ndarray(current_state: State, rate_constants: Array2<Rate>) -> Result<Self> {
+        let (rows, cols) = rate_constants.dim();
+        if rows != cols {
+            return Err(StateMachineError::NumElems {
+                actual: cols,
+                expected: rows,
+            });
+        }
+        if current_state >= rows {
+            return Err(StateMachineError::StateOutOfRange {
+                state: current_state,
+                num_states: rows,
+            });
+        }
+
+        // Iterating yields the elements in logical (row-major) order regardless of memory layout
+        let data: Vec<Rate> = rate_constants.iter().copied().collect();
+
+        Ok(Stepper::new(
+            current_state,
+            Array2D {
+                data,
+                shape: (rows, cols),
+            },
+        ))
+    }
+
+    /// Returns the total rate of leaving the current state subject to the given control
+    /// parameters, i.e. the rate of the exponential waiting time before the next transition.
+    ///
+    /// Like `step`, this updates whether the stepper is stopped.
+    pub(crate) fn current_exit_rate(&mut self, ctrl_params: &[CtrlParam]) -> Result<Rate> {
+        self.compute_rates(ctrl_params);
+        self.stopped = is_absorbing_row(self.current_row(self.current_state)?);
+
+        Ok(self
+            .current_row(self.current_state)?
+            .iter()
+            .filter(|rate| **rate > 0.0)
+            .sum())
+    }
+
+    /// Moves the stepper to its next state like `step`, but without sampling a waiting time or
+    /// advancing the clock.
+    ///
+    /// The next state is drawn from the jump probabilities of the embedded discrete-time chain,
+    /// which is exact because the next state of a memoryless machine does not depend on its
+    /// waiting time. This draws a single random number.
+    pub(crate) fn jump<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[CtrlParam],
+        rng: &mut R,
+    ) -> Result<State> {
+        self.compute_rates(ctrl_params);
+        self.stopped = is_absorbing_row(self.current_row(self.current_state)?);
+        if self.stopped {
+            return Err(StateMachineError::Stopped);
+        }
+
+        let ks = self.current_row(self.current_state)?;
+        let total_rate: Rate = ks.iter().filter(|rate| **rate > 0.0).sum();
+        let mut threshold = rng.gen::<f64>() * total_rate;
+        let mut new_state = self.current_state;
+        for (state, rate) in ks.iter().enumerate() {
+            if *rate <= 0.0 {
+                continue;
+            }
+            // The last state with a positive rate is kept if rounding exhausts the threshold
+            new_state = state;
+            if threshold < *rate {
+                break;
+            }
+            threshold -= rate;
+        }
+
+        self.current_state = new_state;
+        self.stopped = is_absorbing_row(self.current_row(new_state)?);
+
+        Ok(new_state)
+    }
+}
+
+impl<H: HoldingTime> Stepper<H> {
+    /// Creates a new stepper that starts in `current_state` and whose waiting times are drawn
+    /// from `holding_time`.
+    pub fn with_holding_time(
+        current_state: State,
+        rate_constants: Array2D,
+        holding_time: H,
+    ) -> Self {
         let rate_coefficients = None;
 
-        Stepper {
+        let rates = Array2D {
+            data: Vec::with_capacity(rate_constants.data.len()),
+            shape: (0, 0),
+        };
+        let powers = Array2D {
+            data: Vec::new(),
+            shape: (0, 0),
+        };
+
+        let mut stepper = Stepper {
             current_state,
+            current_time: 0.0,
+            holding_time,
             rate_constants,
             rate_coefficients,
             stopped: false,
+            labels: None,
+            categories: None,
+            min_dwell_time: 0.0,
+            precomputed_rates: BTreeMap::new(),
+            powers,
+            rates,
+            ctrl_key: Vec::new(),
+        };
+        stepper.stopped = stepper.is_absorbing(current_state);
+
+        stepper
+    }
+}
+
+impl<H: HoldingTime, T: Float> Stepper<H, T> {
+    /// Converts the stepper into one whose simulated time is of type `U`, e.g. `f32` to halve
+    /// the memory needed to store the transition times.
+    pub fn with_time_type<U: Float>(self) -> Stepper<H, U> {
+        Stepper {
+            current_state: self.current_state,
+            current_time: U::from_f64(self.current_time.to_f64()),
+            holding_time: self.holding_time,
+            rate_constants: self.rate_constants,
+            rate_coefficients: self.rate_coefficients,
+            stopped: self.stopped,
+            labels: self.labels,
+            categories: self.categories,
+            min_dwell_time: self.min_dwell_time,
+            precomputed_rates: self.precomputed_rates,
+            powers: self.powers,
+            rates: self.rates,
+            ctrl_key: self.ctrl_key,
         }
     }
 
-    /// Returns the stepper's number of states.
-    pub fn num_states(&self) -> State {
-        self.rate_constants.shape.0
+    /// Returns the simulated time elapsed since the stepper was created or last reset.
+    pub fn current_time(&self) -> T {
+        self.current_time
     }
 
-    /// Compute the rate coefficients subject to the given control parameters.
+    /// Returns true if the stepper could not transition out of its current state at the control
+    /// parameters of its last step.
     ///
-    /// Panics if order is greater than 255.
-    fn compute_rates(&self, ctrl_params: &[CtrlParam]) -> Array2D {
-        if let Some(rate_coefficients) = &self.rate_coefficients {
-            // Order is by definition the size of the second dimension of the rate coefficients array
-            let order = rate_coefficients.shape.1;
+    /// Before the first step, this depends only on the rate constants. A stepper with rate
+    /// coefficients or a rate model is never stopped before its first step, since its rates are
+    /// only known once the control parameters are given.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
 
-            let powers = power(ctrl_params, order.try_into().expect("order is too large"));
-            tensordot(&powers, &rate_coefficients)
-        } else {
-            self.rate_constants.clone()
+    /// Returns true if the rates depend on the control parameters, i.e. if rate coefficients or a
+    /// rate model were set, and false if the rate constants are used.
+    pub fn has_rate_coefficients(&self) -> bool {
+        self.rate_coefficients.is_some()
+    }
+
+    /// Moves the stepper to `state` and sets its current time back to zero.
+    ///
+    /// The stepper is stopped if `state` is absorbing.
+    pub fn reset(&mut self, state: State) -> Result<()> {
+        let num_states = self.num_states();
+        if state >= num_states {
+            return Err(StateMachineError::StateOutOfRange { state, num_states });
         }
+
+        self.current_state = state;
+        self.current_time = T::ZERO;
+        self.stopped = self.is_absorbing(state);
+
+        Ok(())
     }
-}
 
-impl Step for Stepper {
-    /// Returns the stepper's current state.
-    fn current_state(&self) -> State {
-        self.current_state
+    /// Moves the stepper to `state` without changing its current time.
+    ///
+    /// Unlike `reset`, the time accumulated so far is kept, so this repositions a machine that is
+    /// stepped manually, e.g. for conditional sampling. The stepper is stopped if `state` is
+    /// absorbing.
+    pub fn set_state(&mut self, state: State) -> Result<()> {
+        let num_states = self.num_states();
+        if state >= num_states {
+            return Err(StateMachineError::StateOutOfRange { state, num_states });
+        }
+
+        self.current_state = state;
+        self.stopped = self.is_absorbing(state);
+
+        Ok(())
     }
 
-    fn step<R: rand::Rng + ?Sized>(
-        &mut self,
-        ctrl_params: &[CtrlParam],
-        rng: &mut R,
-    ) -> Result<Transition> {
+    /// Returns the rate constant of the transition from `from` to `to`.
+    pub fn get_rate(&self, from: State, to: State) -> Result<Rate> {
+        let index = self.rate_index(from, to)?;
+
+        Ok(self.rate_constants.data[index])
+    }
+
+    /// Sets the rate constant of the transition from `from` to `to`, e.g. to tweak a single edge
+    /// between runs of a fit without rebuilding the stepper.
+    ///
+    /// Like in `new`, a non-positive rate disables the transition. Whether the stepper is stopped
+    /// is updated for the new rates. The rate constants are not used if the stepper has rate
+    /// coefficients.
+    pub fn set_rate(&mut self, from: State, to: State, rate: Rate) -> Result<()> {
+        let index = self.rate_index(from, to)?;
+        self.rate_constants.data[index] = rate;
+        self.stopped = self.is_absorbing(self.current_state);
+
+        Ok(())
+    }
+
+    /// Returns the index of the rate constant from `from` to `to` in the row-major data.
+    fn rate_index(&self, from: State, to: State) -> Result<usize> {
+        let num_states = self.num_states();
+        for state in [from, to] {
+            if state >= num_states {
+                return Err(StateMachineError::StateOutOfRange { state, num_states });
+            }
+        }
+
+        Ok(from * num_states + to)
+    }
+
+    /// Reverts the last step, which returned `transition`, e.g. when a control parameter changes
+    /// before the transition would have happened.
+    pub(crate) fn undo_step(&mut self, transition: &Transition<T>) {
+        self.current_state = transition.from;
+        self.current_time -= transition.time;
+        self.stopped = false;
+    }
+
+    /// Clamps a sampled waiting time from below to `min_dwell_time` and converts it to the time
+    /// type.
+    ///
+    /// Returns `WaitingTimeUnderflow` if the waiting time would not advance the clock.
+    pub(crate) fn waiting_time(&self, sampled: Time) -> Result<T> {
+        let sampled = sampled.max(self.min_dwell_time);
+
+        // A zero or subnormal waiting time would not advance the simulation time, also after
+        // rounding it to the time type
+        let waiting_time = T::from_f64(sampled);
+        if sampled < f64::MIN_POSITIVE || waiting_time < T::MIN_POSITIVE {
+            return Err(StateMachineError::WaitingTimeUnderflow(
+                sampled,
+                self.current_state,
+            ));
+        }
+
+        Ok(waiting_time)
+    }
+
+    /// Advances the clock by `time` without a transition, e.g. up to a time at which the rates
+    /// change while the machine stays in its state.
+    pub(crate) fn advance_time(&mut self, time: T) {
+        self.current_time += time;
+    }
+
+    /// Makes the rates of the stepper depend on the control parameters.
+    ///
+    /// The rates become the polynomial sum_ij c_i^(j+1) * rate_coefficients[i, j, k, l] over the
+    /// control parameters c_i, i.e. `rate_coefficients` is a I x J x N x N array for I control
+    /// parameters, a polynomial of order J, and N states. The rate constants are not used once
+    /// rate coefficients are set.
+    pub fn with_rate_coefficients(self, rate_coefficients: Array4D) -> Result<Self> {
+        self.with_coefficients(RateCoefficients::Dense(rate_coefficients))
+    }
+
+    /// Makes the rates of the stepper depend on the control parameters like
+    /// `with_rate_coefficients`, but only stores the coefficients of the edges of the machine.
+    ///
+    /// The rates of the pairs of states that are not edges are zero, i.e. no transition.
+    pub fn with_sparse_rate_coefficients(self, rate_coefficients: SparseArray4D) -> Result<Self> {
+        self.with_coefficients(RateCoefficients::Sparse(rate_coefficients))
+    }
+
+    /// Makes the rates of the stepper follow an arbitrary rate law of the control parameters.
+    ///
+    /// This generalizes `with_rate_coefficients` beyond polynomials. The model must have as many
+    /// states as the rate constants, which are not used once a model is set.
+    pub fn with_rate_model<M>(self, model: M) -> Result<Self>
+    where
+        M: RateModel + Send + Sync + 'static,
+    {
+        self.with_coefficients(RateCoefficients::Model(Arc::new(model)))
+    }
+
+    fn with_coefficients(mut self, rate_coefficients: RateCoefficients) -> Result<Self> {
+        let num_states = self.num_states();
+        let (k, l) = rate_coefficients.dim();
+        for actual in [k, l] {
+            if actual != num_states {
+                return Err(StateMachineError::NumElems {
+                    actual,
+                    expected: num_states,
+                });
+            }
+        }
+
+        self.rate_coefficients = Some(rate_coefficients);
+        self.stopped = self.is_absorbing(self.current_state);
+
+        Ok(self)
+    }
+
+    /// Uses precomputed rate matrices instead of the rate coefficients for specific values of
+    /// the control parameters.
+    ///
+    /// When the control parameters of a step are exactly equal to those of a pair in `rates`, its
+    /// rate matrix is used; otherwise the rates are computed from the rate coefficients as usual.
+    /// This avoids evaluating the polynomial at every step of a sweep over a few discrete control
+    /// parameter values. The precomputed rates are only used if the stepper has rate
+    /// coefficients.
+    pub fn with_precomputed_rates<I>(mut self, rates: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (Vec<CtrlParam>, Array2D)>,
+    {
+        let num_states = self.num_states();
+        for (ctrl_params, rate_matrix) in rates {
+            if rate_matrix.shape != (num_states, num_states) {
+                return Err(StateMachineError::NumElems {
+                    actual: rate_matrix.data.len(),
+                    expected: num_states * num_states,
+                });
+            }
+
+            let mut key = Vec::with_capacity(ctrl_params.len());
+            ctrl_key_into(&ctrl_params, &mut key);
+            self.precomputed_rates.insert(key, rate_matrix);
+        }
+
+        Ok(self)
+    }
+
+    /// Clamps the sampled waiting times from below to `min_dwell_time`.
+    ///
+    /// This keeps models with huge rates from stalling on zero or subnormal waiting times, at the
+    /// cost of a bias: every waiting time shorter than `min_dwell_time` is lengthened, so the
+    /// simulated dynamics are slower than the model's when the mean waiting times are comparable
+    /// to or smaller than it. The default of zero disables the clamp. Returns an error if
+    /// `min_dwell_time` is negative, NaN, or infinite.
+    pub fn with_min_dwell_time(mut self, min_dwell_time: Time) -> Result<Self> {
+        if !min_dwell_time.is_finite() || min_dwell_time < 0.0 {
+            return Err(StateMachineError::InvalidMinDwellTime(min_dwell_time));
+        }
+        self.min_dwell_time = min_dwell_time;
+
+        Ok(self)
+    }
+
+    /// Returns the lower bound of the sampled waiting times.
+    pub fn min_dwell_time(&self) -> Time {
+        self.min_dwell_time
+    }
+
+    /// Attaches human-readable names to the states, one per state in index order.
+    ///
+    /// The labels are only used to describe outputs and do not affect the simulation.
+    pub fn with_labels(mut self, labels: Vec<String>) -> Result<Self> {
+        let num_states = self.num_states();
+        if labels.len() != num_states {
+            return Err(StateMachineError::NumElems {
+                actual: labels.len(),
+                expected: num_states,
+            });
+        }
+
+        self.labels = Some(labels);
+
+        Ok(self)
+    }
+
+    /// Returns the state labels, if any.
+    pub fn labels(&self) -> Option<&[String]> {
+        self.labels.as_deref()
+    }
+
+    /// Returns the label of `state`, or `None` if the stepper has no labels or `state` is out of
+    /// range.
+    pub fn label_of(&self, state: State) -> Option<&str> {
+        self.labels.as_ref()?.get(state).map(String::as_str)
+    }
+
+    /// Assigns a category to each state, one per state in index order, e.g. to distinguish the
+    /// bright and dark states of a fluorophore.
+    ///
+    /// Like labels, categories only describe outputs; see `analysis::category_dwell_times`.
+    pub fn with_categories(mut self, categories: Vec<u8>) -> Result<Self> {
+        let num_states = self.num_states();
+        if categories.len() != num_states {
+            return Err(StateMachineError::NumElems {
+                actual: categories.len(),
+                expected: num_states,
+            });
+        }
+
+        self.categories = Some(categories);
+
+        Ok(self)
+    }
+
+    /// Returns the category of each state, if any.
+    pub fn categories(&self) -> Option<&[u8]> {
+        self.categories.as_deref()
+    }
+
+    /// Returns the category of `state`, or `None` if the stepper has no categories or `state` is
+    /// out of range.
+    pub fn category_of(&self, state: State) -> Option<u8> {
+        self.categories.as_ref()?.get(state).copied()
+    }
+
+    /// Steps the state machine to its most probable next state after its mean waiting time.
+    ///
+    /// This is a deterministic approximation of `Step::step`. The next state is the one with the
+    /// highest rate, the first one in case of ties, and the waiting time is the reciprocal of the
+    /// sum of all the non-negative rates out of the current state.
+    pub fn step_mean(&mut self, ctrl_params: &[CtrlParam]) -> Result<Transition<T>> {
+        let current_state = self.current_state;
+        self.compute_rates(ctrl_params);
+
+        let ks = self.current_row(current_state)?;
+        self.stopped = is_absorbing_row(ks);
         if self.stopped {
             return Err(StateMachineError::Stopped);
         }
 
-        // Get the rate coefficients only for the current state
-        let (_rows, cols) = self.rate_constants.shape;
-        let ks = &self.rate_constants.data
-            [(self.current_state * cols)..((self.current_state * cols) + cols)];
-
-        // Draw exponential random numbers using the rate coefficients as the mean and keep the
-        // smallest random number. The index of the corresponding rate coefficient is the next
-        // state.
-        let mut exp: Exp<Time>;
-        let mut rn: Time;
-        let mut new_state: State = self.current_state; // Initialization needed because the compiler can't tell when the machine is stopped
-        let mut transition_time: Time = f64::INFINITY;
+        let ks = self.current_row(current_state)?;
+
+        let mut new_state: State = current_state;
+        let mut max_rate: Rate = f64::NEG_INFINITY;
+        let mut total_rate: Rate = 0.0;
         for (state, rate) in ks.iter().enumerate() {
-            // Negative rate => No transition possible to the corresponding state
-            if *rate < 0.0 {
+            // Non-positive rate => No transition possible to the corresponding state
+            if *rate <= 0.0 {
                 continue;
             }
 
-            exp = Exp::new(*rate)?;
-            rn = exp.sample(rng);
-
-            // The smallest random number determines the transition time and the next state
-            if rn < transition_time {
+            total_rate += rate;
+            if *rate > max_rate {
                 new_state = state;
-                transition_time = rn;
+                max_rate = *rate;
             }
         }
 
         let old_state = self.current_state;
         self.current_state = new_state;
 
-        // The stepper is stopped when all its rate coefficients out of its current state are < 0
-        if self.rate_constants.data
-            [(self.current_state * cols)..((self.current_state * cols) + cols)]
-            .iter()
-            .all(|&rate| rate < 0.0)
-        {
-            self.stopped = true;
-        }
+        self.stopped = is_absorbing_row(self.current_row(new_state)?);
+
+        let transition_time = T::from_f64(1.0 / total_rate);
+        self.current_time += transition_time;
 
         Ok(Transition {
             from: old_state,
             time: transition_time,
+            dwell: transition_time,
             to: new_state,
         })
     }
-}
 
-mod tests {
-    #[cfg(test)]
-    use std::iter::zip;
+    /// Steps the state machine like `Step::step`, but returns `Ok(None)` if the machine is stopped.
+    ///
+    /// Errors are reserved for genuine failures, which lets the stepper be driven by iterator
+    /// adapters such as `std::iter::from_fn`.
+    pub fn try_step<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[CtrlParam],
+        rng: &mut R,
+    ) -> Result<Option<Transition<T>>> {
+        match self.step(ctrl_params, rng) {
+            Ok(transition) => Ok(Some(transition)),
+            Err(StateMachineError::Stopped) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
 
-    use rand::thread_rng;
+    /// Steps the state machine up to `n` times and returns the transitions.
+    ///
+    /// Unlike `Step::step`, the times of the returned transitions are cumulative, i.e. measured
+    /// since the stepper was created or last reset. Fewer than `n` transitions are returned if the
+    /// machine reaches an absorbing state.
+    pub fn step_many<R: rand::Rng + ?Sized>(
+        &mut self,
+        n: usize,
+        ctrl_params: &[CtrlParam],
+        rng: &mut R,
+    ) -> Result<Vec<Transition<T>>> {
+        let mut transitions = Vec::with_capacity(n);
+        for _ in 0..n {
+            let mut transition = match self.step(ctrl_params, rng) {
+                Ok(transition) => transition,
+                Err(StateMachineError::Stopped) => break,
+                Err(err) => return Err(err),
+            };
+            transition.time = self.current_time;
+            transitions.push(transition);
 
-    use super::Stepper;
-    use crate::arrays::Array2D;
-    use crate::{Rate, Step};
+            if self.stopped {
+                break;
+            }
+        }
 
-    #[test]
-    fn stepper_new() {
-        let current_state = 0;
-        let rate_constants = Array2D {
-            data: vec![-1.0, 1.0, 1.0, -1.0],
-            shape: (2, 2),
-        };
+        Ok(transitions)
+    }
 
-        let result = Stepper::new(
-            current_state,
-            Array2D {
-                data: vec![-1.0, 1.0, 1.0, -1.0],
-                shape: (2, 2),
-            },
-        );
+    /// Returns the total rate of leaving each state subject to the given control parameters.
+    ///
+    /// The exit rate of a state is the sum of its positive rates, including a positive rate on
+    /// the diagonal, since `step` also takes such self-transitions. Its reciprocal is the mean
+    /// waiting time in the state before a transition.
+    pub fn exit_rates(&self, ctrl_params: &[CtrlParam]) -> Vec<Rate> {
+        let rates = self.rates_at(ctrl_params);
+        let (rows, cols) = rates.shape;
 
-        assert_eq!(current_state, result.current_state());
-        for elements in zip(
-            result.rate_constants.data.iter(),
-            rate_constants.data.iter(),
-        ) {
-            assert!((elements.0 - elements.1).abs() < 0.000001)
+        (0..rows)
+            .map(|i| {
+                rates.data[(i * cols)..((i * cols) + cols)]
+                    .iter()
+                    .filter(|rate| **rate > 0.0)
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// Returns the rate matrix at each point of a grid of control parameters without stepping.
+    ///
+    /// This shows how the rates change with the control parameters before simulating. The rates
+    /// are the rate constants at every point if the stepper has no rate coefficients.
+    pub fn rate_matrices_over_grid(&mut self, param_grid: &[Vec<CtrlParam>]) -> Vec<Array2D> {
+        param_grid
+            .iter()
+            .map(|ctrl_params| {
+                self.compute_rates(ctrl_params);
+                self.current_rates().clone()
+            })
+            .collect()
+    }
+
+    /// Returns the mean time spent in each state per visit subject to the given control
+    /// parameters.
+    ///
+    /// This is the reciprocal of the exit rate of each state, i.e. the analytic mean of the
+    /// exponential waiting times, and is infinite for absorbing states. It can be compared with
+    /// the dwell times of simulated trajectories.
+    pub fn mean_dwell_times(&self, ctrl_params: &[CtrlParam]) -> Vec<Time> {
+        self.exit_rates(ctrl_params)
+            .into_iter()
+            .map(|exit_rate| {
+                // The exit rate of an absorbing state may be -0.0, the sum of no rates
+                if exit_rate > 0.0 {
+                    1.0 / exit_rate
+                } else {
+                    Time::INFINITY
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the probability that the next transition goes to each state, given the current
+    /// state and the control parameters.
+    ///
+    /// These are the jump probabilities of the embedded discrete-time chain, i.e. each positive
+    /// rate out of the current state divided by their sum. The probabilities of states that
+    /// cannot be reached are zero, and all of them are zero if the current state is absorbing.
+    pub fn transition_probabilities(&self, ctrl_params: &[CtrlParam]) -> Vec<f64> {
+        let rates = self.rates_at(ctrl_params);
+        let ks = rates.row(self.current_state).unwrap_or(&[]);
+        let total_rate: Rate = ks.iter().filter(|rate| **rate > 0.0).sum();
+
+        ks.iter()
+            .map(|rate| if *rate > 0.0 { rate / total_rate } else { 0.0 })
+            .collect()
+    }
+
+    /// Returns the rates subject to the given control parameters without using the scratch
+    /// buffers.
+    ///
+    /// This is meant for queries outside of the stepping loop, which must not mutate the stepper.
+    fn rates_at(&self, ctrl_params: &[CtrlParam]) -> Cow<'_, Array2D> {
+        if let Some(rate_coefficients) = &self.rate_coefficients {
+            let mut key = Vec::with_capacity(ctrl_params.len());
+            ctrl_key_into(ctrl_params, &mut key);
+            if let Some(rates) = self.precomputed_rates.get(&key) {
+                return Cow::Borrowed(rates);
+            }
+
+            let mut powers = Array2D {
+                data: Vec::new(),
+                shape: (0, 0),
+            };
+            let mut rates = Array2D {
+                data: Vec::new(),
+                shape: (0, 0),
+            };
+            rate_coefficients.rates_into(ctrl_params, &mut powers, &mut rates);
+
+            Cow::Owned(rates)
+        } else {
+            Cow::Borrowed(&self.rate_constants)
         }
     }
 
-    #[test]
-    fn stepper_step() {
-        let mut rng = rand::thread_rng();
-        let rate_constants = Array2D {
-            data: vec![-1.0, 1.0, 1.0, -1.0],
-            shape: (2, 2),
-        };
+    /// Returns true when none of the rate constants out of a state is positive.
+    ///
+    /// A state that is out of range cannot transition and is considered absorbing. If the rates
+    /// depend on the control parameters, no state in range is considered absorbing, because the
+    /// rates are unknown until a step.
+    fn is_absorbing(&self, state: State) -> bool {
+        if self.rate_coefficients.is_some() {
+            return state >= self.num_states();
+        }
 
-        let mut sm = Stepper::new(0, rate_constants);
-        let ctrl_params = vec![1.0];
-        let old_state = sm.current_state();
+        self.rate_constants
+            .row(state)
+            .map(is_absorbing_row)
+            .unwrap_or(true)
+    }
 
-        let transition = sm.step(ctrl_params.as_slice(), &mut rng).unwrap();
+    /// Returns the stepper's number of states.
+    pub fn num_states(&self) -> State {
+        self.rate_constants.shape.0
+    }
 
-        assert_ne!(old_state, sm.current_state());
-        assert_ne!(transition.from(), transition.to());
+    /// Compute the rate coefficients subject to the given control parameters.
+    ///
+    /// The rates are written into a scratch buffer owned by the stepper, so no allocation occurs
+    /// once the buffer has grown to its final size. Use `current_rates` to read them.
+    ///
+    /// Panics if order is greater than 255.
+    fn compute_rates(&mut self, ctrl_params: &[CtrlParam]) {
+        if let Some(rate_coefficients) = &self.rate_coefficients {
+            if !self.precomputed_rates.is_empty() {
+                ctrl_key_into(ctrl_params, &mut self.ctrl_key);
+                if let Some(rates) = self.precomputed_rates.get(&self.ctrl_key) {
+                    self.rates.clone_from(rates);
+                    return;
+                }
+            }
+
+            rate_coefficients.rates_into(ctrl_params, &mut self.powers, &mut self.rates);
+        }
+    }
+
+    /// Returns the rates from the last call to `compute_rates`.
+    fn current_rates(&self) -> &Array2D {
+        if self.rate_coefficients.is_some() {
+            &self.rates
+        } else {
+            &self.rate_constants
+        }
+    }
+
+    /// Returns the rates out of `state` from the last call to `compute_rates`.
+    fn current_row(&self, state: State) -> Result<&[Rate]> {
+        self.current_rates()
+            .row(state)
+            .map_err(|_| StateMachineError::StateOutOfRange {
+                state,
+                num_states: self.num_states(),
+            })
+    }
+}
+
+/// The source of the control parameter dependent rates of a stepper: polynomial coefficients
+/// stored densely or only for the edges of the machine, or a custom rate model.
+///
+/// Rate models are shared between clones of a stepper since they are never mutated.
+#[derive(Clone)]
+enum RateCoefficients {
+    Dense(Array4D),
+    Sparse(SparseArray4D),
+    Model(Arc<dyn RateModel + Send + Sync>),
+}
+
+impl RateCoefficients {
+    /// Returns the numbers of states from and to which the rates are defined.
+    fn dim(&self) -> (usize, usize) {
+        match self {
+            RateCoefficients::Dense(arr) => (arr.shape.2, arr.shape.3),
+            RateCoefficients::Sparse(arr) => (arr.shape.2, arr.shape.3),
+            RateCoefficients::Model(model) => (model.num_states(), model.num_states()),
+        }
+    }
+
+    /// Computes the rates subject to the given control parameters, writing into `out`.
+    ///
+    /// `powers` is a scratch buffer for the powers of the control parameters.
+    ///
+    /// Panics if the order of the polynomial is greater than 255.
+    fn rates_into(&self, ctrl_params: &[CtrlParam], powers: &mut Array2D, out: &mut Array2D) {
+        // Order is by definition the size of the second dimension of the rate coefficients array
+        let order = |shape: (usize, usize, usize, usize)| -> u8 {
+            shape.1.try_into().expect("order is too large")
+        };
+
+        match self {
+            RateCoefficients::Dense(arr) => {
+                power_into(ctrl_params, order(arr.shape), powers);
+                tensordot_into(powers, arr, out);
+            }
+            RateCoefficients::Sparse(arr) => {
+                power_into(ctrl_params, order(arr.shape), powers);
+                tensordot_sparse_into(powers, arr, out);
+            }
+            RateCoefficients::Model(model) => {
+                let n = model.num_states();
+                out.data.clear();
+                out.data.resize(n * n, 0.0);
+                for (state, row) in out.data.chunks_exact_mut(n.max(1)).enumerate() {
+                    model.rates(state, ctrl_params, row);
+                }
+                out.shape = (n, n);
+            }
+        }
+    }
+}
+
+/// Writes the key of `ctrl_params` in a map of precomputed rates into `key`.
+///
+/// The key is the bit pattern of each parameter, so parameters match only if they are exactly
+/// equal. Adding zero maps -0.0 to 0.0 so that both have the same key.
+fn ctrl_key_into(ctrl_params: &[CtrlParam], key: &mut Vec<u64>) {
+    key.clear();
+    key.extend(ctrl_params.iter().map(|param| (param + 0.0).to_bits()));
+}
+
+/// Returns true when a state with the given rates out of it cannot transition to any state.
+///
+/// Both negative and zero rates denote the absence of a transition.
+fn is_absorbing_row(rates: &[Rate]) -> bool {
+    rates.iter().all(|&rate| rate <= 0.0)
+}
+
+/// A memoryless state machine whose control parameters vary with time.
+///
+/// The control parameters at time t are `control(t, ctrl_params)`, where `ctrl_params` are the
+/// ones passed to `step`. Transitions are sampled exactly with Lewis-Shedler thinning: candidate
+/// times are drawn at a constant dominating rate and accepted with probability equal to the ratio
+/// of the total rate out of the current state at that time to the dominating rate. The sampler
+/// is only exact if the total rate never exceeds the bound, so `step` fails with
+/// `StateMachineError::RateBoundExceeded` when it does.
+///
+/// `step` fails with `StateMachineError::Stopped` when no transition happens before the horizon
+/// set by `with_horizon`, or right away if the rates do not depend on the control parameters and
+/// the current state is absorbing.
+pub struct TimeVaryingStepper<F>
+where
+    F: Fn(Time, &[CtrlParam]) -> Vec<CtrlParam>,
+{
+    stepper: Stepper,
+    control: F,
+    rate_bound: Rate,
+    horizon: Time,
+}
+
+impl<F> TimeVaryingStepper<F>
+where
+    F: Fn(Time, &[CtrlParam]) -> Vec<CtrlParam>,
+{
+    /// Creates a time-varying stepper whose total rate out of any state is dominated by
+    /// `rate_bound`.
+    ///
+    /// `rate_bound` must be positive and finite. Tighter bounds reject fewer candidate times.
+    pub fn with_rate_bound(stepper: Stepper, control: F, rate_bound: Rate) -> Result<Self> {
+        if !(rate_bound > 0.0 && rate_bound.is_finite()) {
+            return Err(StateMachineError::InvalidRateBound(rate_bound));
+        }
+
+        Ok(TimeVaryingStepper {
+            stepper,
+            control,
+            rate_bound,
+            horizon: Time::INFINITY,
+        })
+    }
+
+    /// Sets the time after which `step` gives up looking for a transition.
+    ///
+    /// Without a horizon, `step` never returns if the rates out of the current state remain zero
+    /// forever. `horizon` must be a positive number, possibly infinite.
+    pub fn with_horizon(mut self, horizon: Time) -> Result<Self> {
+        if horizon.is_nan() || horizon <= 0.0 {
+            return Err(StateMachineError::InvalidCutoff(horizon));
+        }
+
+        self.horizon = horizon;
+        Ok(self)
+    }
+
+    /// Returns the simulated time elapsed since the stepper was created.
+    pub fn current_time(&self) -> Time {
+        self.stepper.current_time()
+    }
+
+    pub fn stepper(&self) -> &Stepper {
+        &self.stepper
+    }
+}
+
+impl<F> Step for TimeVaryingStepper<F>
+where
+    F: Fn(Time, &[CtrlParam]) -> Vec<CtrlParam>,
+{
+    fn current_state(&self) -> State {
+        self.stepper.current_state
+    }
+
+    fn step<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[CtrlParam],
+        rng: &mut R,
+    ) -> Result<Transition> {
+        let current_state = self.stepper.current_state;
+        if self.stepper.rate_coefficients.is_none() && self.stepper.is_absorbing(current_state) {
+            return Err(StateMachineError::Stopped);
+        }
+
+        let t_previous = self.stepper.current_time;
+        let mut t_candidate = t_previous;
+        loop {
+            t_candidate += Exponential.sample(self.rate_bound, rng)?;
+            if t_candidate > self.horizon {
+                return Err(StateMachineError::Stopped);
+            }
+
+            let rates = self
+                .stepper
+                .rates_at(&(self.control)(t_candidate, ctrl_params));
+            let ks = rates
+                .row(current_state)
+                .map_err(|_| StateMachineError::StateOutOfRange {
+                    state: current_state,
+                    num_states: self.stepper.num_states(),
+                })?;
+
+            // Non-positive rate => No transition possible to the corresponding state
+            let total_rate: Rate = ks.iter().filter(|rate| **rate > 0.0).sum();
+            if total_rate > self.rate_bound {
+                return Err(StateMachineError::RateBoundExceeded {
+                    rate: total_rate,
+                    bound: self.rate_bound,
+                });
+            }
+
+            // Accept the candidate time with probability total_rate / rate_bound, then pick the
+            // next state with probability proportional to its rate
+            let threshold = rng.gen::<f64>() * self.rate_bound;
+            if threshold >= total_rate {
+                continue;
+            }
+
+            let mut cumulative_rate: Rate = 0.0;
+            let mut new_state = current_state;
+            for (state, rate) in ks.iter().enumerate() {
+                if *rate <= 0.0 {
+                    continue;
+                }
+                new_state = state;
+                cumulative_rate += rate;
+                if threshold < cumulative_rate {
+                    break;
+                }
+            }
+
+            self.stepper.current_state = new_state;
+            self.stepper.current_time = t_candidate;
+            self.stepper.stopped = false;
+
+            return Ok(Transition {
+                from: current_state,
+                time: t_candidate - t_previous,
+                dwell: t_candidate - t_previous,
+                to: new_state,
+            });
+        }
+    }
+}
+
+/// A rough estimate of the size of a run, obtained without simulating it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RunEstimate {
+    /// The expected number of transitions, assuming the machine keeps leaving states at the exit
+    /// rate of its starting state.
+    pub expected_transitions: f64,
+    /// The memory needed to store the expected number of transitions.
+    pub bytes_per_machine: usize,
+}
+
+/// Estimates the number of transitions and the memory that a run of duration `t_cutoff` needs.
+///
+/// The estimate is `t_cutoff` times the exit rate of the stepper's current state, so it is only
+/// accurate when the exit rates of all the visited states are similar. It is meant for sizing an
+/// ensemble before launching it, not as a prediction of a single run.
+pub fn estimate_run<H: HoldingTime>(
+    stepper: &Stepper<H>,
+    t_cutoff: Time,
+    ctrl_params: &[CtrlParam],
+) -> RunEstimate {
+    let exit_rate = stepper
+        .exit_rates(ctrl_params)
+        .get(stepper.current_state())
+        .copied()
+        .unwrap_or(0.0);
+    let expected_transitions = t_cutoff * exit_rate;
+    let bytes_per_machine =
+        (expected_transitions * core::mem::size_of::<Transition>() as f64) as usize;
+
+    RunEstimate {
+        expected_transitions,
+        bytes_per_machine,
+    }
+}
+
+impl<H: HoldingTime, T: Float> Step<T> for Stepper<H, T> {
+    /// Returns the stepper's current state.
+    fn current_state(&self) -> State {
+        self.current_state
+    }
+
+    fn step<R: rand::Rng + ?Sized>(
+        &mut self,
+        ctrl_params: &[CtrlParam],
+        rng: &mut R,
+    ) -> Result<Transition<T>> {
+        let current_state = self.current_state;
+        self.compute_rates(ctrl_params);
+
+        // Whether the machine is stopped depends on the rates at the current control parameters,
+        // so a machine that stopped may resume when they change
+        self.stopped = is_absorbing_row(self.current_row(current_state)?);
+        if self.stopped {
+            return Err(StateMachineError::Stopped);
+        }
+
+        // Get the rate coefficients only for the current state
+        let ks = self.current_row(current_state)?;
+
+        // Draw random waiting times using the rate coefficients and keep the smallest one. The
+        // index of the corresponding rate coefficient is the next state.
+        let mut rn: Time;
+        let mut new_state: State = current_state; // Initialization needed because the compiler can't tell when the machine is stopped
+        let mut transition_time: Time = f64::INFINITY;
+        for (state, rate) in ks.iter().enumerate() {
+            // Non-positive rate => No transition possible to the corresponding state
+            if *rate <= 0.0 {
+                continue;
+            }
+
+            rn = self.holding_time.sample(*rate, rng)?;
+
+            // The smallest random number determines the transition time and the next state
+            if rn < transition_time {
+                new_state = state;
+                transition_time = rn;
+            }
+        }
+
+        let waiting_time = self.waiting_time(transition_time)?;
+
+        let old_state = self.current_state;
+        self.current_state = new_state;
+        self.current_time += waiting_time;
+
+        // The stepper is stopped when none of the rates out of its new state at the current
+        // control parameters is positive
+        self.stopped = is_absorbing_row(self.current_row(new_state)?);
+
+        Ok(Transition {
+            from: old_state,
+            time: waiting_time,
+            dwell: waiting_time,
+            to: new_state,
+        })
+    }
+}
+
+/// The configuration of a memoryless stepper as it is serialized.
+///
+/// The fields borrow from the stepper when serializing and own their values when deserializing.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StepperConfig<'a> {
+    current_state: State,
+    rate_constants: Cow<'a, Array2D>,
+    #[serde(default)]
+    rate_coefficients: Option<Cow<'a, Array4D>>,
+    #[serde(default)]
+    sparse_rate_coefficients: Option<Cow<'a, SparseArray4D>>,
+    #[serde(default)]
+    min_dwell_time: Time,
+    #[serde(default)]
+    labels: Option<Cow<'a, [String]>>,
+    #[serde(default)]
+    categories: Option<Cow<'a, [u8]>>,
+}
+
+/// Serializes the configuration of the stepper: its current state, rate constants and
+/// coefficients, minimum dwell time, labels and categories.
+///
+/// The simulated time and the precomputed rates are not saved, so a deserialized stepper starts
+/// at time zero. Steppers with a rate model set by `with_rate_model` cannot be serialized.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Stepper {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> core::result::Result<S::Ok, S::Error> {
+        let (rate_coefficients, sparse_rate_coefficients) = match &self.rate_coefficients {
+            None => (None, None),
+            Some(RateCoefficients::Dense(arr)) => (Some(Cow::Borrowed(arr)), None),
+            Some(RateCoefficients::Sparse(arr)) => (None, Some(Cow::Borrowed(arr))),
+            Some(RateCoefficients::Model(_)) => {
+                return Err(serde::ser::Error::custom(
+                    "steppers with a rate model cannot be serialized",
+                ))
+            }
+        };
+
+        StepperConfig {
+            current_state: self.current_state,
+            rate_constants: Cow::Borrowed(&self.rate_constants),
+            rate_coefficients,
+            sparse_rate_coefficients,
+            min_dwell_time: self.min_dwell_time,
+            labels: self.labels.as_deref().map(Cow::Borrowed),
+            categories: self.categories.as_deref().map(Cow::Borrowed),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Deserializes a stepper with the same checks as the constructor and builder methods, and also
+/// checks that the rate constants are square and that the current state is one of their states.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Stepper {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> core::result::Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let config = StepperConfig::deserialize(deserializer)?;
+        let (rows, cols) = config.rate_constants.shape;
+        if rows != cols {
+            return Err(D::Error::custom(StateMachineError::NumElems {
+                actual: cols,
+                expected: rows,
+            }));
+        }
+        if config.current_state >= rows {
+            return Err(D::Error::custom(StateMachineError::StateOutOfRange {
+                state: config.current_state,
+                num_states: rows,
+            }));
+        }
+        if config.rate_coefficients.is_some() && config.sparse_rate_coefficients.is_some() {
+            return Err(D::Error::custom(
+                "a stepper cannot have both dense and sparse rate coefficients",
+            ));
+        }
+
+        let build = || -> Result<Self> {
+            let mut stepper =
+                Stepper::new(config.current_state, config.rate_constants.into_owned())
+                    .with_min_dwell_time(config.min_dwell_time)?;
+            if let Some(arr) = config.rate_coefficients {
+                stepper = stepper.with_rate_coefficients(arr.into_owned())?;
+            }
+            if let Some(arr) = config.sparse_rate_coefficients {
+                stepper = stepper.with_sparse_rate_coefficients(arr.into_owned())?;
+            }
+            if let Some(labels) = config.labels {
+                stepper = stepper.with_labels(labels.into_owned())?;
+            }
+            if let Some(categories) = config.categories {
+                stepper = stepper.with_categories(categories.into_owned())?;
+            }
+
+            Ok(stepper)
+        };
+
+        build().map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter::zip;
+
+    use std::num::NonZeroU32;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use ndarray::arr2;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::{estimate_run, Stepper, TimeVaryingStepper};
+    use crate::accumulators::StepUntil;
+    use crate::arrays::{power, tensordot, Array2D, Array4D, SparseArray4D};
+    use crate::holding_times::Erlang;
+    use crate::rate_models::RateModel;
+    use crate::{Accumulate, StateMachineError, Step, Transition};
+
+    #[test]
+    fn stepper_exit_rates() {
+        let sm = Stepper::new(
+            0,
+            Array2D {
+                data: vec![-1.0, 0.5, 1.0, 1.5, -1.0, 2.0, -1.0, 3.5, -1.0],
+                shape: (3, 3),
+            },
+        );
+
+        let result = sm.exit_rates(&[]);
+
+        assert_eq!(vec![0.5 + 1.0, 1.5 + 2.0, 3.5], result);
+    }
+
+    #[test]
+    fn stepper_exit_rates_match_simulated_dwell_times() {
+        let mut rng = StdRng::seed_from_u64(12);
+        // State 0 also transitions to itself at rate 1
+        let mut sm = Stepper::new(0, Array2D::new(vec![1.0, 2.0, 1.0, -1.0], (2, 2)).unwrap());
+        let exit_rates = sm.exit_rates(&[]);
+        assert_eq!(vec![3.0, 1.0], exit_rates);
+
+        let mut totals = [0.0; 2];
+        let mut counts = [0usize; 2];
+        for _ in 0..20_000 {
+            let transition = sm.step(&[], &mut rng).unwrap();
+            totals[transition.from()] += transition.dwell();
+            counts[transition.from()] += 1;
+        }
+
+        for state in 0..2 {
+            let mean_dwell = totals[state] / counts[state] as f64;
+            assert!((mean_dwell * exit_rates[state] - 1.0).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn stepper_with_f32_time() {
+        let mut rng = StdRng::seed_from_u64(15);
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, 1.0, -1.0], (2, 2)).unwrap();
+        let mut sm = Stepper::new(0, rate_constants).with_time_type::<f32>();
+
+        let transition: Transition<f32> = sm.step(&[], &mut rng).unwrap();
+        assert_eq!(transition.time(), sm.current_time());
+
+        let mut acc = StepUntil::new(sm, 100.0f32).unwrap();
+        let transitions = acc.accumulate(&[], &mut rng).unwrap();
+
+        assert!(!transitions.is_empty());
+        assert!(transitions.windows(2).all(|w| w[0].time() < w[1].time()));
+        assert!(transitions.iter().all(|t| t.time() <= 100.0f32));
+    }
+
+    #[test]
+    fn stepper_rate_matrices_over_grid() {
+        let rate_constants = Array2D::new(vec![-1.0; 4], (2, 2)).unwrap();
+        let rate_coefficients = Array4D::new(
+            vec![-1.0, 1.0, 2.0, -1.0, -1.0, 0.5, 0.5, -1.0],
+            (1, 2, 2, 2),
+        )
+        .unwrap();
+        let mut sm = Stepper::new(0, rate_constants)
+            .with_rate_coefficients(rate_coefficients)
+            .unwrap();
+        let grid = vec![vec![0.0], vec![1.0], vec![2.0]];
+
+        let matrices = sm.rate_matrices_over_grid(&grid);
+
+        assert_eq!(3, matrices.len());
+        for (ctrl_params, matrix) in grid.iter().zip(&matrices) {
+            sm.compute_rates(ctrl_params);
+            assert_eq!(sm.current_rates().shape, matrix.shape);
+            assert_eq!(sm.current_rates().data, matrix.data);
+        }
+        // The rate from 0 to 1 is c + 0.5 c^2
+        assert_eq!(1.0 + 0.5, matrices[1].data[1]);
+        assert_eq!(0.0, sm.current_time());
+    }
+
+    #[test]
+    fn stepper_mean_dwell_times() {
+        let sm = Stepper::new(
+            0,
+            Array2D {
+                data: vec![-1.0, 0.5, 1.0, 1.5, -1.0, 2.0, -1.0, -1.0, -1.0],
+                shape: (3, 3),
+            },
+        );
+
+        let result = sm.mean_dwell_times(&[]);
+
+        assert_eq!(
+            vec![1.0 / (0.5 + 1.0), 1.0 / (1.5 + 2.0), f64::INFINITY],
+            result
+        );
+    }
+
+    #[test]
+    fn stepper_new() {
+        let current_state = 0;
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 1.0, -1.0],
+            shape: (2, 2),
+        };
+
+        let result = Stepper::new(
+            current_state,
+            Array2D {
+                data: vec![-1.0, 1.0, 1.0, -1.0],
+                shape: (2, 2),
+            },
+        );
+
+        assert_eq!(current_state, result.current_state());
+        for elements in zip(
+            result.rate_constants.data.iter(),
+            rate_constants.data.iter(),
+        ) {
+            assert!((elements.0 - elements.1).abs() < 0.000001)
+        }
+        assert!(!result.has_rate_coefficients());
+    }
+
+    #[test]
+    fn stepper_set_rate_changes_dynamics() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, -1.0, -1.0], (2, 2)).unwrap();
+        let mut sm = Stepper::new(0, rate_constants);
+        assert_eq!(1.0, sm.get_rate(0, 1).unwrap());
+
+        sm.set_rate(0, 1, 0.0).unwrap();
+        assert_eq!(0.0, sm.get_rate(0, 1).unwrap());
+        assert!(sm.is_stopped());
+        assert!(matches!(
+            sm.step(&[], &mut rng),
+            Err(StateMachineError::Stopped)
+        ));
+
+        sm.set_rate(0, 1, 5.0).unwrap();
+        assert!(!sm.is_stopped());
+        assert_eq!(1, sm.step(&[], &mut rng).unwrap().to);
+        assert_eq!(vec![5.0, 0.0], sm.exit_rates(&[]));
+    }
+
+    #[test]
+    fn stepper_rate_accessors_check_bounds() {
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, 1.0, -1.0], (2, 2)).unwrap();
+        let mut sm = Stepper::new(0, rate_constants);
+
+        assert!(matches!(
+            sm.get_rate(2, 0),
+            Err(StateMachineError::StateOutOfRange {
+                state: 2,
+                num_states: 2
+            })
+        ));
+        assert!(sm.set_rate(0, 2, 1.0).is_err());
+        assert_eq!(vec![1.0, 1.0], sm.exit_rates(&[]));
+    }
+
+    #[test]
+    fn stepper_step() {
+        let mut rng = rand::thread_rng();
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 1.0, -1.0],
+            shape: (2, 2),
+        };
+
+        let mut sm = Stepper::new(0, rate_constants);
+        let ctrl_params = vec![1.0];
+        let old_state = sm.current_state();
+
+        let transition = sm.step(ctrl_params.as_slice(), &mut rng).unwrap();
+
+        assert_ne!(old_state, sm.current_state());
+        assert_ne!(transition.from(), transition.to());
+    }
+
+    #[test]
+    fn stepper_compute_rates_reuses_buffer() {
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 1.0, -1.0],
+            shape: (2, 2),
+        };
+        let rate_coefficients = Array4D::new(
+            vec![
+                0.0, 0.0, 2.0, 0.0, 0.0, 2.0, 0.5, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0, 4.0, 0.0,
+                0.0, 2.0, 0.5, 0.0, 0.0, 2.0, 2.0, 0.0,
+            ],
+            (2, 3, 2, 2),
+        )
+        .unwrap();
+        let mut sm = Stepper::new(0, rate_constants)
+            .with_rate_coefficients(rate_coefficients.clone())
+            .unwrap();
+
+        for ctrl_params in [[2.0, 3.0], [0.5, 1.5], [2.0, 3.0]] {
+            let expected = tensordot(&power(&ctrl_params, 3), &rate_coefficients);
+            let capacity = sm.rates.data.capacity();
+
+            sm.compute_rates(&ctrl_params);
+            let result = sm.current_rates();
+
+            assert_eq!(expected.shape, result.shape);
+            assert_eq!(expected.data, result.data);
+            if capacity > 0 {
+                assert_eq!(capacity, sm.rates.data.capacity());
+            }
+        }
+    }
+
+    #[test]
+    fn stepper_new_in_absorbing_state() {
+        let mut rng = rand::thread_rng();
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, -1.0, -1.0],
+            shape: (2, 2),
+        };
+
+        let mut sm = Stepper::new(1, rate_constants);
+
+        assert!(sm.stopped);
+        assert!(matches!(
+            sm.step(&[], &mut rng),
+            Err(StateMachineError::Stopped)
+        ));
+    }
+
+    #[test]
+    fn stepper_with_rate_coefficients_is_not_stopped_before_first_step() {
+        let mut rng = StdRng::seed_from_u64(3);
+        // The rate constants are all absent, but the coefficients make both states transition
+        let mut sm = Stepper::new(0, Array2D::new(vec![-1.0; 4], (2, 2)).unwrap())
+            .with_rate_coefficients(Array4D::new(vec![-1.0, 1.0, 1.0, -1.0], (1, 1, 2, 2)).unwrap())
+            .unwrap();
+
+        assert!(!sm.is_stopped());
+        sm.step(&[1.0], &mut rng).unwrap();
+        assert!(!sm.is_stopped());
+        // At a control parameter of zero there are no transitions
+        assert!(sm.step(&[0.0], &mut rng).is_err());
+        assert!(sm.is_stopped());
+    }
+
+    #[test]
+    fn stepper_erlang_shape_one_is_exponential() {
+        let rate_constants = Array2D {
+            data: vec![-1.0, 0.5, 1.0, 1.5, -1.0, 2.0, 2.5, 3.5, -1.0],
+            shape: (3, 3),
+        };
+        let erlang = Erlang {
+            shape: NonZeroU32::new(1).unwrap(),
+        };
+        let mut exponential_sm = Stepper::new(0, rate_constants.clone());
+        let mut erlang_sm = Stepper::with_holding_time(0, rate_constants, erlang);
+        let mut exponential_rng = StdRng::seed_from_u64(42);
+        let mut erlang_rng = StdRng::seed_from_u64(42);
+
+        for _ in 0..100 {
+            let expected = exponential_sm.step(&[], &mut exponential_rng).unwrap();
+            let actual = erlang_sm.step(&[], &mut erlang_rng).unwrap();
+
+            assert_eq!(expected.from(), actual.from());
+            assert_eq!(expected.to(), actual.to());
+            assert_eq!(expected.time(), actual.time());
+        }
+    }
+
+    #[test]
+    fn stepper_with_rate_coefficients() {
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 1.0, -1.0],
+            shape: (2, 2),
+        };
+        // A single control parameter and a first order polynomial
+        let rate_coefficients = Array4D::new(vec![-1.0, 2.0, 3.0, -1.0], (1, 1, 2, 2)).unwrap();
+
+        let sm = Stepper::new(0, rate_constants)
+            .with_rate_coefficients(rate_coefficients)
+            .unwrap();
+
+        assert!(sm.has_rate_coefficients());
+        assert_eq!(vec![2.0, 3.0], sm.exit_rates(&[1.0]));
+        assert_eq!(vec![20.0, 30.0], sm.exit_rates(&[10.0]));
+    }
+
+    #[test]
+    fn stepper_with_rate_coefficients_wrong_shape() {
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 1.0, -1.0],
+            shape: (2, 2),
+        };
+        let rate_coefficients = Array4D::new(vec![0.0; 6], (1, 1, 2, 3)).unwrap();
+
+        let result = Stepper::new(0, rate_constants).with_rate_coefficients(rate_coefficients);
+
+        assert!(matches!(
+            result,
+            Err(StateMachineError::NumElems {
+                actual: 3,
+                expected: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn stepper_set_state_keeps_time() {
+        let mut rng = StdRng::seed_from_u64(4);
+        // 0 -> 1 -> 2 with state 2 absorbing
+        let rate_constants = Array2D::new(
+            vec![-1.0, 1.0, -1.0, -1.0, -1.0, 1.0, -1.0, -1.0, -1.0],
+            (3, 3),
+        )
+        .unwrap();
+        let mut sm = Stepper::new(0, rate_constants);
+        sm.step(&[], &mut rng).unwrap();
+        let time = sm.current_time();
+
+        sm.set_state(2).unwrap();
+        assert!(sm.is_stopped());
+        assert_eq!(time, sm.current_time());
+
+        sm.set_state(1).unwrap();
+        assert!(!sm.is_stopped());
+        let transition = sm.step(&[], &mut rng).unwrap();
+        assert_eq!(1, transition.from);
+        assert_eq!(2, transition.to);
+        assert!((sm.current_time() - time - transition.time).abs() < 1e-12);
+
+        assert!(matches!(
+            sm.set_state(3),
+            Err(StateMachineError::StateOutOfRange {
+                state: 3,
+                num_states: 3
+            })
+        ));
+        assert_eq!(2, sm.current_state());
+    }
+
+    #[test]
+    fn stepper_current_time() {
+        let mut rng = rand::thread_rng();
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 1.0, -1.0],
+            shape: (2, 2),
+        };
+        let mut sm = Stepper::new(0, rate_constants);
+
+        let mut total: f64 = 0.0;
+        for _ in 0..10 {
+            total += sm.step(&[], &mut rng).unwrap().time();
+        }
+
+        assert!((sm.current_time() - total).abs() < 1e-12);
+
+        sm.reset(1).unwrap();
+
+        assert_eq!(0.0, sm.current_time());
+        assert_eq!(1, sm.current_state());
+        assert!(sm.reset(2).is_err());
+    }
+
+    #[test]
+    fn stepper_min_dwell_time_prevents_zero_time_transitions() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let rate_constants = Array2D {
+            data: vec![-1.0, f64::MAX, f64::MAX, -1.0],
+            shape: (2, 2),
+        };
+        let mut sm = Stepper::new(0, rate_constants)
+            .with_min_dwell_time(1e-9)
+            .unwrap();
+
+        for _ in 0..100 {
+            let transition = sm.step(&[], &mut rng).unwrap();
+
+            assert_eq!(1e-9, transition.time);
+        }
+        assert!(sm.current_time() > 0.0);
+    }
+
+    #[test]
+    fn stepper_rejects_invalid_min_dwell_time() {
+        for min_dwell_time in [-1.0, f64::NAN, f64::INFINITY] {
+            assert!(matches!(
+                Stepper::new(0, Array2D::new(vec![-1.0; 4], (2, 2)).unwrap())
+                    .with_min_dwell_time(min_dwell_time),
+                Err(StateMachineError::InvalidMinDwellTime(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn stepper_step_rejects_underflowing_waiting_times() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let rate_constants = Array2D {
+            data: vec![-1.0, f64::MAX, 1.0, -1.0],
+            shape: (2, 2),
+        };
+        let mut sm = Stepper::new(0, rate_constants);
+
+        let result = sm.step(&[], &mut rng);
+
+        assert!(matches!(
+            result,
+            Err(StateMachineError::WaitingTimeUnderflow(_, 0))
+        ));
+        assert_eq!(0, sm.current_state());
+    }
+
+    #[test]
+    fn stepper_from_ndarray() {
+        let rate_constants = arr2(&[[-1.0, 0.5, 1.0], [1.5, -1.0, 2.0], [2.5, 3.5, -1.0]]);
+
+        let sm = Stepper::from_ndarray(1, rate_constants).unwrap();
+
+        assert_eq!(1, sm.current_state());
+        assert_eq!(3, sm.num_states());
+        assert_eq!(
+            vec![-1.0, 0.5, 1.0, 1.5, -1.0, 2.0, 2.5, 3.5, -1.0],
+            sm.rate_constants.data
+        );
+    }
+
+    #[test]
+    fn stepper_from_ndarray_column_major() {
+        let rate_constants = arr2(&[[-1.0, 2.0], [3.0, -1.0]]).reversed_axes();
+
+        let sm = Stepper::from_ndarray(0, rate_constants).unwrap();
+
+        assert_eq!(vec![-1.0, 3.0, 2.0, -1.0], sm.rate_constants.data);
+    }
+
+    #[test]
+    fn stepper_from_ndarray_not_square() {
+        let rate_constants = arr2(&[[-1.0, 1.0, 1.0], [1.0, -1.0, 1.0]]);
+
+        assert!(Stepper::from_ndarray(0, rate_constants).is_err());
+    }
+
+    #[test]
+    fn stepper_new_generator_matches_hand_built() {
+        let generator = Array2D {
+            data: vec![-1.5, 0.5, 1.0, 1.5, -1.5, 0.0, 2.5, 3.5, -6.0],
+            shape: (3, 3),
+        };
+        let rate_constants = Array2D {
+            data: vec![-1.0, 0.5, 1.0, 1.5, -1.0, -1.0, 2.5, 3.5, -1.0],
+            shape: (3, 3),
+        };
+        let mut generator_sm = Stepper::new_generator(0, generator).unwrap();
+        let mut hand_built_sm = Stepper::new(0, rate_constants);
+        let mut generator_rng = StdRng::seed_from_u64(7);
+        let mut hand_built_rng = StdRng::seed_from_u64(7);
+
+        assert_eq!(
+            hand_built_sm.rate_constants.data,
+            generator_sm.rate_constants.data
+        );
+        for _ in 0..100 {
+            let expected = hand_built_sm.step(&[], &mut hand_built_rng).unwrap();
+            let actual = generator_sm.step(&[], &mut generator_rng).unwrap();
+
+            assert_eq!(
+                (expected.from(), expected.time(), expected.to()),
+                (actual.from(), actual.time(), actual.to())
+            );
+        }
+    }
+
+    #[test]
+    fn stepper_resumes_when_ctrl_params_change() {
+        let mut rng = rand::thread_rng();
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 1.0, -1.0],
+            shape: (2, 2),
+        };
+        // rate(1 -> 0) = c, rate(0 -> 1) = c^2, and the diagonal is -c^2, so state 1 is
+        // absorbing for c < 0
+        let rate_coefficients =
+            Array4D::new(vec![0.0, 0.0, 1.0, 0.0, -1.0, 1.0, 0.0, -1.0], (1, 2, 2, 2)).unwrap();
+        let mut sm = Stepper::new(0, rate_constants)
+            .with_rate_coefficients(rate_coefficients)
+            .unwrap();
+
+        assert_eq!(1, sm.step(&[1.0], &mut rng).unwrap().to());
+        assert!(matches!(
+            sm.step(&[-1.0], &mut rng),
+            Err(StateMachineError::Stopped)
+        ));
+        assert_eq!(1, sm.current_state());
+
+        let transition = sm.step(&[1.0], &mut rng).unwrap();
+
+        assert_eq!((1, 0), (transition.from(), transition.to()));
+    }
+
+    #[test]
+    fn stepper_stops_using_ctrl_param_dependent_rates() {
+        let mut rng = rand::thread_rng();
+        // State 1 is absorbing according to the rate constants...
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, -1.0, -1.0],
+            shape: (2, 2),
+        };
+        // ...but the rates are c * [[-1, 1], [1, -1]], so it is absorbing only when c = 0
+        let rate_coefficients = Array4D::new(vec![-1.0, 1.0, 1.0, -1.0], (1, 1, 2, 2)).unwrap();
+        let mut sm = Stepper::new(0, rate_constants)
+            .with_rate_coefficients(rate_coefficients)
+            .unwrap();
+
+        assert_eq!(1, sm.step(&[1.0], &mut rng).unwrap().to());
+        assert!(!sm.stopped);
+
+        assert!(matches!(
+            sm.step(&[0.0], &mut rng),
+            Err(StateMachineError::Stopped)
+        ));
+        assert!(sm.stopped);
+    }
+
+    #[test]
+    fn stepper_step_from_out_of_range_state() {
+        let mut rng = rand::thread_rng();
+        let rate_constants = Array2D {
+            data: vec![-1.0, 1.0, 1.0, -1.0],
+            shape: (2, 2),
+        };
+        let mut sm = Stepper::new(5, rate_constants);
+
+        assert!(matches!(
+            sm.step(&[], &mut rng),
+            Err(StateMachineError::StateOutOfRange {
+                state: 5,
+                num_states: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn estimate_run_is_close_to_actual_run() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let t_cutoff = 1000.0;
+        let rate_constants = Array2D {
+            data: vec![-1.0, 2.0, 1.0, -1.0],
+            shape: (2, 2),
+        };
+        let sm = Stepper::new(0, rate_constants);
+
+        let estimate = estimate_run(&sm, t_cutoff, &[]);
+        let mut accumulator = StepUntil::new(sm, t_cutoff).unwrap();
+        let actual = accumulator.accumulate(&[], &mut rng).unwrap().len() as f64;
+
+        // The exit rates are 2 and 1, so the estimate is about 1.5 times too large
+        assert_eq!(2000.0, estimate.expected_transitions);
+        assert_eq!(
+            2000 * std::mem::size_of::<Transition>(),
+            estimate.bytes_per_machine
+        );
+        assert!(actual < estimate.expected_transitions);
+        assert!(actual > estimate.expected_transitions / 2.0);
+    }
+
+    #[test]
+    fn time_varying_stepper_matches_piecewise_constant_rates() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let num_samples = 20_000;
+        // The rate out of state 0 ramps up linearly from 0 until it saturates at 2
+        let control = |t: f64, _: &[f64]| vec![t.min(2.0)];
+        let ramp = |t: f64| t.min(2.0);
+        let rate_coefficients = Array4D::new(vec![-1.0, 1.0, -1.0, -1.0], (1, 1, 2, 2)).unwrap();
+
+        let mut first_event_times = Vec::with_capacity(num_samples);
+        for _ in 0..num_samples {
+            let stepper = Stepper::new(0, Array2D::new(vec![-1.0; 4], (2, 2)).unwrap())
+                .with_rate_coefficients(rate_coefficients.clone())
+                .unwrap();
+            let mut sm = TimeVaryingStepper::with_rate_bound(stepper, control, 2.0).unwrap();
+            let transition = sm.step(&[], &mut rng).unwrap();
+            assert_eq!((0, 1), (transition.from(), transition.to()));
+            first_event_times.push(transition.time());
+        }
+
+        // The survival function of a fine piecewise constant approximation of the rate
+        let dt = 1e-4;
+        for t in [0.5, 1.0, 1.5, 2.0, 3.0] {
+            let num_bins = (t / dt) as usize;
+            let integral: f64 = (0..num_bins)
+                .map(|i| ramp((i as f64 + 0.5) * dt) * dt)
+                .sum();
+            let expected = (-integral).exp();
+            let actual = first_event_times.iter().filter(|time| **time > t).count() as f64
+                / num_samples as f64;
+
+            assert!(
+                (expected - actual).abs() < 0.02,
+                "t = {t}: expected {expected}, actual {actual}"
+            );
+        }
+    }
+
+    #[test]
+    fn time_varying_stepper_rejects_rates_above_bound() {
+        let mut rng = rand::thread_rng();
+        let rate_coefficients = Array4D::new(vec![-1.0, 1.0, 1.0, -1.0], (1, 1, 2, 2)).unwrap();
+        let stepper = Stepper::new(0, Array2D::new(vec![-1.0; 4], (2, 2)).unwrap())
+            .with_rate_coefficients(rate_coefficients)
+            .unwrap();
+        let mut sm =
+            TimeVaryingStepper::with_rate_bound(stepper, |_: f64, _: &[f64]| vec![5.0], 1.0)
+                .unwrap();
+
+        assert!(matches!(
+            sm.step(&[], &mut rng),
+            Err(StateMachineError::RateBoundExceeded {
+                rate: 5.0,
+                bound: 1.0
+            })
+        ));
+    }
+
+    #[test]
+    fn time_varying_stepper_stops_when_rates_stay_zero() {
+        let mut rng = StdRng::seed_from_u64(42);
+        // The rate out of state 0 is switched off after t = 1
+        let rate_coefficients = Array4D::new(vec![-1.0, 1.0, -1.0, -1.0], (1, 1, 2, 2)).unwrap();
+        let stepper = Stepper::new(0, Array2D::new(vec![-1.0; 4], (2, 2)).unwrap())
+            .with_rate_coefficients(rate_coefficients)
+            .unwrap();
+        let control = |t: f64, _: &[f64]| vec![if t < 1.0 { 1e-9 } else { 0.0 }];
+        let mut sm = TimeVaryingStepper::with_rate_bound(stepper, control, 1.0)
+            .unwrap()
+            .with_horizon(10.0)
+            .unwrap();
+        assert!(matches!(
+            sm.step(&[], &mut rng),
+            Err(StateMachineError::Stopped)
+        ));
+        assert_eq!(0.0, sm.current_time());
+
+        let constant = Stepper::new(
+            0,
+            Array2D::new(vec![-1.0, 1.0, -1.0, -1.0], (2, 2)).unwrap(),
+        );
+        let mut sm =
+            TimeVaryingStepper::with_rate_bound(constant, |_: f64, p: &[f64]| p.to_vec(), 1.0)
+                .unwrap();
+        assert_eq!(1, sm.step(&[], &mut rng).unwrap().to());
+        assert!(matches!(
+            sm.step(&[], &mut rng),
+            Err(StateMachineError::Stopped)
+        ));
+    }
+
+    #[test]
+    fn time_varying_stepper_rejects_invalid_horizons() {
+        for horizon in [0.0, -1.0, f64::NAN] {
+            let stepper = Stepper::new(0, Array2D::new(vec![-1.0; 4], (2, 2)).unwrap());
+            let result =
+                TimeVaryingStepper::with_rate_bound(stepper, |_: f64, p: &[f64]| p.to_vec(), 1.0)
+                    .unwrap()
+                    .with_horizon(horizon);
+
+            assert!(matches!(result, Err(StateMachineError::InvalidCutoff(_))));
+        }
+    }
+
+    #[test]
+    fn time_varying_stepper_rejects_invalid_bounds() {
+        for bound in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let stepper = Stepper::new(0, Array2D::new(vec![-1.0; 4], (2, 2)).unwrap());
+            let result =
+                TimeVaryingStepper::with_rate_bound(stepper, |_: f64, p: &[f64]| p.to_vec(), bound);
+
+            assert!(matches!(
+                result,
+                Err(StateMachineError::InvalidRateBound(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn stepper_labels() {
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, 1.0, -1.0], (2, 2)).unwrap();
+        let labels = vec!["bright".to_string(), "dark".to_string()];
+
+        let unlabeled = Stepper::new(0, rate_constants.clone());
+        assert_eq!(None, unlabeled.labels());
+        assert_eq!(None, unlabeled.label_of(0));
+
+        let sm = Stepper::new(0, rate_constants)
+            .with_labels(labels.clone())
+            .unwrap();
+        assert_eq!(Some(labels.as_slice()), sm.labels());
+        assert_eq!(Some("bright"), sm.label_of(0));
+        assert_eq!(Some("dark"), sm.label_of(1));
+        assert_eq!(None, sm.label_of(2));
+    }
+
+    #[test]
+    fn stepper_with_labels_rejects_wrong_number_of_labels() {
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, 1.0, -1.0], (2, 2)).unwrap();
+
+        let result = Stepper::new(0, rate_constants).with_labels(vec!["bright".to_string()]);
+
+        assert!(matches!(
+            result,
+            Err(StateMachineError::NumElems {
+                actual: 1,
+                expected: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn stepper_categories() {
+        let rate_constants = Array2D::new(vec![-1.0; 9], (3, 3)).unwrap();
+
+        let sm = Stepper::new(0, rate_constants.clone())
+            .with_categories(vec![0, 0, 1])
+            .unwrap();
+        assert_eq!(Some(&[0, 0, 1][..]), sm.categories());
+        assert_eq!(Some(1), sm.category_of(2));
+        assert_eq!(None, sm.category_of(3));
+
+        assert!(matches!(
+            Stepper::new(0, rate_constants).with_categories(vec![0, 1]),
+            Err(StateMachineError::NumElems {
+                actual: 2,
+                expected: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn stepper_transition_probabilities() {
+        let rate_constants =
+            Array2D::new(vec![-1.0, 1.0, 1.0, 1.0, -1.0, 3.0, 0.0, 2.0, -1.0], (3, 3)).unwrap();
+
+        let sm = Stepper::new(1, rate_constants.clone());
+        assert_eq!(vec![0.25, 0.0, 0.75], sm.transition_probabilities(&[]));
+
+        let sm = Stepper::new(2, rate_constants);
+        assert_eq!(vec![0.0, 1.0, 0.0], sm.transition_probabilities(&[]));
+    }
+
+    #[test]
+    fn stepper_is_stopped_after_absorption() {
+        let mut rng = rand::thread_rng();
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, -1.0, -1.0], (2, 2)).unwrap();
+        let mut sm = Stepper::new(0, rate_constants);
+        assert!(!sm.is_stopped());
+
+        sm.step(&[], &mut rng).unwrap();
+
+        assert!(sm.is_stopped());
+    }
+
+    #[test]
+    fn stepper_try_step_returns_none_when_stopped() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, -1.0, -1.0], (2, 2)).unwrap();
+        let mut sm = Stepper::new(0, rate_constants);
+
+        let transition = sm.try_step(&[], &mut rng).unwrap().unwrap();
+        assert_eq!(1, transition.to);
+
+        assert!(sm.try_step(&[], &mut rng).unwrap().is_none());
+        assert_eq!(1, sm.current_state());
+    }
+
+    #[test]
+    fn step_many_returns_at_most_n_transitions() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let rate_constants = Array2D::new(vec![-1.0, 1.0, 1.0, -1.0], (2, 2)).unwrap();
+        let mut sm = Stepper::new(0, rate_constants);
+
+        let transitions = sm.step_many(10, &[], &mut rng).unwrap();
+
+        assert_eq!(10, transitions.len());
+        assert!(transitions.windows(2).all(|w| w[0].time < w[1].time));
+        assert_eq!(sm.current_time(), transitions[9].time);
+    }
+
+    #[test]
+    fn step_many_stops_on_absorption() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let rate_constants = Array2D::new(
+            vec![-1.0, 1.0, -1.0, -1.0, -1.0, 1.0, -1.0, -1.0, -1.0],
+            (3, 3),
+        )
+        .unwrap();
+        let mut sm = Stepper::new(0, rate_constants);
+
+        let transitions = sm.step_many(10, &[], &mut rng).unwrap();
+
+        assert_eq!(2, transitions.len());
+        assert_eq!(2, transitions[1].to);
+        assert!(sm.step_many(10, &[], &mut rng).unwrap().is_empty());
+    }
+
+    /// Polynomial rates that count how many times the rate matrix is computed.
+    struct CountingModel {
+        rate_coefficients: Array4D,
+        count: Arc<AtomicUsize>,
+    }
+
+    impl RateModel for CountingModel {
+        fn num_states(&self) -> usize {
+            self.rate_coefficients.num_states()
+        }
+
+        fn rates(&self, state: usize, ctrl_params: &[f64], out: &mut [f64]) {
+            // The rates out of every state are computed once per rate matrix
+            if state == 0 {
+                self.count.fetch_add(1, Ordering::Relaxed);
+            }
+            self.rate_coefficients.rates(state, ctrl_params, out);
+        }
+    }
+
+    #[test]
+    fn stepper_with_precomputed_rates() {
+        let rate_constants = Array2D::new(vec![-1.0; 4], (2, 2)).unwrap();
+        let rate_coefficients = Array4D::new(vec![-1.0, 1.0, 2.0, -1.0], (1, 1, 2, 2)).unwrap();
+        let precomputed = vec![(vec![3.0], tensordot(&power(&[3.0], 1), &rate_coefficients))];
+        let new_stepper = |count: &Arc<AtomicUsize>| {
+            Stepper::new(0, rate_constants.clone())
+                .with_rate_model(CountingModel {
+                    rate_coefficients: rate_coefficients.clone(),
+                    count: Arc::clone(count),
+                })
+                .unwrap()
+        };
+        let num_cached = Arc::new(AtomicUsize::new(0));
+        let num_uncached = Arc::new(AtomicUsize::new(0));
+        let mut cached = new_stepper(&num_cached)
+            .with_precomputed_rates(precomputed)
+            .unwrap();
+        let mut uncached = new_stepper(&num_uncached);
+
+        let mut rng_cached = StdRng::seed_from_u64(9);
+        let mut rng_uncached = StdRng::seed_from_u64(9);
+        for _ in 0..100 {
+            let a = cached.step(&[3.0], &mut rng_cached).unwrap();
+            let b = uncached.step(&[3.0], &mut rng_uncached).unwrap();
+            assert_eq!(
+                (a.from(), a.time().to_bits(), a.to()),
+                (b.from(), b.time().to_bits(), b.to())
+            );
+        }
+
+        // Cache hits skip the rate model, misses fall back to it
+        assert_eq!(0, num_cached.load(Ordering::Relaxed));
+        assert_eq!(100, num_uncached.load(Ordering::Relaxed));
+        cached.step(&[2.0], &mut rng_cached).unwrap();
+        assert_eq!(1, num_cached.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn stepper_with_precomputed_rates_rejects_wrong_shape() {
+        let rate_constants = Array2D::new(vec![-1.0; 4], (2, 2)).unwrap();
+        let rate_matrix = Array2D::new(vec![-1.0; 9], (3, 3)).unwrap();
+
+        let result =
+            Stepper::new(0, rate_constants).with_precomputed_rates([(vec![1.0], rate_matrix)]);
+
+        assert!(matches!(
+            result,
+            Err(StateMachineError::NumElems {
+                actual: 9,
+                expected: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn stepper_sparse_rate_coefficients_match_dense() {
+        let rate_constants = Array2D::new(vec![-1.0; 9], (3, 3)).unwrap();
+        // A linear chain 0 <-> 1 <-> 2 whose rates depend on two control parameters
+        let mut data = vec![0.0; 2 * 2 * 9];
+        for (k, l) in [(0, 1), (1, 0), (1, 2), (2, 1)] {
+            for i in 0..2 {
+                for j in 0..2 {
+                    data[(i * 2 * 9) + (j * 9) + (k * 3) + l] = (k + 2 * l + i + j + 1) as f64;
+                }
+            }
+        }
+        let dense = Array4D::new(data, (2, 2, 3, 3)).unwrap();
+        let sparse = SparseArray4D::from_dense(&dense);
+        let mut dense_sm = Stepper::new(0, rate_constants.clone())
+            .with_rate_coefficients(dense)
+            .unwrap();
+        let mut sparse_sm = Stepper::new(0, rate_constants)
+            .with_sparse_rate_coefficients(sparse)
+            .unwrap();
+
+        for ctrl_params in [[1.0, 2.0], [0.5, 0.1]] {
+            dense_sm.compute_rates(&ctrl_params);
+            sparse_sm.compute_rates(&ctrl_params);
+
+            assert_eq!(
+                dense_sm.current_rates().data,
+                sparse_sm.current_rates().data
+            );
+            assert_eq!(
+                dense_sm.exit_rates(&ctrl_params),
+                sparse_sm.exit_rates(&ctrl_params)
+            );
+        }
+    }
+
+    /// The Arrhenius equation k = A exp(-E_a / T) for every edge, where the temperature T (in
+    /// units of the activation energy) is the first control parameter.
+    struct Arrhenius {
+        prefactors: Array2D,
+        activation_energies: Array2D,
+    }
+
+    impl RateModel for Arrhenius {
+        fn num_states(&self) -> usize {
+            self.prefactors.shape.0
+        }
+
+        fn rates(&self, state: usize, ctrl_params: &[f64], out: &mut [f64]) {
+            let temperature = ctrl_params[0];
+            let prefactors = self.prefactors.row(state).unwrap();
+            let energies = self.activation_energies.row(state).unwrap();
+            for ((rate, a), e) in out.iter_mut().zip(prefactors).zip(energies) {
+                *rate = if *a > 0.0 {
+                    a * (-e / temperature).exp()
+                } else {
+                    -1.0
+                };
+            }
+        }
+    }
+
+    #[test]
+    fn stepper_with_arrhenius_rate_model() {
+        let model = || Arrhenius {
+            prefactors: Array2D::new(vec![-1.0, 10.0, 5.0, -1.0], (2, 2)).unwrap(),
+            activation_energies: Array2D::new(vec![0.0, 2.0, 1.0, 0.0], (2, 2)).unwrap(),
+        };
+        let temperature = 0.5;
+        let rate_constants = Array2D::new(
+            vec![
+                -1.0,
+                10.0 * (-2.0f64 / temperature).exp(),
+                5.0 * (-1.0f64 / temperature).exp(),
+                -1.0,
+            ],
+            (2, 2),
+        )
+        .unwrap();
+        let mut custom = Stepper::new(0, Array2D::new(vec![-1.0; 4], (2, 2)).unwrap())
+            .with_rate_model(model())
+            .unwrap();
+        let mut constant = Stepper::new(0, rate_constants.clone());
+
+        // The rates of the model at the given temperature are the equivalent rate constants
+        assert_eq!(
+            vec![rate_constants.data[1], rate_constants.data[2]],
+            custom.exit_rates(&[temperature])
+        );
+        let mut rng_custom = StdRng::seed_from_u64(4);
+        let mut rng_constant = StdRng::seed_from_u64(4);
+        for _ in 0..50 {
+            let a = custom.step(&[temperature], &mut rng_custom).unwrap();
+            let b = constant.step(&[temperature], &mut rng_constant).unwrap();
+            assert_eq!(
+                (a.from(), a.time().to_bits(), a.to()),
+                (b.from(), b.time().to_bits(), b.to())
+            );
+        }
+
+        // Higher temperatures speed up the transitions
+        assert!(custom.exit_rates(&[2.0])[0] > custom.exit_rates(&[temperature])[0]);
+
+        let three_states = Stepper::new(0, Array2D::new(vec![-1.0; 9], (3, 3)).unwrap());
+        assert!(matches!(
+            three_states.with_rate_model(model()),
+            Err(StateMachineError::NumElems {
+                actual: 2,
+                expected: 3
+            })
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn stepper_json_round_trip() {
+        let rate_coefficients = Array4D::new(vec![-1.0, 2.0, 3.0, -1.0], (1, 1, 2, 2)).unwrap();
+        let mut original = Stepper::new(1, Array2D::new(vec![-1.0; 4], (2, 2)).unwrap())
+            .with_rate_coefficients(rate_coefficients)
+            .unwrap()
+            .with_labels(vec!["open".to_string(), "closed".to_string()])
+            .unwrap();
+
+        let json = serde_json::to_string(&original).unwrap();
+        let mut loaded: Stepper = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(1, loaded.current_state());
+        assert!(loaded.has_rate_coefficients());
+        assert_eq!(original.labels(), loaded.labels());
+        assert_eq!(original.exit_rates(&[2.0]), loaded.exit_rates(&[2.0]));
+        let expected = original
+            .step_many(20, &[2.0], &mut StdRng::seed_from_u64(3))
+            .unwrap();
+        let actual = loaded
+            .step_many(20, &[2.0], &mut StdRng::seed_from_u64(3))
+            .unwrap();
+        for (expected, actual) in zip(&expected, &actual) {
+            assert_eq!(expected.time, actual.time);
+            assert_eq!(expected.to, actual.to);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn stepper_json_rejects_invalid_configurations() {
+        let invalid = [
+            // The rate constants do not match their shape
+            r#"{"current_state":0,"rate_constants":{"data":[-1.0,1.0,1.0],"shape":[2,2]}}"#,
+            // The rate constants are not square
+            r#"{"current_state":0,"rate_constants":{"data":[-1.0,1.0],"shape":[1,2]}}"#,
+            // The current state is out of range
+            r#"{"current_state":2,"rate_constants":{"data":[-1.0,1.0,1.0,-1.0],"shape":[2,2]}}"#,
+            // The rate coefficients have the wrong number of states
+            r#"{"current_state":0,"rate_constants":{"data":[-1.0,1.0,1.0,-1.0],"shape":[2,2]},
+                "rate_coefficients":{"data":[1.0],"shape":[1,1,1,1]}}"#,
+        ];
+        for json in invalid {
+            assert!(serde_json::from_str::<Stepper>(json).is_err(), "{json}");
+        }
+
+        let model = Arrhenius {
+            prefactors: Array2D::new(vec![-1.0, 10.0, 5.0, -1.0], (2, 2)).unwrap(),
+            activation_energies: Array2D::new(vec![0.0, 2.0, 1.0, 0.0], (2, 2)).unwrap(),
+        };
+        let custom = Stepper::new(0, Array2D::new(vec![-1.0; 4], (2, 2)).unwrap())
+            .with_rate_model(model)
+            .unwrap();
+        assert!(serde_json::to_string(&custom).is_err());
     }
 }